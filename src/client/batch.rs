@@ -0,0 +1,150 @@
+use std::io::BufRead;
+
+use dusa_collection_utils::errors::{ErrorArray, ErrorArrayItem, Errors};
+use dusa_common::{client::ClientError, client::Result, BatchItem, Commands, Encoding};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// One line of `--batch` input.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchLine {
+    /// Anything the caller wants to find its result by, copied to the result as is.
+    id: Option<Value>,
+    /// What to do with this line's data, the command the batch was given for if it's left out.
+    command: Option<Operation>,
+    data: String,
+}
+
+/// The text operations a batch line may ask for.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Operation {
+    Encrypt,
+    Decrypt,
+}
+
+impl From<Operation> for Commands {
+    fn from(operation: Operation) -> Self {
+        match operation {
+            Operation::Encrypt => Commands::EncryptRawText,
+            Operation::Decrypt => Commands::DecryptRawText,
+        }
+    }
+}
+
+/// Reads a batch of `command` operations on data in `encoding`, one json object per line. A line
+/// with a `"command"` of `"encrypt"` or `"decrypt"` is handled that way instead, so one batch can
+/// do both. Blank lines are skipped.
+///
+/// # Returns
+/// The ids of the lines and the items to send, in the order they were read.
+pub fn read_batch<R: BufRead>(
+    reader: R,
+    command: Commands,
+    encoding: Encoding,
+) -> Result<(Vec<Option<Value>>, Vec<BatchItem>)> {
+    let mut ids: Vec<Option<Value>> = Vec::new();
+    let mut items: Vec<BatchItem> = Vec::new();
+
+    for (index, line) in reader.lines().enumerate() {
+        let line: String = line.map_err(|e| invalid(Errors::InputOutput, e.to_string()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let parsed: BatchLine = serde_json::from_str(&line).map_err(|e| {
+            invalid(
+                Errors::InvalidType,
+                format!(
+                    "Line {} of the batch is not a {{\"data\": ...}} object: {}",
+                    index + 1,
+                    e
+                ),
+            )
+        })?;
+        ids.push(parsed.id);
+        items.push(BatchItem {
+            command: parsed.command.map(Commands::from).unwrap_or(command),
+            data: parsed.data,
+            encoding,
+        });
+    }
+
+    Ok((ids, items))
+}
+
+fn invalid(kind: Errors, message: String) -> ClientError {
+    ClientError::Io(ErrorArray::new(vec![ErrorArrayItem::new(kind, message)]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(input: &str) -> Result<(Vec<Option<Value>>, Vec<BatchItem>)> {
+        read_batch(input.as_bytes(), Commands::EncryptRawText, Encoding::Utf8)
+    }
+
+    fn error_message(input: &str) -> String {
+        match read(input) {
+            Ok(_) => panic!("{:?} was read as a batch", input),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn blank_lines_are_skipped() {
+        let (ids, items) =
+            read("\n{\"data\": \"a\"}\n   \n\t\n{\"id\": 7, \"data\": \"b\"}\n\n").unwrap();
+        assert_eq!(ids, [None, Some(Value::from(7))]);
+        let data: Vec<&str> = items.iter().map(|i| i.data.as_str()).collect();
+        assert_eq!(data, ["a", "b"]);
+        assert!(items
+            .iter()
+            .all(|i| i.command == Commands::EncryptRawText && i.encoding == Encoding::Utf8));
+
+        let (ids, items) = read("\n\n").unwrap();
+        assert!(ids.is_empty() && items.is_empty());
+    }
+
+    #[test]
+    fn malformed_lines_are_reported_by_their_number() {
+        let message: String = error_message("{\"data\": \"a\"}\n\nnot json\n");
+        assert!(message.starts_with("Line 3 of the batch"), "{}", message);
+
+        let message: String = error_message("{\"data\": \"a\"}\n{\"id\": 2}\n");
+        assert!(message.starts_with("Line 2 of the batch"), "{}", message);
+
+        let message: String = error_message("{\"data\": \"a\", \"dat\": \"b\"}\n");
+        assert!(message.starts_with("Line 1 of the batch"), "{}", message);
+    }
+
+    #[test]
+    fn lines_may_mix_encrypting_and_decrypting() {
+        let input: &str = "{\"data\": \"a\"}\n\
+            {\"command\": \"decrypt\", \"data\": \"b\"}\n\
+            {\"command\": \"encrypt\", \"data\": \"c\"}\n";
+        let (_, items) = read(input).unwrap();
+        let commands: Vec<Commands> = items.iter().map(|i| i.command).collect();
+        assert_eq!(
+            commands,
+            [
+                Commands::EncryptRawText,
+                Commands::DecryptRawText,
+                Commands::EncryptRawText
+            ]
+        );
+    }
+
+    #[test]
+    fn lines_may_only_encrypt_or_decrypt() {
+        for command in ["remove", "DecryptFile", "Encrypt", ""] {
+            let input: String = format!(
+                "{{\"data\": \"a\"}}\n{{\"command\": {:?}, \"data\": \"b\"}}\n",
+                command
+            );
+            let message: String = error_message(&input);
+            assert!(message.starts_with("Line 2 of the batch"), "{}", message);
+        }
+    }
+}
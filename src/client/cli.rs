@@ -1,73 +1,513 @@
-use std::path::PathBuf;
+use std::{ffi::OsString, io, path::PathBuf};
 
-use clap::{value_parser, Arg, Command};
-use dusa_common::VERSION;
+use clap::{value_parser, Arg, ArgMatches, Command};
+use clap_complete::Shell;
+use dusa_collection_utils::errors::{ErrorArray, UnifiedResult as uf};
+use dusa_common::{
+    config::{config_path, Config},
+    Encoding, VERSION,
+};
 
 pub fn build_cli() -> Command {
-    Command::new("Dusa")
-        .about("A recs based encryption client / server")
+    Command::new("dusa")
+        .about("A client daemon implementation of the RECS library")
         .version(VERSION)
-        .trailing_var_arg(true)
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .after_help(
+            "Exit codes: 1 for local errors, 2 for usage errors, 3 to 15 and 19 for errors from \
+            the daemon, 16 when stat finds nothing, 17 when status finds problems and 18 when \
+            items of a batch failed",
+        )
         .arg(
-            Arg::new("path")
-                .short('p')
-                .long("path")
+            Arg::new("config")
+                .long("config")
+                .global(true)
                 .value_parser(value_parser!(PathBuf))
-                .help("filepath to encrypt")
+                .help("Config file to read instead of /etc/dusa/dusa.toml or $DUSA_CONFIG")
                 .num_args(1),
         )
         .arg(
-            Arg::new("owner")
-                .short('o')
-                .long("owner")
-                .value_parser(value_parser!(String))
-                .help("The owner of encrypted file or the owner of the file to be encrypted")
+            Arg::new("output")
+                .long("output")
+                .global(true)
+                .value_parser(["pretty", "plain", "json"])
+                .default_value("pretty")
+                .help("How results are written, plain and json are meant for scripts")
                 .num_args(1),
         )
         .arg(
-            Arg::new("name")
-                .short('n')
-                .long("name")
-                .value_parser(value_parser!(String))
-                .help("The name of the file to be encrypted or already encrypted")
+            Arg::new("socket")
+                .long("socket")
+                .global(true)
+                .value_parser(value_parser!(PathBuf))
+                .help("The daemon's socket")
                 .num_args(1),
         )
-        .arg(
-            Arg::new("encrypt_file")
-                .long("ef")
-                .action(clap::ArgAction::SetTrue)
-                .help("Encrypting file"),
+        .subcommand(
+            Command::new("encrypt-file")
+                .about("Encrypt and store a file, or whatever is piped in")
+                .arg(
+                    Arg::new("path")
+                        .value_parser(value_parser!(PathBuf))
+                        .help("The file to encrypt, stdin is read if omitted or '-'"),
+                )
+                .arg(owner_arg())
+                .arg(name_arg()),
         )
-        .arg(
-            Arg::new("encrypt_text")
-                .long("et")
-                .action(clap::ArgAction::SetTrue)
-                .help("Encrypt text data"),
+        .subcommand(
+            Command::new("decrypt-file")
+                .about("Decrypt a stored file into a file")
+                .arg(
+                    Arg::new("destination")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true)
+                        .help(
+                            "Where to write the plaintext, a new file only we can read. The \
+                            path an entry was stored from is only shown by stat, whoever stored \
+                            it chose it",
+                        ),
+                )
+                .arg(owner_arg())
+                .arg(name_arg()),
         )
-        .arg(
-            Arg::new("decrypt_file")
-                .long("df")
-                .action(clap::ArgAction::SetTrue)
-                .help("Decrypt file"),
+        .subcommand(
+            Command::new("cat")
+                .about("Decrypt a stored file and write it to stdout")
+                .arg(
+                    Arg::new("entry")
+                        .value_parser(parse_entry)
+                        .conflicts_with_all(["owner", "name"])
+                        .help("The file as owner/name, instead of --owner and --name"),
+                )
+                .arg(owner_arg())
+                .arg(name_arg().required(false).required_unless_present("entry")),
         )
-        .arg(
-            Arg::new("decrypt_text")
-                .long("dt")
-                .action(clap::ArgAction::SetTrue)
-                .help("Decrypt text data"),
+        .subcommand(
+            Command::new("encrypt-text")
+                .visible_alias("et")
+                .about("Encrypt text, prints a recs sequence")
+                .arg(data_arg("The text to encrypt"))
+                .arg(encoding_arg(
+                    "How the text is encoded: utf8, base64 or hex, the last two carry any bytes",
+                ))
+                .arg(batch_arg()),
         )
-        .arg(
-            Arg::new("remove_file")
-                .long("rf")
-                .action(clap::ArgAction::SetTrue)
-                .help("Remove encrypted file"),
+        .subcommand(
+            Command::new("decrypt-text")
+                .visible_alias("dt")
+                .about("Decrypt a recs sequence created by encrypt-text")
+                .arg(data_arg("The recs sequence to decrypt"))
+                .arg(encoding_arg(
+                    "How the plaintext is printed: utf8, base64 or hex if it isn't utf8 text",
+                ))
+                .arg(batch_arg()),
         )
-        .arg(
-            Arg::new("data")
-                .short('d')
-                .long("data")
-                .value_parser(value_parser!(String))
-                .help("plain text data to encrypt or decrypt")
-                .num_args(1),
+        .subcommand(
+            Command::new("rm")
+                .about("Remove a stored file")
+                .arg(owner_arg())
+                .arg(name_arg()),
+        )
+        .subcommand(
+            Command::new("list")
+                .about("List the stored entries you can read")
+                .arg(
+                    Arg::new("owner")
+                        .short('o')
+                        .long("owner")
+                        .value_parser(value_parser!(String))
+                        .help("Only list the entries of this owner")
+                        .num_args(1),
+                ),
+        )
+        .subcommand(
+            Command::new("stat")
+                .about(
+                    "Show a stored file without decrypting it, exits with 16 if it doesn't exist",
+                )
+                .arg(owner_arg())
+                .arg(name_arg()),
+        )
+        .subcommand(
+            Command::new("status")
+                .about("Show how the daemon is doing, exits with 17 if it's unhealthy"),
         )
+        .subcommand(
+            Command::new("completions")
+                .about("Print the completion script for a shell")
+                .arg(
+                    Arg::new("shell")
+                        .value_parser(value_parser!(Shell))
+                        .required(true)
+                        .help("The shell to generate completions for"),
+                ),
+        )
+        .subcommand(Command::new("man").about("Print the dusa(1) man page"))
+}
+
+fn owner_arg() -> Arg {
+    Arg::new("owner")
+        .short('o')
+        .long("owner")
+        .value_parser(value_parser!(String))
+        .default_value("system")
+        .help("The owner the file is stored under")
+        .num_args(1)
+}
+
+fn name_arg() -> Arg {
+    Arg::new("name")
+        .short('n')
+        .long("name")
+        .value_parser(value_parser!(String))
+        .required(true)
+        .help("The name the file is stored as")
+        .num_args(1)
+}
+
+/// Splits an `owner/name` argument, neither may be empty.
+fn parse_entry(entry: &str) -> Result<(String, String), String> {
+    match entry.split_once('/') {
+        Some((owner, name)) if !owner.is_empty() && !name.is_empty() => {
+            Ok((owner.to_owned(), name.to_owned()))
+        }
+        _ => Err(String::from("expected owner/name")),
+    }
+}
+
+fn data_arg(help: &'static str) -> Arg {
+    Arg::new("data")
+        .value_parser(value_parser!(String))
+        .required_unless_present("batch")
+        .help(help)
+}
+
+fn encoding_arg(help: &'static str) -> Arg {
+    Arg::new("encoding")
+        .short('e')
+        .long("encoding")
+        .value_parser(value_parser!(Encoding))
+        .default_value("utf8")
+        .help(help)
+        .num_args(1)
+}
+
+fn batch_arg() -> Arg {
+    Arg::new("batch")
+        .long("batch")
+        .action(clap::ArgAction::SetTrue)
+        .conflicts_with("data")
+        .help(
+            "Read one {\"data\": ...} object per line from stdin and send them as one request, \
+            an \"id\" in the object is copied to its result and a \"command\" of \"encrypt\" or \
+            \"decrypt\" overrides the subcommand for its line. Exits with 18 if any item failed",
+        )
+}
+
+/// The flags the client used before it had subcommands, and what replaced them.
+const LEGACY_MODES: [(&str, &str); 8] = [
+    ("--ef", "encrypt-file"),
+    ("--df", "decrypt-file"),
+    ("--cat", "cat"),
+    ("--et", "encrypt-text"),
+    ("--dt", "decrypt-text"),
+    ("--rf", "rm"),
+    ("--list", "list"),
+    ("--stat", "stat"),
+];
+
+/// Rewrites a command line using the old mode flags (`--ef -p file -n name`) into the matching
+/// subcommand, keeping the old defaults for `--name` and `--data`. Like the old client the mode
+/// flag may come anywhere among the options, but only before a `--` and before anything that
+/// isn't an option, so a subcommand's arguments are never taken for a mode. Anything else is
+/// returned unchanged.
+pub fn legacy_args(args: Vec<OsString>) -> Vec<OsString> {
+    let subcommand: &str = match legacy_mode(&args) {
+        Some(d) => d,
+        None => return args,
+    };
+    eprintln!(
+        "Warning: the --ef style flags are deprecated, use 'dusa {}' instead",
+        subcommand
+    );
+
+    // The old client accepted every option with every mode and ignored what it didn't need
+    let is_text: bool = matches!(subcommand, "encrypt-text" | "decrypt-text");
+    let takes_owner: bool = !is_text;
+    let takes_name: bool = !is_text && subcommand != "list";
+
+    let mut rewritten: Vec<OsString> = Vec::new();
+    let mut positional: Option<OsString> = None;
+    let mut rest: Vec<OsString> = Vec::new();
+    let mut name: Option<OsString> = None;
+    let mut iter = args.into_iter();
+    rewritten.extend(iter.next());
+    rewritten.push(OsString::from(subcommand));
+
+    while let Some(arg) = iter.next() {
+        if arg == "--" {
+            rest.extend(iter.by_ref());
+            break;
+        }
+        let text: String = arg.to_string_lossy().into_owned();
+        let (flag, inline) = match text.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag, Some(value)),
+            _ => (text.as_str(), None),
+        };
+        let (keep, is_positional) = match flag {
+            // The mode flag itself
+            _ if LEGACY_MODES.iter().any(|(legacy, _)| *legacy == flag) => continue,
+            // -p and -d became the positional argument of their subcommand
+            "-p" | "--path" | "-d" | "--data" => (false, true),
+            "-o" | "--owner" => (takes_owner, false),
+            "-n" | "--name" => (takes_name, false),
+            _ => {
+                rewritten.push(arg);
+                continue;
+            }
+        };
+
+        let value: Option<OsString> = match inline {
+            Some(d) => Some(OsString::from(d)),
+            None => iter.next(),
+        };
+        if is_positional {
+            positional = value;
+        } else if flag == "-n" || flag == "--name" {
+            name = value.clone();
+            if keep {
+                rewritten.push(OsString::from(flag));
+                rewritten.extend(value);
+            }
+        } else if keep {
+            rewritten.push(OsString::from(flag));
+            rewritten.extend(value);
+        }
+    }
+
+    match subcommand {
+        // Unless the text came after a --
+        "encrypt-text" | "decrypt-text" if rest.is_empty() => {
+            positional.get_or_insert_with(|| OsString::from("hello world"));
+        }
+        "encrypt-text" | "decrypt-text" => (),
+        "encrypt-file" => (),
+        // The old client wrote back to wherever the file was stored from, that path is only a
+        // claim of whoever stored it. A file named after the entry in the current directory
+        // is the closest safe thing.
+        "decrypt-file" if positional.is_none() && rest.is_empty() => {
+            let destination: OsString = name.clone().unwrap_or_else(|| OsString::from("lost"));
+            eprintln!(
+                "Warning: --df writes to ./{} now, give -p to write somewhere else",
+                destination.to_string_lossy()
+            );
+            positional = Some(destination);
+        }
+        "decrypt-file" => (),
+        _ => positional = None,
+    }
+    if takes_name && name.is_none() {
+        rewritten.push(OsString::from("--name"));
+        rewritten.push(OsString::from("lost"));
+    }
+    if positional.is_some() || !rest.is_empty() {
+        rewritten.push(OsString::from("--"));
+        rewritten.extend(positional);
+        rewritten.extend(rest);
+    }
+    rewritten
+}
+
+/// Finds the single old mode flag of a command line, `None` if it has none, more than one or is
+/// already using a subcommand.
+fn legacy_mode(args: &[OsString]) -> Option<&'static str> {
+    let mut modes: Vec<&'static str> = Vec::new();
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        let text: String = arg.to_string_lossy().into_owned();
+        if let Some((_, subcommand)) = LEGACY_MODES.iter().find(|(flag, _)| *flag == text) {
+            modes.push(subcommand);
+            continue;
+        }
+        match text.as_str() {
+            "--" => break,
+            // Their value could be anything, a mode flag included
+            "-p" | "--path" | "-d" | "--data" | "-o" | "--owner" | "-n" | "--name" | "--config"
+            | "--output" | "--socket" => {
+                iter.next();
+            }
+            // A subcommand, or the value of an option we don't know of
+            d if !d.starts_with('-') && modes.is_empty() => return None,
+            _ => (),
+        }
+    }
+
+    match modes.as_slice() {
+        [d] => Some(d),
+        _ => None,
+    }
+}
+
+/// The completion script for `shell`.
+pub fn completions(shell: Shell) -> String {
+    let mut script: Vec<u8> = Vec::new();
+    clap_complete::generate(shell, &mut build_cli(), "dusa", &mut script);
+    String::from_utf8_lossy(&script).into_owned()
+}
+
+/// The dusa(1) man page, in roff.
+pub fn man_page() -> io::Result<String> {
+    let mut page: Vec<u8> = Vec::new();
+    clap_mangen::Man::new(build_cli())
+        .section("1")
+        .render(&mut page)?;
+    Ok(String::from_utf8_lossy(&page).into_owned())
+}
+
+/// Reads the config file and the environment, then applies the command line on top.
+pub fn resolve_config(cmd: &ArgMatches, errors: ErrorArray) -> uf<Config> {
+    let path: PathBuf = cmd
+        .get_one::<PathBuf>("config")
+        .cloned()
+        .unwrap_or_else(config_path);
+
+    let mut config: Config = match Config::load_with_env(&path, errors).uf_unwrap() {
+        Ok(d) => d,
+        Err(e) => return uf::new(Err(e)),
+    };
+
+    if let Some(d) = cmd.get_one::<PathBuf>("socket") {
+        config.socket_path = d.clone();
+    }
+    uf::new(Ok(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn legacy(args: &[&str]) -> Vec<String> {
+        legacy_args(args.iter().map(OsString::from).collect())
+            .into_iter()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn a_leading_mode_flag_becomes_its_subcommand() {
+        assert_eq!(
+            legacy(&["dusa", "--ef", "-p", "/tmp/a", "-n", "a"]),
+            ["dusa", "encrypt-file", "-n", "a", "--", "/tmp/a"]
+        );
+        assert_eq!(
+            legacy(&["dusa", "--et", "--data=secret", "-o", "me"]),
+            ["dusa", "encrypt-text", "--", "secret"]
+        );
+        assert_eq!(
+            legacy(&["dusa", "--cat", "-o", "me"]),
+            ["dusa", "cat", "-o", "me", "--name", "lost"]
+        );
+    }
+
+    #[test]
+    fn mode_flags_count_anywhere_among_the_options() {
+        assert_eq!(
+            legacy(&["dusa", "-n", "a", "--ef", "-p", "file"]),
+            ["dusa", "encrypt-file", "-n", "a", "--", "file"]
+        );
+        assert_eq!(
+            legacy(&["dusa", "--output", "json", "--stat", "-n", "a"]),
+            ["dusa", "stat", "--output", "json", "-n", "a"]
+        );
+        // The value of an option is never a mode
+        assert_eq!(
+            legacy(&["dusa", "--et", "-d", "--list"]),
+            ["dusa", "encrypt-text", "--", "--list"]
+        );
+    }
+
+    #[test]
+    fn subcommand_arguments_are_never_a_mode() {
+        let args = ["dusa", "encrypt-text", "--", "--list"];
+        assert_eq!(legacy(&args), args);
+        let args = ["dusa", "encrypt-text", "--list"];
+        assert_eq!(legacy(&args), args);
+        let args = ["dusa", "-o", "me", "cat", "--df"];
+        assert_eq!(legacy(&args), args);
+    }
+
+    #[test]
+    fn decrypt_file_without_a_path_writes_next_to_us() {
+        let rewritten = legacy(&["dusa", "--df", "-o", "system", "-n", "foo"]);
+        assert_eq!(
+            rewritten,
+            [
+                "dusa",
+                "decrypt-file",
+                "-o",
+                "system",
+                "-n",
+                "foo",
+                "--",
+                "foo"
+            ]
+        );
+        let cmd = build_cli().try_get_matches_from(rewritten).unwrap();
+        let (_, df) = cmd.subcommand().unwrap();
+        assert_eq!(
+            df.get_one::<PathBuf>("destination"),
+            Some(&PathBuf::from("foo"))
+        );
+
+        assert_eq!(
+            legacy(&["dusa", "-n", "foo", "--df", "-p", "/tmp/out"]),
+            ["dusa", "decrypt-file", "-n", "foo", "--", "/tmp/out"]
+        );
+    }
+
+    #[test]
+    fn nothing_after_a_double_dash_is_translated() {
+        assert_eq!(
+            legacy(&["dusa", "--et", "--", "--df"]),
+            ["dusa", "encrypt-text", "--", "--df"]
+        );
+        assert_eq!(
+            legacy(&["dusa", "--list", "--", "-n"]),
+            ["dusa", "list", "--", "-n"]
+        );
+    }
+
+    #[test]
+    fn cat_takes_the_entry_as_owner_slash_name() {
+        let cmd = build_cli().get_matches_from(["dusa", "cat", "db/backup"]);
+        let (_, cat) = cmd.subcommand().unwrap();
+        assert_eq!(
+            cat.get_one::<(String, String)>("entry"),
+            Some(&(String::from("db"), String::from("backup")))
+        );
+
+        for args in [
+            &["dusa", "cat", "backup"][..],
+            &["dusa", "cat", "db/"],
+            &["dusa", "cat", "db/backup", "-n", "other"],
+            &["dusa", "cat"],
+        ] {
+            assert!(
+                build_cli().try_get_matches_from(args).is_err(),
+                "{:?}",
+                args
+            );
+        }
+        assert!(build_cli()
+            .try_get_matches_from(["dusa", "cat", "-n", "backup"])
+            .is_ok());
+    }
+
+    #[test]
+    fn two_mode_flags_are_left_for_clap_to_refuse() {
+        let args = ["dusa", "--ef", "--df", "-n", "a"];
+        assert_eq!(legacy(&args), args);
+        assert!(build_cli().try_get_matches_from(args).is_err());
+    }
 }
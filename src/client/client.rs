@@ -1,616 +1,205 @@
+mod batch;
 mod cli;
-mod log;
+mod output;
 use {
-    cli::build_cli, dusa_collection_utils::{
-        errors::{
-            ErrorArray, ErrorArrayItem, Errors, OkWarning, UnifiedResult as uf, WarningArray, WarningArrayItem, Warnings
-        }, types::PathType
-    }, dusa_common::{
-        get_id, prefix::{receive_message, send_message}, set_file_ownership, DecryptResponseData, Message, MessageType, RequestPayload, RequestRecsPlainText, RequestRecsSimple, RequestRecsWrite, SOCKET_PATH, VERSION
-    }, nix::unistd::geteuid, simple_pretty::{halt, pass, warn}, std::{
-        fs, os::unix::net::UnixStream, path::PathBuf, process::exit, time::Duration
-    }
+    batch::read_batch,
+    clap_complete::Shell,
+    cli::{build_cli, completions, legacy_args, man_page, resolve_config},
+    dusa_collection_utils::errors::ErrorArray,
+    dusa_common::{
+        client::{ClientError, DusaClient, Result},
+        config::set_config,
+        BatchResult, Commands, Encoding,
+    },
+    output::{fail, print_out, report, Outcome, OutputFormat},
+    std::{env, fs::OpenOptions, io, os::unix::fs::OpenOptionsExt, path::PathBuf, process::exit},
 };
 
-type Callback =
-    fn(clap::ArgMatches, UnixStream, WarningArray, ErrorArray) -> uf<OkWarning<Option<String>>>;
+type Callback = fn(&clap::ArgMatches, &DusaClient) -> Result<Outcome>;
 
 fn main() {
     let e1: ErrorArray = ErrorArray::new_container();
-    let w1: WarningArray = WarningArray::new_container();
 
-    let socket_path: PathType = match SOCKET_PATH(false, e1.clone(), w1.clone()).uf_unwrap() {
-        Ok(d) => {
-            d.warning.display();
-            d.data
-        }
-        Err(e) => {
-            e.display(true);
-            unreachable!()
-        }
-    };
+    // clapping, the old mode flags are translated into subcommands first
+    let cmd: clap::ArgMatches = build_cli().get_matches_from(legacy_args(env::args_os().collect()));
 
-    // clapping
-    let cmd: clap::ArgMatches = build_cli()
-        .version(VERSION)
-        .about("A client daemon implementation of the RECS library")
-        .get_matches();
+    let format: OutputFormat = OutputFormat::from_arg(cmd.get_one::<String>("output"));
+    let (command, args): (&str, &clap::ArgMatches) = match cmd.subcommand() {
+        Some(d) => d,
+        // clap insists on a known subcommand
+        None => unreachable!(),
+    };
 
-    enum ProgramMode {
-        StoreFile(Callback),
-        RetrieveFile(Callback),
-        EncryptText(Callback),
-        DecryptText(Callback),
-        RemoveFile(Callback),
-        Invalid,
+    // These only describe the cli, they work without a config or a daemon
+    match command {
+        "completions" => {
+            if let Some(shell) = args.get_one::<Shell>("shell") {
+                print_out(&completions(*shell));
+            }
+            exit(0)
+        }
+        "man" => match man_page() {
+            Ok(page) => {
+                print_out(&page);
+                exit(0)
+            }
+            Err(e) => fail(format, command, ClientError::Output(e)),
+        },
+        _ => (),
     }
 
-    // Get operating mode
-    let (ef, df, et, dt, rf) = (
-        cmd.get_flag("encrypt_file"),
-        cmd.get_flag("decrypt_file"),
-        cmd.get_flag("encrypt_text"),
-        cmd.get_flag("decrypt_text"),
-        cmd.get_flag("remove_file"),
-    );
-
-    let mode: ProgramMode = match (ef, df, et, dt, rf) {
-        (true, false, false, false, false) => ProgramMode::StoreFile(encrypt_file),
-        (false, true, false, false, false) => ProgramMode::RetrieveFile(decrypt_file),
-        (false, false, true, false, false) => ProgramMode::EncryptText(encrypt_text),
-        (false, false, false, true, false) => ProgramMode::DecryptText(decrypt_text),
-        (false, false, false, false, true) => ProgramMode::RemoveFile(remove_file),
-        _ => ProgramMode::Invalid,
-    };
+    match resolve_config(&cmd, e1.clone()).uf_unwrap() {
+        Ok(d) => {
+            set_config(d);
+        }
+        Err(e) => fail(format, command, ClientError::Io(e)),
+    }
 
-    let stream: UnixStream = match UnixStream::connect(socket_path.clone()) {
+    let client: DusaClient = match DusaClient::new() {
         Ok(d) => d,
-        Err(_) => {
-            halt("The server is not running or You do not have access to this application");
-            unreachable!()
-        },
+        Err(e) => fail(format, command, e),
     };
 
-    let result: uf<OkWarning<Option<String>>> = match mode {
-        ProgramMode::StoreFile(callback) => callback(cmd, stream, w1.clone(), e1.clone()),
-        ProgramMode::RetrieveFile(callback) => callback(cmd, stream, w1.clone(), e1.clone()),
-        ProgramMode::EncryptText(callback) => callback(cmd, stream, w1.clone(), e1.clone()),
-        ProgramMode::DecryptText(callback) => callback(cmd, stream, w1.clone(), e1.clone()),
-        ProgramMode::RemoveFile(callback) => callback(cmd, stream, w1.clone(), e1.clone()),
-        ProgramMode::Invalid => {
-            warn("Invalid command given use '-h' or '--help' for more info");
-            exit(1)
-        }
+    let callback: Callback = match command {
+        "encrypt-file" => encrypt_file,
+        "decrypt-file" => decrypt_file,
+        "cat" => stream_file,
+        "encrypt-text" => encrypt_text,
+        "decrypt-text" => decrypt_text,
+        "rm" => remove_file,
+        "list" => list,
+        "stat" => stat,
+        "status" => status,
+        _ => unreachable!(),
     };
 
-    match result.uf_unwrap() {
-        Ok(d) => d.warning.display(),
-        Err(e) => e.display(false),
+    match callback(args, &client) {
+        Ok(outcome) => report(format, command, outcome),
+        Err(e) => fail(format, command, e),
     }
 
-    fn encrypt_file(
-        cmd: clap::ArgMatches,
-        mut stream: UnixStream,
-        mut warnings: WarningArray,
-        errors: ErrorArray,
-    ) -> uf<OkWarning<Option<String>>> {
-        // ensuring path exists
-        let file_path: PathType = match get_file_path(
-            errors.clone(),
-            warnings.clone(),
-            cmd.get_one::<PathBuf>("path"),
-        )
-        .uf_unwrap()
-        {
-            Ok(d) => {
-                d.warning.display();
-                d.data
-            }
-            Err(e) => return uf::new(Err(e)),
-        };
-
-        // Changing owner ship of the file
-        let (uid, gid) = get_id();
-        if let Err(err) = set_file_ownership(&file_path.to_path_buf(), uid, gid, errors.clone()).uf_unwrap() {
-            return uf::new(Err(err))
-        }
-
-        // Creating the command to send
-        let request_data = RequestRecsWrite {
-            path: file_path,
-            owner: cmd
-                .get_one::<String>("owner")
-                .unwrap_or(&String::from("system"))
-                .to_owned(),
-            name: cmd
-                .get_one::<String>("name")
-                .unwrap_or(&String::from("lost"))
-                .to_string(),
-            uid: u32::from(geteuid()),
-        };
+    fn encrypt_file(cmd: &clap::ArgMatches, client: &DusaClient) -> Result<Outcome> {
+        let (owner, name) = (get_owner(cmd), get_name(cmd));
 
-        let msg = Message {
-            version: VERSION.to_owned(),
-            msg_type: MessageType::Request,
-            payload: serde_json::to_value(RequestPayload::Write(request_data)).unwrap(),
-            error: None,
+        // Without a path, or with "-", whatever is piped in gets stored
+        let stored = match cmd.get_one::<PathBuf>("path") {
+            Some(path) if path.as_os_str() != "-" => client.store_file(path, &owner, &name),
+            _ => client.store_reader(&mut io::stdin().lock(), &owner, &name, None),
         };
+        stored.map(Outcome::Message)
+    }
 
-        // Communicating with server
-        if let Err(err) = send_message(&mut stream, &msg, errors.clone()).uf_unwrap() {
-            return uf::new(Err(err))
-        }
-        std::thread::sleep(Duration::from_nanos(100));
-        let response = receive_message(&mut stream, errors.clone()).unwrap();
-
-        match response.msg_type {
-            MessageType::Response => {
-                let response_data = response.payload;
-                let msg = response_data
-                    .get("Ok")
-                    .and_then(|v| v.as_str().map(|s| s.to_string()));
-
-                // ping the file from the server 
-
-                // delete the original file
+    fn decrypt_file(cmd: &clap::ArgMatches, client: &DusaClient) -> Result<Outcome> {
+        let mut data = client.open_file(&get_owner(cmd), &get_name(cmd))?;
 
-                pass(&format!("{}", msg.unwrap()));
-            }
-            MessageType::ErrorResponse => {
-                halt(&format!("We received the following error: {}", response.payload));
-            }
-            _ => {
-                let msg = String::from("Server responded in an unexpected way, ignoring ...");
-                warnings.push(WarningArrayItem::new_details(
-                    Warnings::Warning,
-                    msg,
-                ))
+        // Never the stored path, anyone who may write to the owner could have picked it
+        let destination: &PathBuf = match cmd.get_one::<PathBuf>("destination") {
+            Some(d) => d,
+            None => {
+                let missing = io::Error::new(io::ErrorKind::InvalidInput, "no destination given");
+                return Err(ClientError::Output(missing));
             }
-        }
-
-        uf::new(Ok(OkWarning {
-            data: None,
-            warning: warnings,
-        }))
+        };
+        // Plaintext is only for us to read, and never written over something already there
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(destination)
+            .and_then(|mut dest| io::copy(&mut data.file, &mut dest))
+            .map_err(ClientError::Output)?;
+
+        Ok(Outcome::Message(String::from("done")))
     }
 
-    fn decrypt_file(
-        cmd: clap::ArgMatches,
-        mut stream: UnixStream,
-        mut warnings: WarningArray,
-        mut errors: ErrorArray,
-    ) -> uf<OkWarning<Option<String>>> {
-        let request_data = RequestRecsSimple {
-            command: dusa_common::Commands::DecryptFile,
-            owner: cmd
-                .get_one::<String>("owner")
-                .unwrap_or(&String::from("system"))
-                .to_owned(),
-            name: cmd
-                .get_one::<String>("name")
-                .unwrap_or(&String::from("lost"))
-                .to_string(),
-            uid: u32::from(geteuid()),
-        };
+    fn stream_file(cmd: &clap::ArgMatches, client: &DusaClient) -> Result<Outcome> {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
 
-        let msg = Message {
-            version: VERSION.to_owned(),
-            msg_type: MessageType::Request,
-            payload: serde_json::to_value(RequestPayload::Simple(request_data)).unwrap(),
-            error: None,
+        let (owner, name): (String, String) = match cmd.get_one::<(String, String)>("entry") {
+            Some(d) => d.clone(),
+            None => (get_owner(cmd), get_name(cmd)),
         };
 
-        // Communicating with server
-        if let Err(err) =
-        send_message(&mut stream, &msg, errors.clone())
-            .uf_unwrap()
-    {
-        err.display(false)
+        // Nothing else is printed, stdout only carries the plaintext
+        match client.stream_file(&owner, &name, &mut out) {
+            Ok(_) => Ok(Outcome::Written),
+            // Whoever we were piping into has seen enough
+            Err(ClientError::Output(e)) if e.kind() == io::ErrorKind::BrokenPipe => exit(0),
+            Err(e) => Err(e),
+        }
     }
-        // std::thread::sleep(Duration::from_nanos(100));
-        let response = receive_message(&mut stream, errors.clone()).unwrap();
-
-        match response.msg_type {
-            MessageType::Response => {
-                let response_data = response.payload;
-                let data = DecryptResponseData {
-                    temp_p: response_data
-                        .get("temp_p")
-                        .and_then(|v| v.get("Content"))
-                        .and_then(|v| v.as_str())
-                        .map(|s| PathType::Content(s.to_string()))
-                        .unwrap_or_else(|| PathType::Content("/tmp/null".to_string())),                
-                    orig_p: response_data
-                        .get("orig_p")
-                        .and_then(|v| v.get("PathBuf"))
-                        .and_then(|v| v.as_str())
-                        .map(|s| PathType::Content(s.to_string()))
-                        .unwrap_or_else(|| PathType::Content("/tmp/null".to_string())),  
-                    ttl: response_data
-                        .get("ttl")
-                        .and_then(|v| v.get("secs"))
-                        .and_then(|v| v.as_u64())
-                        .map(|t| Duration::from_secs(t))
-                        .unwrap_or(Duration::from_secs(5)), // keep the timing tight
-                };
-                let data_cloned = data.clone();
 
-                // Send an ACK message
-                let ack = Message {
-                    version: VERSION.to_owned(),
-                    msg_type: MessageType::Acknowledge,
-                    payload: serde_json::json!({}),
-                    error: None,
-                };
-                send_message(&mut stream, &ack, errors.clone());
-                let _ = receive_message(&mut stream, errors.clone()).unwrap();
-
-                // copy the file to the original path
-                match fs::copy(data.temp_p, data.orig_p) {
-                    Ok(d) => if d != 0 {
-                        log::log(format!("{:#?}", data_cloned));
-                        pass("done");
-                        unreachable!()
-                    },
-                    Err(e) => {
-                        errors.push(ErrorArrayItem::from(e));
-                        errors.display(true);
-                        unreachable!()
-                    },
-                }
-            }
-            MessageType::ErrorResponse => {
-                halt(&format!("{}", response.payload));
-            }
-            _ => {
-                let msg = String::from("Server responded in an unexpected way, ignoring ...");
-                warnings.push(WarningArrayItem::new_details(
-                    Warnings::Warning,
-                    msg,
-                ))
-            }
+    fn encrypt_text(cmd: &clap::ArgMatches, client: &DusaClient) -> Result<Outcome> {
+        let encoding: Encoding = get_encoding(cmd);
+        match cmd.get_flag("batch") {
+            true => batch(client, Commands::EncryptRawText, encoding),
+            false => client
+                .encrypt_encoded(&get_data(cmd), encoding)
+                .map(Outcome::Value),
         }
-
-        return uf::new(Ok(OkWarning {
-            data: None,
-            warning: warnings,
-        }));
     }
 
-    fn encrypt_text(
-        cmd: clap::ArgMatches,
-        mut stream: UnixStream,
-        mut warnings: WarningArray,
-        errors: ErrorArray,
-    ) -> uf<OkWarning<Option<String>>> {
-        let request_data = RequestRecsPlainText {
-            command: dusa_common::Commands::EncryptRawText,
-            data: cmd
-                .get_one::<String>("data")
-                .unwrap_or(&String::from("hello world"))
-                .to_owned(),
-            uid: u32::from(geteuid()),
-        };
-
-        let msg = Message {
-            version: VERSION.to_owned(),
-            msg_type: MessageType::Request,
-            payload: serde_json::to_value(RequestPayload::PlainText(request_data)).unwrap(),
-            error: None,
-        };
-
-        // Communicating with server
-        let _ = send_message(&mut stream, &msg, errors.clone());
-        std::thread::sleep(Duration::from_nanos(100));
-        let response = receive_message(&mut stream, errors.clone()).unwrap();
-
-        match response.msg_type {
-            MessageType::Response => {
-                let response_data = response.payload;
-
-                // Send an ACK message
-                let ack = Message {
-                    version: VERSION.to_owned(),
-                    msg_type: MessageType::Acknowledge,
-                    payload: serde_json::json!({}),
-                    error: None,
-                };
-                send_message(&mut stream, &ack, errors.clone());
-                let _ = receive_message(&mut stream, errors.clone()).unwrap();
-
-                pass(
-                    response_data
-                        .get("value")
-                        .and_then(|v| v.as_str())
-                        .unwrap_or("Invalid data received"),
-                );
-            }
-            MessageType::ErrorResponse => {
-                halt(&format!("{}", response.payload));
-            }
-            _ => {
-                let msg = String::from("Server responded in an unexpected way, ignoring ...");
-                warnings.push(WarningArrayItem::new_details(
-                    Warnings::Warning,
-                    msg,
-                ))
-            }
-        };
-
-        return uf::new(Ok(OkWarning {
-            warning: warnings,
-            data: None,
-        }));
+    fn decrypt_text(cmd: &clap::ArgMatches, client: &DusaClient) -> Result<Outcome> {
+        let encoding: Encoding = get_encoding(cmd);
+        match cmd.get_flag("batch") {
+            true => batch(client, Commands::DecryptRawText, encoding),
+            false => client
+                .decrypt_encoded(&get_data(cmd), encoding)
+                .map(Outcome::Value),
+        }
     }
 
-    fn decrypt_text(
-        cmd: clap::ArgMatches,
-        mut stream: UnixStream,
-        mut warnings: WarningArray,
-        errors: ErrorArray,
-    ) -> uf<OkWarning<Option<String>>> {
-        let request_data = RequestRecsPlainText {
-            command: dusa_common::Commands::DecryptRawText,
-            data: cmd
-                .get_one::<String>("data")
-                .unwrap_or(&String::from("hello world"))
-                .to_owned(),
-            uid: u32::from(geteuid()),
-        };
-
-        let msg = Message {
-            version: VERSION.to_owned(),
-            msg_type: MessageType::Request,
-            payload: serde_json::to_value(RequestPayload::PlainText(request_data)).unwrap(),
-            error: None,
-        };
-
-        // Communicating with server
-        let _ = send_message(&mut stream, &msg, errors.clone());
-        std::thread::sleep(Duration::from_nanos(100));
-        let response = receive_message(&mut stream, errors.clone()).unwrap();
-
-        match response.msg_type {
-            MessageType::Response => {
-                let response_data = response.payload;
-
-                // Send an ACK message
-                let ack = Message {
-                    version: VERSION.to_owned(),
-                    msg_type: MessageType::Acknowledge,
-                    payload: serde_json::json!({}),
-                    error: None,
-                };
-                send_message(&mut stream, &ack, errors.clone());
-                let _ = receive_message(&mut stream, errors.clone()).unwrap();
-
-                pass(
-                    response_data
-                        .get("value")
-                        .and_then(|v| v.as_str())
-                        .unwrap_or("Invalid data received"),
-                );
-            }
-            MessageType::ErrorResponse => {
-                halt(&format!("{}", response.payload));
-            }
-            _ => {
-                let msg = String::from("Server responded in an unexpected way, ignoring ...");
-                warnings.push(WarningArrayItem::new_details(
-                    Warnings::Warning,
-                    msg,
-                ))
-            }
-        };
-
-        return uf::new(Ok(OkWarning {
-            warning: warnings,
-            data: None,
-        }));
+    fn batch(client: &DusaClient, command: Commands, encoding: Encoding) -> Result<Outcome> {
+        let (ids, items) = read_batch(io::stdin().lock(), command, encoding)?;
+        let sent: usize = items.len();
+        let results: Vec<BatchResult> = client.batch(items)?;
+        if results.len() != sent {
+            return Err(ClientError::UnexpectedResponse(format!(
+                "{} results for a batch of {}",
+                results.len(),
+                sent
+            )));
+        }
+        Ok(Outcome::Batch(ids.into_iter().zip(results).collect()))
     }
 
-    fn remove_file(
-        cmd: clap::ArgMatches,
-        mut stream: UnixStream,
-        mut warnings: WarningArray,
-        errors: ErrorArray,
-    ) -> uf<OkWarning<Option<String>>> {
-        let request_data = RequestRecsSimple {
-            command: dusa_common::Commands::RemoveFile,
-            owner: cmd
-                .get_one::<String>("owner")
-                .unwrap_or(&String::from("system"))
-                .to_owned(),
-            name: cmd
-                .get_one::<String>("name")
-                .unwrap_or(&String::from("lost"))
-                .to_string(),
-            uid: u32::from(geteuid()),
-        };
-
-        let msg = Message {
-            version: VERSION.to_owned(),
-            msg_type: MessageType::Request,
-            payload: serde_json::to_value(RequestPayload::Simple(request_data)).unwrap(),
-            error: None,
-        };
-
-        // Communicating with server
-        let _ = send_message(&mut stream, &msg, errors.clone());
-        std::thread::sleep(Duration::from_nanos(100));
-        let response = receive_message(&mut stream, errors.clone()).unwrap();
-
-        match response.msg_type {
-            MessageType::Response => {
-                let response_data = response.payload;
-
-                // Send an ACK message
-                let ack = Message {
-                    version: VERSION.to_owned(),
-                    msg_type: MessageType::Acknowledge,
-                    payload: serde_json::json!({}),
-                    error: None,
-                };
-                send_message(&mut stream, &ack, errors.clone());
-                let _ = receive_message(&mut stream, errors.clone()).unwrap();
-
-                pass(
-                    response_data
-                        .get("value")
-                        .and_then(|v| v.as_str())
-                        .unwrap_or("Invalid data recived"),
-                );
-            }
-            MessageType::ErrorResponse => {
-                halt(&format!("{}", response.payload));
-            }
-            _ => {
-                let msg = String::from("Server responded in an unexpected way, ignoring ...");
-                warnings.push(WarningArrayItem::new_details(
-                    Warnings::Warning,
-                    msg,
-                ))
-            }
-        };
-
-        return uf::new(Ok(OkWarning {
-            warning: warnings,
-            data: None,
-        }));
+    fn remove_file(cmd: &clap::ArgMatches, client: &DusaClient) -> Result<Outcome> {
+        client
+            .remove(&get_owner(cmd), &get_name(cmd))
+            .map(|_| Outcome::Message(String::from("Ok")))
     }
 
-    fn get_file_path(
-        mut errors: ErrorArray,
-        _warnings: WarningArray,
-        option_path_ref: Option<&PathBuf>,
-    ) -> uf<OkWarning<PathType>> {
-        let err = match option_path_ref {
-            Some(d) => match d.to_path_buf().canonicalize() {
-                Ok(d) => {
-                    let result = OkWarning {
-                        data: PathType::PathBuf(d),
-                        warning: _warnings,
-                    };
-                    return uf::new(Ok(result));
-                }
-                Err(err) => ErrorArrayItem::from(err),
-            },
-            None => ErrorArrayItem::new(Errors::InvalidFile, "".to_owned()),
-        };
-        errors.push(err);
-        return uf::new(Err(errors));
+    fn list(cmd: &clap::ArgMatches, client: &DusaClient) -> Result<Outcome> {
+        let owner: Option<&String> = cmd.get_one::<String>("owner");
+        client.list(owner.map(String::as_str)).map(Outcome::Entries)
     }
 
-    // let request_data = RequestRecsWrite {
-    //     command: "write".to_string(),
-    //     path: "/example/path".to_string(),
-    //     owner: "user".to_string(),
-    //     name: "example".to_string(),
-    //     uid: 1,
-    // };
-
-    // msg_vec.push(Message {
-    //     version: VERSION.to_owned(),
-    //     msg_type: MessageType::Request,
-    //     payload: serde_json::to_value(RequestPayload::Write(request_data)).unwrap(),
-    //     error: None,
-    // });
-
-    // msg_vec.push(Message {
-    //     version: VERSION.to_owned(),
-    //     msg_type: MessageType::Simple,
-    //     payload: json!({"Key": "Hello world"}),
-    //     error: None,
-    // });
-
-    // let request_data = RequestRecsPlainText {
-    // command: "Write raw".to_string(),
-    // data: "32333666336336313233313439643065303464356538353963356139316637303d33303331326435323331326533303265333232643335333036363336333336343634333933323334333736313632333436323330333436313632363232643331613361323366313237333935633462366537663832343266343838663761353364653235373364353864613839633964356136396234623234643939333530666745744162617432317769333334546f646533663735333761646536643136633562376631376264363037316464646430343639393937333332303861343764653739363637323230383435353434653d31".to_string(),
-    // uid: 1,
-    // };
-
-    // let request_data = RequestRecsWrite {
-    //     path: PathType::Content("/tmp/dusa/general.log".to_string()),
-    //     owner: String::from("dwhitfield"),
-    //     name: String::from("test"),
-    //     uid: 1000,
-    // };
-
-    // let request_data = RequestRecsSimple {
-
-    // }
-
-    // let request_data = RequestRecsPlainText {
-    //     uid: 1000,
-    //     command: dusa_common::Commands::EncryptRawText,
-    //     data: "hello world".to_string(),
-    // };
-
-    // let msg = Message {
-    //     version: VERSION.to_owned(),
-    //     msg_type: MessageType::Request,
-    //     payload: serde_json::to_value(RequestPayload::Write(request_data)).unwrap(),
-    //     error: None,
-    // };
-
-    // let _ = send_message(&mut stream, &msg, e1.clone());
-
-    // // print!("{:#?}", receive_message(&mut stream, e1.clone()));
-    // std::thread::sleep(Duration::from_nanos(100));
-    // let response = receive_message(&mut stream, e1.clone()).unwrap();
+    fn stat(cmd: &clap::ArgMatches, client: &DusaClient) -> Result<Outcome> {
+        let (owner, name) = (get_owner(cmd), get_name(cmd));
+        let stat = client.stat(&owner, &name)?;
+        Ok(Outcome::Stat { owner, name, stat })
+    }
 
-    // match response.msg_type {
-    // MessageType::Response => todo!(),
-    // MessageType::ErrorResponse => todo!(),
-    // MessageType::Simple => todo!(),
-    // MessageType::Acknowledge => todo!(),
-    // }
-    // print!("{:#?}", response);
+    fn status(_: &clap::ArgMatches, client: &DusaClient) -> Result<Outcome> {
+        client.status().map(Outcome::Status)
+    }
 
-    // if let Some(error) = response.error {
-    //     match error.code {
-    //         ErrorCode::UnknownMessageType => {
-    //             println!("Error: Unknown message type");
-    //         }
-    //         ErrorCode::InvalidPayload => {
-    //             println!("Error: Invalid payload");
-    //         }
-    //         ErrorCode::InternalError => {
-    //             println!("Error: Internal error");
-    //         }
-    //         ErrorCode::InvalidVersion => {
-    //             println!("Error: We are'nt speaking the same language")
-    //         }
-    //         ErrorCode::InvalidPermissions => {
-    //             println!("The bouncer said our id was fake")
-    //         } // Handle more error codes as needed
-    //     }
-    //     println!("Error message: {}", error.message);
-    // } else {
-    //     if response.msg_type == MessageType::Acknowledge {
-    //         println!("Received ACK from server, closing connection.");
-    //         // return to whatevet
-    //         // std::process::exit(0)
-    //     } else {
-    //         println!("Received response: {:?}", response);
-    //     }
-    // }
+    fn get_owner(cmd: &clap::ArgMatches) -> String {
+        cmd.get_one::<String>("owner").cloned().unwrap_or_default()
+    }
 
-    // // Send an ACK message
-    // let ack = Message {
-    //     version: VERSION.to_owned(),
-    //     msg_type: MessageType::Acknowledge,
-    //     payload: serde_json::json!({}),
-    //     error: None,
-    // };
-    // let ack_data = serde_json::to_vec(&ack).unwrap();
-    // stream.write_all(&ack_data).unwrap();
+    fn get_name(cmd: &clap::ArgMatches) -> String {
+        cmd.get_one::<String>("name").cloned().unwrap_or_default()
+    }
 
-    // // Read the server's ACK message before closing
-    // let n = stream.read(&mut buffer).unwrap();
-    // let server_ack: Message<serde_json::Value> = serde_json::from_slice(&buffer[..n]).unwrap();
-    // if server_ack.msg_type == MessageType::Acknowledge {
-    //     println!("Received ACK from server, closing connection.");
-    // }
+    fn get_encoding(cmd: &clap::ArgMatches) -> Encoding {
+        cmd.get_one::<Encoding>("encoding")
+            .copied()
+            .unwrap_or_default()
+    }
 
+    fn get_data(cmd: &clap::ArgMatches) -> String {
+        cmd.get_one::<String>("data").cloned().unwrap_or_default()
+    }
 }
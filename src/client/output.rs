@@ -0,0 +1,565 @@
+use std::{
+    io::{self, Write},
+    process::exit,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use dusa_collection_utils::errors::ErrorArray;
+use dusa_common::{client::ClientError, BatchResult, DaemonStatus, EntryInfo, ErrorCode, FileStat};
+use serde_json::{json, Value};
+use simple_pretty::{pass, warn};
+
+// 1 is a local error, 2 a usage error from clap and 3 to 15 are taken by [`exit_code`], the
+// outcomes that aren't errors come after them. Error codes added to the daemon later go after
+// those, from 19 on.
+
+/// Exit code of a stat for an entry that doesn't exist.
+pub const EXIT_NOT_FOUND: i32 = 16;
+/// Exit code of a status when the daemon answers but reports problems.
+pub const EXIT_UNHEALTHY: i32 = 17;
+/// Exit code of a batch when some of its items failed.
+pub const EXIT_BATCH_FAILED: i32 = 18;
+
+/// How results and errors are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Coloured messages for people.
+    Pretty,
+    /// Just the value, for shell scripts.
+    Plain,
+    /// One json document per run.
+    Json,
+}
+
+impl OutputFormat {
+    pub fn from_arg(arg: Option<&String>) -> Self {
+        match arg.map(String::as_str) {
+            Some("plain") => OutputFormat::Plain,
+            Some("json") => OutputFormat::Json,
+            _ => OutputFormat::Pretty,
+        }
+    }
+}
+
+/// What a command produced.
+#[derive(Debug)]
+pub enum Outcome {
+    /// A status message such as "stored" or "removed".
+    Message(String),
+    /// A value a script wants to capture, like a ciphertext.
+    Value(String),
+    /// The result of a listing.
+    Entries(Vec<EntryInfo>),
+    /// The result of a stat.
+    Stat {
+        owner: String,
+        name: String,
+        stat: FileStat,
+    },
+    /// The daemon's status.
+    Status(DaemonStatus),
+    /// The results of a batch, each with the id its input line had.
+    Batch(Vec<(Option<Value>, BatchResult)>),
+    /// The command already wrote its output, as `cat` does.
+    Written,
+}
+
+/// The exit code for a failed command. Every [`ErrorCode`] the daemon can answer with has its own.
+pub fn exit_code(err: &ClientError) -> i32 {
+    match err {
+        ClientError::Server(e) => match e.code {
+            ErrorCode::InvalidPermissions => 3,
+            ErrorCode::InvalidPayload => 4,
+            ErrorCode::InvalidVersion => 5,
+            ErrorCode::UnknownMessageType => 6,
+            ErrorCode::InternalError => 7,
+            ErrorCode::FrameTooLarge => 10,
+            ErrorCode::MalformedFrame => 11,
+            ErrorCode::Timeout => 12,
+            ErrorCode::Busy => 13,
+            ErrorCode::Unsupported => 14,
+            ErrorCode::InvalidEncoding => 15,
+            ErrorCode::UploadTooLarge => 19,
+        },
+        ClientError::Connection(_) => 8,
+        ClientError::UnexpectedResponse(_) => 9,
+        ClientError::Io(_) | ClientError::Output(_) => 1,
+    }
+}
+
+/// Writes a command's result and exits with the matching code.
+pub fn report(format: OutputFormat, command: &str, outcome: Outcome) -> ! {
+    let code: i32 = outcome_code(&outcome);
+
+    match format {
+        OutputFormat::Pretty => pretty(outcome),
+        OutputFormat::Plain => print_out(&plain(outcome)),
+        OutputFormat::Json => {
+            if let Some(document) = result_document(command, outcome) {
+                print_out(&format!("{}\n", document));
+            }
+        }
+    }
+    exit(code)
+}
+
+/// Writes a command's error and exits with the code for it.
+///
+/// Json errors go to stdout like results do, unless the command streams data there.
+pub fn fail(format: OutputFormat, command: &str, err: ClientError) -> ! {
+    let code: i32 = exit_code(&err);
+
+    match format {
+        OutputFormat::Pretty => err.into_errors(ErrorArray::new_container()).display(false),
+        OutputFormat::Plain => eprintln!("{}", err),
+        OutputFormat::Json => {
+            let document: Value = error_document(command, &err);
+            match command {
+                "cat" => eprintln!("{}", document),
+                _ => print_out(&format!("{}\n", document)),
+            }
+        }
+    }
+    exit(code)
+}
+
+/// The exit code for a command that got as far as `outcome`.
+fn outcome_code(outcome: &Outcome) -> i32 {
+    match outcome {
+        Outcome::Stat { stat, .. } if !stat.exists => EXIT_NOT_FOUND,
+        Outcome::Status(status) if !status.healthy() => EXIT_UNHEALTHY,
+        Outcome::Batch(results)
+            if results
+                .iter()
+                .any(|(_, r)| matches!(r, BatchResult::Error(_))) =>
+        {
+            EXIT_BATCH_FAILED
+        }
+        _ => 0,
+    }
+}
+
+/// The json document for a command's result, `None` if the command already wrote its output.
+fn result_document(command: &str, outcome: Outcome) -> Option<Value> {
+    let result: Value = match outcome {
+        Outcome::Written => return None,
+        Outcome::Message(d) => json!({ "message": d }),
+        Outcome::Value(d) => json!({ "value": d }),
+        Outcome::Entries(d) => {
+            let entries: Vec<Value> = d
+                .iter()
+                .map(|e| {
+                    json!({
+                        "owner": e.owner,
+                        "name": e.name,
+                        "size": e.size,
+                        "stored": e.stored,
+                        "original_path": e.orig_p.as_ref().map(|p| p.to_string()),
+                    })
+                })
+                .collect();
+            json!({ "entries": entries })
+        }
+        Outcome::Stat { owner, name, stat } => json!({
+            "owner": owner,
+            "name": name,
+            "exists": stat.exists,
+            "size": stat.size,
+            "created": stat.created,
+            "original_path": stat.orig_p.map(|p| p.to_string()),
+        }),
+        Outcome::Status(d) => json!({
+            "healthy": d.healthy(),
+            "version": d.version,
+            "uptime": d.uptime,
+            "recs_initialized": d.recs_initialized,
+            "active_connections": d.active_connections,
+            "pending_temp_files": d.pending_temp_files,
+            "counters": d.counters,
+            "problems": d.problems,
+        }),
+        Outcome::Batch(d) => {
+            let items: Vec<Value> = d.iter().map(batch_result).collect();
+            json!({ "items": items })
+        }
+    };
+    Some(json!({ "ok": true, "command": command, "result": result }))
+}
+
+/// The json document for a command's error.
+fn error_document(command: &str, err: &ClientError) -> Value {
+    let error: Value = match err {
+        ClientError::Server(e) => json!({ "code": e.code, "message": e.message }),
+        _ => json!({ "code": kind(err), "message": err.to_string() }),
+    };
+    json!({ "ok": false, "command": command, "error": error })
+}
+
+/// A batch result as json, `{"value": ...}` or `{"error": ...}` next to the id of its line.
+fn batch_result((id, result): &(Option<Value>, BatchResult)) -> Value {
+    let mut value: Value = match result {
+        BatchResult::Text(d) => json!({ "value": d }),
+        BatchResult::Error(e) => json!({ "error": { "code": e.code, "message": e.message } }),
+    };
+    if let Some(id) = id {
+        value["id"] = id.clone();
+    }
+    value
+}
+
+/// Batch results as one json object per line, in the order of the input lines.
+fn batch_lines(results: &[(Option<Value>, BatchResult)]) -> String {
+    results
+        .iter()
+        .map(|r| format!("{}\n", batch_result(r)))
+        .collect()
+}
+
+/// Names the errors that happen on the client side, in the style of [`ErrorCode`].
+fn kind(err: &ClientError) -> &'static str {
+    match err {
+        ClientError::Connection(_) => "ConnectionError",
+        ClientError::Io(_) => "InputOutput",
+        ClientError::Output(_) => "OutputError",
+        ClientError::Server(_) => "ServerError",
+        ClientError::UnexpectedResponse(_) => "UnexpectedResponse",
+    }
+}
+
+fn pretty(outcome: Outcome) {
+    match outcome {
+        Outcome::Message(d) | Outcome::Value(d) => pass(&d),
+        Outcome::Entries(entries) => {
+            let mut table: String = format!(
+                "{:<16} {:<24} {:>12} {:<20} ORIGINAL PATH\n",
+                "OWNER", "NAME", "SIZE", "STORED"
+            );
+            for entry in &entries {
+                table.push_str(&format!(
+                    "{:<16} {:<24} {:>12} {:<20} {}\n",
+                    entry.owner,
+                    entry.name,
+                    or_dash(entry.size.map(|s| s.to_string())),
+                    or_dash(entry.stored.map(format_time)),
+                    or_dash(entry.orig_p.as_ref().map(|p| p.to_string())),
+                ));
+            }
+            print_out(&table);
+        }
+        Outcome::Stat { owner, name, stat } if stat.exists => print_out(&format!(
+            "entry:    {}/{}\nsize:     {}\ncreated:  {}\noriginal: {}\n",
+            owner,
+            name,
+            or_dash(stat.size.map(|s| s.to_string())),
+            or_dash(stat.created.map(format_time)),
+            or_dash(stat.orig_p.map(|p| p.to_string())),
+        )),
+        Outcome::Stat { owner, name, .. } => warn(&format!("{}/{} does not exist", owner, name)),
+        Outcome::Status(status) => {
+            let mut rows: Vec<(String, String)> = vec![
+                (String::from("version"), status.version.clone()),
+                (
+                    String::from("uptime"),
+                    humantime::format_duration(Duration::from_secs(status.uptime)).to_string(),
+                ),
+                (
+                    String::from("recs initialized"),
+                    status.recs_initialized.to_string(),
+                ),
+                (
+                    String::from("active connections"),
+                    status.active_connections.to_string(),
+                ),
+                (
+                    String::from("pending temp files"),
+                    status.pending_temp_files.to_string(),
+                ),
+            ];
+            rows.extend(
+                status
+                    .counters
+                    .iter()
+                    .map(|(name, value)| (name.replace('_', " "), value.to_string())),
+            );
+            let width: usize = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0) + 2;
+            let text: String = rows
+                .iter()
+                .map(|(name, value)| format!("{:<width$}{}\n", format!("{}:", name), value))
+                .collect();
+            print_out(&text);
+            match status.problems.is_empty() {
+                true => pass("healthy"),
+                false => status.problems.iter().for_each(|p| warn(p)),
+            }
+        }
+        // Batches are read as json lines and answered the same way
+        Outcome::Batch(results) => print_out(&batch_lines(&results)),
+        Outcome::Written => (),
+    }
+}
+
+/// The plain text for a command's result.
+fn plain(outcome: Outcome) -> String {
+    match outcome {
+        Outcome::Message(d) | Outcome::Value(d) => format!("{}\n", d),
+        // One tab separated line per entry: owner, name, size, stored, original path
+        Outcome::Entries(entries) => entries
+            .iter()
+            .map(|e| {
+                format!(
+                    "{}\t{}\t{}\t{}\t{}\n",
+                    e.owner,
+                    e.name,
+                    or_dash(e.size.map(|s| s.to_string())),
+                    or_dash(e.stored.map(|s| s.to_string())),
+                    or_dash(e.orig_p.as_ref().map(|p| p.to_string())),
+                )
+            })
+            .collect(),
+        Outcome::Stat { stat, .. } if stat.exists => format!(
+            "{}\t{}\t{}\n",
+            or_dash(stat.size.map(|s| s.to_string())),
+            or_dash(stat.created.map(|s| s.to_string())),
+            or_dash(stat.orig_p.map(|p| p.to_string())),
+        ),
+        // One tab separated name and value per line
+        Outcome::Status(status) => {
+            let mut lines: String = format!(
+                "healthy\t{}\nversion\t{}\nuptime\t{}\nrecs_initialized\t{}\nactive_connections\t{}\npending_temp_files\t{}\n",
+                status.healthy(),
+                status.version,
+                status.uptime,
+                status.recs_initialized,
+                status.active_connections,
+                status.pending_temp_files,
+            );
+            for (name, value) in &status.counters {
+                lines.push_str(&format!("{}\t{}\n", name, value));
+            }
+            for problem in &status.problems {
+                lines.push_str(&format!("problem\t{}\n", problem));
+            }
+            lines
+        }
+        Outcome::Batch(results) => batch_lines(&results),
+        Outcome::Stat { .. } | Outcome::Written => String::new(),
+    }
+}
+
+/// Writes command output to stdout, a reader that went away early is not an error.
+pub fn print_out(text: &str) {
+    let mut out = io::stdout().lock();
+    if let Err(e) = out.write_all(text.as_bytes()).and_then(|_| out.flush()) {
+        if e.kind() == io::ErrorKind::BrokenPipe {
+            exit(0)
+        }
+    }
+}
+
+fn or_dash(value: Option<String>) -> String {
+    value.unwrap_or_else(|| String::from("-"))
+}
+
+fn format_time(secs: u64) -> String {
+    let time = UNIX_EPOCH + Duration::from_secs(secs);
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, io, path::PathBuf};
+
+    use dusa_collection_utils::{
+        errors::{ErrorArrayItem, Errors},
+        types::PathType,
+    };
+    use dusa_common::DusaError;
+
+    use super::*;
+
+    fn server_error(code: ErrorCode) -> ClientError {
+        ClientError::Server(DusaError {
+            code,
+            message: String::from("refused"),
+        })
+    }
+
+    fn entry(owner: &str, name: &str, size: Option<u64>) -> EntryInfo {
+        EntryInfo {
+            owner: owner.to_owned(),
+            name: name.to_owned(),
+            orig_p: size.map(|_| PathType::PathBuf(PathBuf::from("/tmp/db"))),
+            size,
+            stored: size.map(|_| 1_700_000_000),
+        }
+    }
+
+    fn status(problems: &[&str]) -> DaemonStatus {
+        DaemonStatus {
+            version: String::from("1.2.6"),
+            uptime: 60,
+            recs_initialized: true,
+            active_connections: 2,
+            pending_temp_files: 0,
+            counters: BTreeMap::from([(String::from("requests_ok"), 5)]),
+            problems: problems.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    fn stat(exists: bool) -> Outcome {
+        Outcome::Stat {
+            owner: String::from("system"),
+            name: String::from("db"),
+            stat: FileStat {
+                exists,
+                size: exists.then_some(11),
+                created: exists.then_some(1_700_000_000),
+                orig_p: None,
+            },
+        }
+    }
+
+    fn batch(failed: bool) -> Outcome {
+        let mut results = vec![(Some(json!(1)), BatchResult::Text(String::from("c1")))];
+        if failed {
+            results.push((
+                None,
+                BatchResult::Error(DusaError {
+                    code: ErrorCode::InvalidPayload,
+                    message: String::from("bad"),
+                }),
+            ));
+        }
+        Outcome::Batch(results)
+    }
+
+    #[test]
+    fn every_error_has_its_own_exit_code() {
+        let mut errors = ErrorArray::new_container();
+        errors.push(ErrorArrayItem::new(Errors::GeneralError, String::from("x")));
+        let table: Vec<(ClientError, i32)> = vec![
+            (ClientError::Io(errors), 1),
+            (ClientError::Output(io::Error::other("full")), 1),
+            (server_error(ErrorCode::InvalidPermissions), 3),
+            (server_error(ErrorCode::InvalidPayload), 4),
+            (server_error(ErrorCode::InvalidVersion), 5),
+            (server_error(ErrorCode::UnknownMessageType), 6),
+            (server_error(ErrorCode::InternalError), 7),
+            (ClientError::Connection(io::Error::other("refused")), 8),
+            (ClientError::UnexpectedResponse(String::from("Ack")), 9),
+            (server_error(ErrorCode::FrameTooLarge), 10),
+            (server_error(ErrorCode::MalformedFrame), 11),
+            (server_error(ErrorCode::Timeout), 12),
+            (server_error(ErrorCode::Busy), 13),
+            (server_error(ErrorCode::Unsupported), 14),
+            (server_error(ErrorCode::InvalidEncoding), 15),
+            (server_error(ErrorCode::UploadTooLarge), 19),
+        ];
+        for (err, code) in &table {
+            assert_eq!(exit_code(err), *code, "{}", err);
+        }
+
+        // None of them may be mistaken for an outcome that isn't an error
+        for code in [EXIT_NOT_FOUND, EXIT_UNHEALTHY, EXIT_BATCH_FAILED] {
+            assert!(table.iter().all(|(err, _)| exit_code(err) != code));
+        }
+    }
+
+    #[test]
+    fn outcomes_that_arent_errors_have_their_own_exit_codes() {
+        assert_eq!(outcome_code(&stat(true)), 0);
+        assert_eq!(outcome_code(&stat(false)), EXIT_NOT_FOUND);
+        assert_eq!(outcome_code(&Outcome::Status(status(&[]))), 0);
+        assert_eq!(
+            outcome_code(&Outcome::Status(status(&["recs is not initialized"]))),
+            EXIT_UNHEALTHY
+        );
+        assert_eq!(outcome_code(&batch(false)), 0);
+        assert_eq!(outcome_code(&batch(true)), EXIT_BATCH_FAILED);
+        assert_eq!(outcome_code(&Outcome::Written), 0);
+    }
+
+    #[test]
+    fn results_as_json() {
+        assert_eq!(
+            result_document("encrypt-text", Outcome::Value(String::from("abc"))),
+            Some(json!({ "ok": true, "command": "encrypt-text", "result": { "value": "abc" } }))
+        );
+        assert_eq!(
+            result_document(
+                "list",
+                Outcome::Entries(vec![
+                    entry("system", "db", Some(11)),
+                    entry("me", "x", None)
+                ])
+            ),
+            Some(
+                json!({ "ok": true, "command": "list", "result": { "entries": [
+                { "owner": "system", "name": "db", "size": 11, "stored": 1_700_000_000,
+                  "original_path": "/tmp/db" },
+                { "owner": "me", "name": "x", "size": null, "stored": null,
+                  "original_path": null },
+            ] } })
+            )
+        );
+        assert_eq!(
+            result_document("stat", stat(false)),
+            Some(json!({ "ok": true, "command": "stat", "result": {
+                "owner": "system", "name": "db", "exists": false, "size": null,
+                "created": null, "original_path": null,
+            } }))
+        );
+        assert_eq!(
+            result_document("batch", batch(true)),
+            Some(
+                json!({ "ok": true, "command": "batch", "result": { "items": [
+                { "id": 1, "value": "c1" },
+                { "error": { "code": "InvalidPayload", "message": "bad" } },
+            ] } })
+            )
+        );
+        assert_eq!(result_document("cat", Outcome::Written), None);
+    }
+
+    #[test]
+    fn errors_as_json() {
+        assert_eq!(
+            error_document("rm", &server_error(ErrorCode::InvalidPermissions)),
+            json!({ "ok": false, "command": "rm", "error": {
+                "code": "InvalidPermissions", "message": "refused",
+            } })
+        );
+
+        let document: Value = error_document(
+            "list",
+            &ClientError::UnexpectedResponse(String::from("Ack")),
+        );
+        assert_eq!(document["ok"], json!(false));
+        assert_eq!(document["error"]["code"], json!("UnexpectedResponse"));
+    }
+
+    #[test]
+    fn results_as_plain_text() {
+        assert_eq!(plain(Outcome::Message(String::from("stored"))), "stored\n");
+        assert_eq!(
+            plain(Outcome::Entries(vec![
+                entry("system", "db", Some(11)),
+                entry("me", "x", None)
+            ])),
+            "system\tdb\t11\t1700000000\t/tmp/db\nme\tx\t-\t-\t-\n"
+        );
+        assert_eq!(plain(stat(true)), "11\t1700000000\t-\n");
+        assert_eq!(plain(stat(false)), "");
+        assert_eq!(
+            plain(Outcome::Status(status(&["recs is not initialized"]))),
+            "healthy\tfalse\nversion\t1.2.6\nuptime\t60\nrecs_initialized\ttrue\n\
+             active_connections\t2\npending_temp_files\t0\nrequests_ok\t5\n\
+             problem\trecs is not initialized\n"
+        );
+        assert_eq!(
+            plain(batch(true)),
+            "{\"id\":1,\"value\":\"c1\"}\n\
+             {\"error\":{\"code\":\"InvalidPayload\",\"message\":\"bad\"}}\n"
+        );
+    }
+}
@@ -0,0 +1,730 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use dusa_collection_utils::errors::{ErrorArray, ErrorArrayItem, Errors, UnifiedResult as uf};
+use dusa_common::{
+    config::config, ErrorCode, Message, MessageType, RequestPayload, ResponsePayload,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::peer::PeerCredentials;
+
+/// One line of the audit log.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditRecord {
+    pub time: String,
+    /// The id the request carried in a session.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    pub uid: u32,
+    pub pid: i32,
+    /// The executable of the peer, `None` if the kernel wouldn't tell us. Reading it takes the
+    /// same rights as tracing the peer, so it's usually only there for our own user.
+    pub exe: Option<String>,
+    /// The process name of the peer. Anyone can read it, but the peer can also set it itself.
+    pub comm: Option<String>,
+    /// What was asked for, `None` if the request couldn't be read.
+    pub command: Option<String>,
+    pub owner: Option<String>,
+    pub name: Option<String>,
+    pub outcome: Outcome,
+    pub error: Option<ErrorCode>,
+    /// Hash of the record before this one, only written with `audit_hash_chain`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev: Option<String>,
+}
+
+/// How a request ended.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+/// What is known about a request before it's answered. It starts with the peer and learns more
+/// as the request is read.
+pub struct AuditContext {
+    id: Option<u64>,
+    uid: u32,
+    pid: i32,
+    exe: Option<String>,
+    comm: Option<String>,
+    command: Option<&'static str>,
+    owner: Option<String>,
+    name: Option<String>,
+}
+
+impl AuditContext {
+    /// Starts the context of a connection. The executable is looked up right away, the peer may
+    /// be gone by the time we answer.
+    pub fn new(peer: &PeerCredentials) -> Self {
+        let exe: Option<String> = fs::read_link(format!("/proc/{}/exe", peer.pid))
+            .ok()
+            .map(|p| p.to_string_lossy().into_owned());
+        let comm: Option<String> = fs::read_to_string(format!("/proc/{}/comm", peer.pid))
+            .ok()
+            .map(|d| d.trim_end().to_owned());
+        AuditContext {
+            id: None,
+            uid: peer.uid,
+            pid: peer.pid,
+            exe,
+            comm,
+            command: None,
+            owner: None,
+            name: None,
+        }
+    }
+
+    /// Forgets about the last request of the connection, the next one carries `id`.
+    pub fn next(&mut self, id: Option<u64>) {
+        self.id = id;
+        self.command = None;
+        self.owner = None;
+        self.name = None;
+    }
+
+    /// The id of the request, `None` outside of sessions.
+    pub fn id(&self) -> Option<u64> {
+        self.id
+    }
+
+    /// What was asked for, `None` until the request has been read.
+    pub fn asked_for(&self) -> Option<&'static str> {
+        self.command
+    }
+
+    /// Notes what was asked for.
+    pub fn command(&mut self, command: &'static str) {
+        self.command = Some(command);
+    }
+
+    /// Notes what a request asks for and which entry it's about.
+    pub fn request(&mut self, request: &RequestPayload) {
+        self.command = Some(request.command());
+        let (owner, name): (Option<&str>, Option<&str>) = match request {
+            RequestPayload::Write(req) => (Some(&req.owner), Some(&req.name)),
+            RequestPayload::Upload(req) => (Some(&req.owner), Some(&req.name)),
+            RequestPayload::Simple(req) => (Some(&req.owner), Some(&req.name)),
+            RequestPayload::Query(req) => (req.owner.as_deref(), None),
+            RequestPayload::PlainText(_) | RequestPayload::Batch(_) => (None, None),
+        };
+        self.owner = owner.map(String::from);
+        self.name = name.map(String::from);
+    }
+}
+
+/// The audit log file and where its hash chain stands.
+struct Current {
+    file: File,
+    size: u64,
+    last_hash: Option<String>,
+}
+
+/// An append only JSON lines log of every request, rotated by size.
+pub struct AuditLog {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    hash_chain: bool,
+    current: Mutex<Current>,
+}
+
+impl AuditLog {
+    /// Opens the configured audit log, picking up the hash chain where the last run left it. With
+    /// `audit_hash_chain` the chain gets its anchor if it has none yet.
+    ///
+    /// # Arguments
+    /// * `errors` - An array of errors to be populated if any occur.
+    pub fn open(mut errors: ErrorArray) -> uf<AuditLog> {
+        let path: PathBuf = config().audit_log.clone();
+        let keep: usize = config().audit_keep;
+
+        let opened = open_current(&path, keep).and_then(|mut current| {
+            if config().audit_hash_chain {
+                current.last_hash = Some(anchor_chain(&path, keep, current.last_hash.take())?);
+            }
+            Ok(current)
+        });
+        let current = match opened {
+            Ok(d) => d,
+            Err(e) => {
+                errors.push(ErrorArrayItem::new(
+                    Errors::OpeningFile,
+                    format!("Couldn't open the audit log {}: {}", path.display(), e),
+                ));
+                return uf::new(Err(errors));
+            }
+        };
+
+        uf::new(Ok(AuditLog {
+            path,
+            max_size: config().audit_max_size,
+            keep,
+            hash_chain: config().audit_hash_chain,
+            current: Mutex::new(current),
+        }))
+    }
+
+    /// Records how a request was answered. A record that can't be written is reported but
+    /// doesn't change the answer, it has already been sent.
+    pub fn record(
+        &self,
+        context: &AuditContext,
+        response: &Message<ResponsePayload>,
+        mut errors: ErrorArray,
+    ) {
+        let (outcome, error) = match response.msg_type {
+            MessageType::ErrorResponse => {
+                (Outcome::Failure, response.error.as_ref().map(|e| e.code))
+            }
+            _ => (Outcome::Success, None),
+        };
+        let mut record = AuditRecord {
+            time: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            id: context.id,
+            uid: context.uid,
+            pid: context.pid,
+            exe: context.exe.clone(),
+            comm: context.comm.clone(),
+            command: context.command.map(String::from),
+            owner: context.owner.clone(),
+            name: context.name.clone(),
+            outcome,
+            error,
+            prev: None,
+        };
+
+        let mut current = match self.current.lock() {
+            Ok(d) => d,
+            Err(_) => {
+                errors.push(ErrorArrayItem::new(
+                    Errors::GeneralError,
+                    String::from("The audit log is unavailable"),
+                ));
+                errors.display(false);
+                return;
+            }
+        };
+
+        if self.hash_chain {
+            record.prev = current.last_hash.clone();
+        }
+        let line: String = match serde_json::to_string(&record) {
+            Ok(d) => d,
+            Err(e) => {
+                errors.push(ErrorArrayItem::from(e));
+                errors.display(false);
+                return;
+            }
+        };
+
+        let full: bool = current.size + line.len() as u64 + 1 > self.max_size;
+        if self.max_size > 0 && current.size > 0 && full {
+            if let Err(e) = self.rotate(&mut current) {
+                errors.push(ErrorArrayItem::new(
+                    Errors::GeneralError,
+                    format!("Couldn't rotate the audit log: {}", e),
+                ));
+                errors.clone().display(false);
+            }
+        }
+
+        match current.file.write_all(format!("{}\n", line).as_bytes()) {
+            Ok(_) => {
+                current.size += line.len() as u64 + 1;
+                current.last_hash = Some(hash(&line));
+            }
+            Err(e) => {
+                errors.push(ErrorArrayItem::from(e));
+                errors.display(false);
+            }
+        }
+    }
+
+    /// Moves the current log to `.1`, shifting the older ones up and dropping what's past `keep`,
+    /// then starts a new one. The hash chain carries on into the new file, and its anchor moves
+    /// on to the last record of the file that was dropped.
+    fn rotate(&self, current: &mut Current) -> std::io::Result<()> {
+        let dropped: PathBuf = match self.keep {
+            0 => self.path.clone(),
+            keep => rotated(&self.path, keep),
+        };
+        let anchor: Option<String> = match self.hash_chain {
+            true => chained_end(&dropped)?,
+            false => None,
+        };
+
+        for n in (1..self.keep).rev() {
+            let from: PathBuf = rotated(&self.path, n);
+            if from.exists() {
+                fs::rename(&from, rotated(&self.path, n + 1))?;
+            }
+        }
+        match self.keep {
+            0 => fs::remove_file(&self.path)?,
+            _ => fs::rename(&self.path, rotated(&self.path, 1))?,
+        }
+
+        if let Some(anchor) = anchor {
+            write_anchor(&self.path, &anchor)?;
+        }
+
+        let last_hash: Option<String> = current.last_hash.take();
+        *current = open_current(&self.path, 0)?;
+        current.last_hash = last_hash;
+        Ok(())
+    }
+}
+
+/// Checks the hash chain of the configured audit log, oldest rotation first.
+///
+/// # Returns
+/// A unified result containing how many chained records were checked, or where the chain is
+/// broken.
+pub fn verify_audit(errors: ErrorArray) -> uf<usize> {
+    verify_chain(&config().audit_log, config().audit_keep, errors)
+}
+
+/// Checks the hash chain of the log at `path` and up to `keep` rotations of it. The first chained
+/// record has to chain on from the anchor, so records removed from the start are found too.
+fn verify_chain(path: &Path, keep: usize, mut errors: ErrorArray) -> uf<usize> {
+    let anchor: Option<String> = match read_anchor(path) {
+        Ok(d) => d,
+        Err(e) => {
+            errors.push(ErrorArrayItem::new(
+                Errors::ReadingFile,
+                format!("Couldn't read {}: {}", anchor_path(path).display(), e),
+            ));
+            return uf::new(Err(errors));
+        }
+    };
+
+    let mut checked: usize = 0;
+    let mut last_hash: Option<String> = None;
+    for file in log_files(path, keep) {
+        let reader = match File::open(&file) {
+            Ok(d) => BufReader::new(d),
+            Err(e) => {
+                errors.push(ErrorArrayItem::new(
+                    Errors::OpeningFile,
+                    format!("Couldn't open {}: {}", file.display(), e),
+                ));
+                return uf::new(Err(errors));
+            }
+        };
+
+        for (index, line) in reader.lines().enumerate() {
+            let at: String = format!("{} line {}", file.display(), index + 1);
+            let line: String = match line {
+                Ok(d) => d,
+                Err(e) => {
+                    errors.push(ErrorArrayItem::new(
+                        Errors::ReadingFile,
+                        format!("{}: {}", at, e),
+                    ));
+                    return uf::new(Err(errors));
+                }
+            };
+            let record: AuditRecord = match serde_json::from_str(&line) {
+                Ok(d) => d,
+                Err(e) => {
+                    errors.push(ErrorArrayItem::new(
+                        Errors::InvalidType,
+                        format!("{} is not an audit record: {}", at, e),
+                    ));
+                    return uf::new(Err(errors));
+                }
+            };
+
+            // Records from before the chain was turned on have no hash, the first one with a
+            // hash chains on from the anchor, the others from the record before them
+            let broken: Option<&str> = match (&record.prev, checked) {
+                (None, 0) => None,
+                (None, _) => Some("a record has no hash"),
+                (Some(_), 0) if anchor.is_none() => Some("the chain has no anchor"),
+                (Some(prev), 0) if anchor.as_ref() != Some(prev) => {
+                    Some("records before it were removed")
+                }
+                (Some(prev), _) if checked > 0 && last_hash.as_ref() != Some(prev) => {
+                    Some("it or the record before it was changed")
+                }
+                _ => None,
+            };
+            if let Some(why) = broken {
+                errors.push(ErrorArrayItem::new(
+                    Errors::GeneralError,
+                    format!("The hash chain is broken at {}, {}", at, why),
+                ));
+                return uf::new(Err(errors));
+            }
+
+            last_hash = Some(hash(&line));
+            if record.prev.is_some() {
+                checked += 1;
+            }
+        }
+    }
+
+    uf::new(Ok(checked))
+}
+
+/// Opens the log for appending, creating it readable by the dusa group only.
+///
+/// # Arguments
+/// * `path` - The current log.
+/// * `keep` - How many rotations of it to look through when it's still empty.
+fn open_current(path: &Path, keep: usize) -> std::io::Result<Current> {
+    let file: File = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o640)
+        .open(path)?;
+    let size: u64 = file.metadata()?.len();
+
+    // Chaining on from the last record written, which is in the newest rotation if the log
+    // was rotated right before the daemon stopped
+    let mut last_hash: Option<String> = None;
+    for file in log_files(path, keep).iter().rev() {
+        if let Some(line) = last_line(file)? {
+            last_hash = Some(hash(&line));
+            break;
+        }
+    }
+
+    Ok(Current {
+        file,
+        size,
+        last_hash,
+    })
+}
+
+/// The log at `path` and up to `keep` rotations of it that exist, oldest first.
+fn log_files(path: &Path, keep: usize) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = (1..=keep)
+        .rev()
+        .map(|n| rotated(path, n))
+        .filter(|p| p.exists())
+        .collect();
+    files.push(path.to_path_buf());
+    files
+}
+
+/// The last record in `path`, `None` if it has none or doesn't exist.
+fn last_line(path: &Path) -> std::io::Result<Option<String>> {
+    let file: File = match File::open(path) {
+        Ok(d) => d,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    Ok(BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter(|line| !line.is_empty())
+        .last())
+}
+
+/// The hash of the last record in `path` if that one is chained, what the records after it
+/// chain on from once `path` is gone.
+fn chained_end(path: &Path) -> std::io::Result<Option<String>> {
+    Ok(last_line(path)?.and_then(|line| {
+        let record: AuditRecord = serde_json::from_str(&line).ok()?;
+        record.prev.map(|_| hash(&line))
+    }))
+}
+
+/// Makes sure the chain of the log at `path` has an anchor, and returns the hash the next record
+/// chains on from.
+///
+/// A chain that is already there is anchored where the first chained record of the kept logs
+/// points to. A new one starts from the last record written before it was turned on, or from a
+/// genesis hash when there is none.
+fn anchor_chain(path: &Path, keep: usize, last_hash: Option<String>) -> std::io::Result<String> {
+    let first_prev: Option<String> = log_files(path, keep)
+        .iter()
+        .filter_map(|file| File::open(file).ok())
+        .flat_map(|file| BufReader::new(file).lines().map_while(Result::ok))
+        .filter_map(|line| serde_json::from_str::<AuditRecord>(&line).ok())
+        .find_map(|record| record.prev);
+    let next: String = last_hash.unwrap_or_else(|| {
+        let time = humantime::format_rfc3339_nanos(SystemTime::now());
+        hash(&format!(
+            "dusad audit genesis {} {}",
+            std::process::id(),
+            time
+        ))
+    });
+
+    if read_anchor(path)?.is_none() {
+        write_anchor(path, first_prev.as_ref().unwrap_or(&next))?;
+    }
+    Ok(next)
+}
+
+/// Where the anchor of the chain of `path` is kept, the hash its first kept record chains on
+/// from.
+fn anchor_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".anchor");
+    PathBuf::from(name)
+}
+
+/// Reads the anchor of the chain of `path`, `None` if the chain has none.
+fn read_anchor(path: &Path) -> std::io::Result<Option<String>> {
+    match fs::read_to_string(anchor_path(path)) {
+        Ok(d) => Ok(Some(d.trim().to_owned())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Replaces the anchor of the chain of `path`.
+fn write_anchor(path: &Path, anchor: &str) -> std::io::Result<()> {
+    let anchor_p: PathBuf = anchor_path(path);
+    let temp_p: PathBuf = anchor_p.with_extension("anchor.tmp");
+    let mut file: File = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o640)
+        .open(&temp_p)?;
+    file.write_all(format!("{}\n", anchor).as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp_p, &anchor_p)
+}
+
+/// Where the `n`th newest rotation of `path` lives.
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// The hex encoded sha256 of a record as it was written.
+fn hash(line: &str) -> String {
+    Sha256::digest(line.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+    /// A fresh directory for the logs of one test.
+    fn log_dir(test: &str) -> PathBuf {
+        let dir: PathBuf =
+            std::env::temp_dir().join(format!("dusa-audit-{}-{}", std::process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn record(command: &str, prev: Option<String>) -> String {
+        let record = AuditRecord {
+            time: String::from("2026-01-01T00:00:00.000Z"),
+            id: None,
+            uid: 1000,
+            pid: 42,
+            exe: None,
+            comm: None,
+            command: Some(command.to_string()),
+            owner: Some(String::from("system")),
+            name: Some(String::from("db")),
+            outcome: Outcome::Success,
+            error: None,
+            prev,
+        };
+        serde_json::to_string(&record).unwrap()
+    }
+
+    /// Chains `commands` into records, the first one chaining on from `anchor`.
+    fn chain(anchor: &str, commands: &[&str]) -> Vec<String> {
+        let mut lines: Vec<String> = Vec::new();
+        for command in commands {
+            let prev: String = match lines.last() {
+                Some(line) => hash(line),
+                None => anchor.to_owned(),
+            };
+            lines.push(record(command, Some(prev)));
+        }
+        lines
+    }
+
+    fn write(path: &Path, lines: &[String]) {
+        let mut data: String = lines.join("\n");
+        data.push('\n');
+        fs::write(path, data).unwrap();
+    }
+
+    fn verify(path: &Path) -> Result<usize, ErrorArray> {
+        verify_chain(path, 5, ErrorArray::new_container()).uf_unwrap()
+    }
+
+    /// An audit log in `dir` that rotates after every few records.
+    fn audit_log(dir: &Path, keep: usize) -> AuditLog {
+        let path: PathBuf = dir.join("audit.jsonl");
+        let mut current: Current = open_current(&path, keep).unwrap();
+        current.last_hash = Some(anchor_chain(&path, keep, current.last_hash.take()).unwrap());
+        AuditLog {
+            path,
+            max_size: 1000,
+            keep,
+            hash_chain: true,
+            current: Mutex::new(current),
+        }
+    }
+
+    fn log_requests(log: &AuditLog, count: usize) {
+        let peer = PeerCredentials {
+            uid: 1000,
+            gid: 1000,
+            pid: 42,
+        };
+        let mut context = AuditContext::new(&peer);
+        let answer = crate::response_err::response(ResponsePayload::Empty);
+        for id in 0..count {
+            context.next(Some(id as u64));
+            context.command("List");
+            log.record(&context, &answer, ErrorArray::new_container());
+        }
+    }
+
+    #[test]
+    fn an_intact_chain_verifies() {
+        let dir: PathBuf = log_dir("intact");
+        let path: PathBuf = dir.join("audit.jsonl");
+        write_anchor(&path, GENESIS).unwrap();
+        write(
+            &path,
+            &chain(GENESIS, &["List", "StreamFile", "RemoveFile"]),
+        );
+        assert_eq!(verify(&path).ok(), Some(3));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn the_chain_carries_on_across_rotations() {
+        let dir: PathBuf = log_dir("rotated");
+        let path: PathBuf = dir.join("audit.jsonl");
+        let lines: Vec<String> = chain(GENESIS, &["List", "StreamFile", "RemoveFile", "List"]);
+        write_anchor(&path, GENESIS).unwrap();
+        write(&rotated(&path, 2), &lines[..1]);
+        write(&rotated(&path, 1), &lines[1..3]);
+        write(&path, &lines[3..]);
+        assert_eq!(verify(&path).ok(), Some(4));
+
+        // Dropping the oldest rotation moves the anchor on to its last record
+        fs::remove_file(rotated(&path, 2)).unwrap();
+        assert!(verify(&path).is_err());
+        write_anchor(&path, &hash(&lines[0])).unwrap();
+        assert_eq!(verify(&path).ok(), Some(3));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rotating_keeps_the_chain_verifiable() {
+        let dir: PathBuf = log_dir("rotating");
+        let log: AuditLog = audit_log(&dir, 2);
+        log_requests(&log, 20);
+
+        let path: PathBuf = dir.join("audit.jsonl");
+        assert!(rotated(&path, 2).exists());
+        let checked: usize = verify_chain(&path, 2, ErrorArray::new_container())
+            .uf_unwrap()
+            .unwrap();
+        let kept: usize = log_files(&path, 2)
+            .iter()
+            .map(|file| fs::read_to_string(file).unwrap().lines().count())
+            .sum();
+        assert_eq!(checked, kept);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_restarted_log_chains_on() {
+        let dir: PathBuf = log_dir("restarted");
+        log_requests(&audit_log(&dir, 2), 3);
+        log_requests(&audit_log(&dir, 2), 3);
+        assert_eq!(verify(&dir.join("audit.jsonl")).ok(), Some(6));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn an_edited_record_breaks_the_chain() {
+        let dir: PathBuf = log_dir("edited");
+        let path: PathBuf = dir.join("audit.jsonl");
+        let mut lines: Vec<String> = chain(GENESIS, &["List", "StreamFile", "RemoveFile"]);
+        lines[1] = lines[1].replace("\"uid\":1000", "\"uid\":0");
+        write_anchor(&path, GENESIS).unwrap();
+        write(&path, &lines);
+        assert!(verify(&path).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_removed_record_breaks_the_chain() {
+        let dir: PathBuf = log_dir("removed");
+        let path: PathBuf = dir.join("audit.jsonl");
+        let lines: Vec<String> = chain(GENESIS, &["List", "StreamFile", "RemoveFile"]);
+        write_anchor(&path, GENESIS).unwrap();
+
+        // Only the newest record can go unnoticed, nothing chains on from it yet
+        for removed in 0..lines.len() - 1 {
+            let mut left: Vec<String> = lines.clone();
+            left.remove(removed);
+            write(&path, &left);
+            assert!(verify(&path).is_err(), "record {} was removed", removed);
+        }
+
+        // Also all of the first ones
+        write(&path, &lines[2..]);
+        assert!(verify(&path).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_chain_without_its_anchor_breaks() {
+        let dir: PathBuf = log_dir("unanchored");
+        let path: PathBuf = dir.join("audit.jsonl");
+        write(&path, &chain(GENESIS, &["List", "StreamFile"]));
+        assert!(verify(&path).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn records_from_before_the_chain_are_left_out() {
+        let dir: PathBuf = log_dir("before");
+        let path: PathBuf = dir.join("audit.jsonl");
+        let mut lines: Vec<String> = vec![record("List", None), record("StreamFile", None)];
+        let anchor: String = hash(&lines[1]);
+        lines.extend(chain(&anchor, &["RemoveFile", "List"]));
+        write(&path, &lines);
+        write_anchor(&path, &anchor).unwrap();
+        assert_eq!(verify(&path).ok(), Some(2));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn records_without_a_hash_after_the_chain_started_break_it() {
+        let dir: PathBuf = log_dir("unhashed");
+        let path: PathBuf = dir.join("audit.jsonl");
+        let mut lines: Vec<String> = chain(GENESIS, &["List", "StreamFile"]);
+        lines.push(record("RemoveFile", None));
+        write_anchor(&path, GENESIS).unwrap();
+        write(&path, &lines);
+        assert!(verify(&path).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
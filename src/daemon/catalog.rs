@@ -0,0 +1,385 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use dusa_collection_utils::{
+    errors::{ErrorArray, ErrorArrayItem, UnifiedResult as uf},
+    types::PathType,
+};
+use dusa_common::EntryInfo;
+use serde::{Deserialize, Serialize};
+
+/// Name of the catalog file in the data directory.
+const CATALOG_FILE: &str = "catalog.json";
+
+/// Directory in the data directory where recs keeps one meta file per stored entry, named
+/// `{owner}-{name}.meta`.
+const META_DIR: &str = "meta";
+
+/// Catalog shared between the worker threads.
+pub type SharedCatalog = Arc<Mutex<Catalog>>;
+
+/// What dusad knows about a stored entry beyond what recs keeps in its encrypted meta files.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CatalogEntry {
+    pub owner: String,
+    pub name: String,
+    /// Where the data came from, `None` for data that was streamed without a file.
+    pub orig_p: Option<PathType>,
+    pub size: u64,
+    /// Seconds since the unix epoch.
+    pub stored: u64,
+}
+
+impl CatalogEntry {
+    pub fn new(owner: &str, name: &str, orig_p: Option<PathType>, size: u64) -> Self {
+        CatalogEntry {
+            owner: owner.to_owned(),
+            name: name.to_owned(),
+            orig_p,
+            size,
+            stored: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        }
+    }
+
+    fn info(&self) -> EntryInfo {
+        EntryInfo {
+            owner: self.owner.clone(),
+            name: self.name.clone(),
+            orig_p: self.orig_p.clone(),
+            size: Some(self.size),
+            stored: Some(self.stored),
+        }
+    }
+}
+
+/// Index of stored entries, persisted as json next to the recs data.
+#[derive(Debug, Default)]
+pub struct Catalog {
+    path: PathBuf,
+    meta_dir: PathBuf,
+    entries: BTreeMap<String, CatalogEntry>,
+}
+
+fn key(owner: &str, name: &str) -> String {
+    format!("{}/{}", owner, name)
+}
+
+/// What recs calls an entry, its meta file is named after this.
+fn stem(owner: &str, name: &str) -> String {
+    format!("{}-{}", owner, name)
+}
+
+impl Catalog {
+    /// Loads the catalog kept in recs' data directory, starting an empty one if it doesn't exist
+    /// yet.
+    ///
+    /// # Arguments
+    /// * `data_dir` - The directory recs stores its data in.
+    /// * `errors` - An array of errors to be populated if any occur.
+    ///
+    /// # Returns
+    /// A unified result containing the loaded catalog.
+    pub fn load(data_dir: &Path, mut errors: ErrorArray) -> uf<Catalog> {
+        let path: PathBuf = data_dir.join(CATALOG_FILE);
+        let meta_dir: PathBuf = data_dir.join(META_DIR);
+        if !path.exists() {
+            return uf::new(Ok(Catalog {
+                path,
+                meta_dir,
+                entries: BTreeMap::new(),
+            }));
+        }
+
+        let data: Vec<u8> = match fs::read(&path) {
+            Ok(d) => d,
+            Err(e) => {
+                errors.push(ErrorArrayItem::from(e));
+                return uf::new(Err(errors));
+            }
+        };
+
+        let list: Vec<CatalogEntry> = match serde_json::from_slice(&data) {
+            Ok(d) => d,
+            Err(e) => {
+                errors.push(ErrorArrayItem::from(e));
+                return uf::new(Err(errors));
+            }
+        };
+
+        uf::new(Ok(Catalog {
+            path,
+            meta_dir,
+            entries: list
+                .into_iter()
+                .map(|e| (key(&e.owner, &e.name), e))
+                .collect(),
+        }))
+    }
+
+    pub fn get(&self, owner: &str, name: &str) -> Option<&CatalogEntry> {
+        self.entries.get(&key(owner, name))
+    }
+
+    /// The entry recs would mix up with `owner`'s `name`, an entry with another owner and name
+    /// that recs keeps under the same `{owner}-{name}`, e.g. `svc`'s `backup-db` and
+    /// `svc-backup`'s `db`.
+    pub fn clash(&self, owner: &str, name: &str) -> Option<&CatalogEntry> {
+        let wanted: String = stem(owner, name);
+        self.entries
+            .values()
+            .find(|e| stem(&e.owner, &e.name) == wanted && (&*e.owner, &*e.name) != (owner, name))
+    }
+
+    /// Lists the stored entries, optionally only those of `owner`.
+    ///
+    /// recs' meta files decide what exists, the catalog adds the details it has. If the meta
+    /// directory can't be read the catalog is used on its own.
+    pub fn list(&self, owner: Option<&str>) -> Vec<EntryInfo> {
+        let wanted = |o: &str| owner.is_none_or(|w| w == o);
+
+        let stems: Vec<String> = match fs::read_dir(&self.meta_dir) {
+            Ok(dir) => dir
+                .filter_map(|e| e.ok())
+                .filter_map(|e| {
+                    let file_name = e.file_name().to_string_lossy().into_owned();
+                    file_name.strip_suffix(".meta").map(str::to_owned)
+                })
+                .collect(),
+            Err(_) => {
+                return self
+                    .entries
+                    .values()
+                    .filter(|e| wanted(&e.owner))
+                    .map(CatalogEntry::info)
+                    .collect()
+            }
+        };
+
+        let by_stem: BTreeMap<String, &CatalogEntry> = self
+            .entries
+            .values()
+            .map(|e| (stem(&e.owner, &e.name), e))
+            .collect();
+
+        let mut list: Vec<EntryInfo> = stems
+            .iter()
+            .filter_map(|stem| match by_stem.get(stem) {
+                Some(entry) => Some(entry.info()),
+                // Not in the catalog, only a single '-' says where the owner ends. With more it
+                // could be anyone's, it's left out rather than shown to the wrong owner
+                None => {
+                    let (o, n) = stem.split_once('-').filter(|(_, n)| !n.contains('-'))?;
+                    Some(EntryInfo {
+                        owner: o.to_owned(),
+                        name: n.to_owned(),
+                        orig_p: None,
+                        size: None,
+                        stored: None,
+                    })
+                }
+            })
+            .filter(|e| wanted(&e.owner))
+            .collect();
+
+        list.sort_by(|a, b| (&a.owner, &a.name).cmp(&(&b.owner, &b.name)));
+        list
+    }
+
+    /// Records a newly stored entry, replacing any previous one with the same owner and name.
+    pub fn record(&mut self, entry: CatalogEntry, errors: ErrorArray) -> uf<()> {
+        self.entries.insert(key(&entry.owner, &entry.name), entry);
+        self.save(errors)
+    }
+
+    /// Forgets a removed entry.
+    pub fn forget(&mut self, owner: &str, name: &str, errors: ErrorArray) -> uf<()> {
+        match self.entries.remove(&key(owner, name)) {
+            Some(_) => self.save(errors),
+            None => uf::new(Ok(())),
+        }
+    }
+
+    /// Writes the catalog to disk, replacing the old file in one step.
+    fn save(&self, mut errors: ErrorArray) -> uf<()> {
+        let list: Vec<&CatalogEntry> = self.entries.values().collect();
+        let data: Vec<u8> = match serde_json::to_vec_pretty(&list) {
+            Ok(d) => d,
+            Err(e) => {
+                errors.push(ErrorArrayItem::from(e));
+                return uf::new(Err(errors));
+            }
+        };
+
+        let temp_path: PathBuf = self.path.with_extension("json.tmp");
+        if let Err(e) = fs::write(&temp_path, data).and_then(|_| fs::rename(&temp_path, &self.path))
+        {
+            errors.push(ErrorArrayItem::from(e));
+            return uf::new(Err(errors));
+        }
+        uf::new(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_dir(test: &str) -> PathBuf {
+        let dir: PathBuf =
+            std::env::temp_dir().join(format!("dusa-catalog-{}-{}", std::process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join(META_DIR)).unwrap();
+        dir
+    }
+
+    fn load(dir: &Path) -> Catalog {
+        Catalog::load(dir, ErrorArray::new_container())
+            .uf_unwrap()
+            .unwrap()
+    }
+
+    fn stored(catalog: &mut Catalog, owner: &str, name: &str, size: u64) {
+        fs::write(
+            catalog.meta_dir.join(format!("{}.meta", stem(owner, name))),
+            "",
+        )
+        .unwrap();
+        let entry = CatalogEntry::new(owner, name, None, size);
+        catalog
+            .record(entry, ErrorArray::new_container())
+            .uf_unwrap()
+            .unwrap();
+    }
+
+    fn names(list: &[EntryInfo]) -> Vec<(&str, &str)> {
+        list.iter()
+            .map(|e| (e.owner.as_str(), e.name.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn a_saved_catalog_loads_again() {
+        let dir: PathBuf = data_dir("round-trip");
+        let mut catalog: Catalog = load(&dir);
+        assert!(catalog.entries.is_empty());
+        stored(&mut catalog, "system", "db", 11);
+        stored(&mut catalog, "svc-backup", "prod-db", 7);
+
+        let loaded: Catalog = load(&dir);
+        assert_eq!(loaded.get("system", "db").map(|e| e.size), Some(11));
+        assert_eq!(loaded.get("svc-backup", "prod-db").map(|e| e.size), Some(7));
+        assert!(loaded.get("system", "other").is_none());
+        assert!(!dir.join("catalog.json.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_broken_catalog_is_an_error() {
+        let dir: PathBuf = data_dir("broken");
+        fs::write(dir.join(CATALOG_FILE), "[{\"owner\": ").unwrap();
+        assert!(Catalog::load(&dir, ErrorArray::new_container())
+            .uf_unwrap()
+            .is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn forgotten_entries_stay_forgotten() {
+        let dir: PathBuf = data_dir("forget");
+        let mut catalog: Catalog = load(&dir);
+        stored(&mut catalog, "system", "db", 11);
+        stored(&mut catalog, "system", "keep", 3);
+
+        catalog
+            .forget("system", "db", ErrorArray::new_container())
+            .uf_unwrap()
+            .unwrap();
+        // Forgetting what isn't there is fine
+        catalog
+            .forget("system", "never", ErrorArray::new_container())
+            .uf_unwrap()
+            .unwrap();
+
+        let loaded: Catalog = load(&dir);
+        assert!(loaded.get("system", "db").is_none());
+        assert!(loaded.get("system", "keep").is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn entries_recs_would_mix_up_clash() {
+        let dir: PathBuf = data_dir("clash");
+        let mut catalog: Catalog = load(&dir);
+        stored(&mut catalog, "svc", "backup-db", 1);
+
+        let clash = catalog
+            .clash("svc-backup", "db")
+            .map(|e| (&*e.owner, &*e.name));
+        assert_eq!(clash, Some(("svc", "backup-db")));
+        assert!(catalog.clash("svc", "backup-db").is_none());
+        assert!(catalog.clash("svc", "db").is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn listing_goes_by_the_meta_files() {
+        let dir: PathBuf = data_dir("list");
+        let mut catalog: Catalog = load(&dir);
+        stored(&mut catalog, "system", "db", 11);
+        stored(&mut catalog, "svc-backup", "prod-db", 7);
+        // Removed behind the catalog's back, recs decides what exists
+        fs::remove_file(dir.join(META_DIR).join("system-db.meta")).unwrap();
+        // Stored before the catalog knew about it
+        fs::write(dir.join(META_DIR).join("me-notes.meta"), "").unwrap();
+        fs::write(dir.join(META_DIR).join("not-a-meta.txt"), "").unwrap();
+
+        let list: Vec<EntryInfo> = catalog.list(None);
+        assert_eq!(names(&list), [("me", "notes"), ("svc-backup", "prod-db")]);
+        assert_eq!(list[0].size, None);
+        assert_eq!(list[1].size, Some(7));
+
+        assert_eq!(names(&catalog.list(Some("me"))), [("me", "notes")]);
+        assert!(catalog.list(Some("system")).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn without_a_catalog_the_meta_files_are_listed() {
+        let dir: PathBuf = data_dir("no-catalog");
+        let catalog: Catalog = load(&dir);
+        assert!(!dir.join(CATALOG_FILE).exists());
+        fs::write(dir.join(META_DIR).join("svc-db.meta"), "").unwrap();
+        fs::write(dir.join(META_DIR).join("system-notes.meta"), "").unwrap();
+        // Could be svc's backup-db or svc-backup's db, it's left out
+        fs::write(dir.join(META_DIR).join("svc-backup-db.meta"), "").unwrap();
+
+        let list: Vec<EntryInfo> = catalog.list(None);
+        assert_eq!(names(&list), [("svc", "db"), ("system", "notes")]);
+        assert!(list.iter().all(|e| e.size.is_none() && e.stored.is_none()));
+        assert_eq!(names(&catalog.list(Some("svc"))), [("svc", "db")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn without_meta_files_the_catalog_lists_on_its_own() {
+        let dir: PathBuf = data_dir("no-meta");
+        let mut catalog: Catalog = load(&dir);
+        stored(&mut catalog, "system", "db", 11);
+        stored(&mut catalog, "me", "notes", 2);
+        fs::remove_dir_all(dir.join(META_DIR)).unwrap();
+
+        let mut list: Vec<EntryInfo> = catalog.list(None);
+        list.sort_by(|a, b| (&a.owner, &a.name).cmp(&(&b.owner, &b.name)));
+        assert_eq!(names(&list), [("me", "notes"), ("system", "db")]);
+        assert_eq!(names(&catalog.list(Some("me"))), [("me", "notes")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
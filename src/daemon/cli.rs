@@ -0,0 +1,274 @@
+use std::{io, path::PathBuf};
+
+use clap::{value_parser, Arg, ArgMatches, Command};
+use dusa_collection_utils::errors::{ErrorArray, UnifiedResult as uf};
+use dusa_common::{
+    config::{config_path, Config},
+    VERSION,
+};
+
+pub fn build_cli() -> Command {
+    Command::new("dusad")
+        .about("The dusa daemon, encrypts and stores data for its clients with recs")
+        .version(VERSION)
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .value_parser(value_parser!(PathBuf))
+                .help("Config file to read instead of /etc/dusa/dusa.toml or $DUSA_CONFIG")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("socket")
+                .long("socket")
+                .value_parser(value_parser!(PathBuf))
+                .help("The unix socket to listen on")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("ttl")
+                .long("ttl")
+                .value_parser(value_parser!(u64))
+                .help("Seconds before a decrypted temp file is deleted")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("user")
+                .long("user")
+                .value_parser(value_parser!(String))
+                .help("The user to run as")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("group")
+                .long("group")
+                .value_parser(value_parser!(String))
+                .help("The group to run as")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("prog_name")
+                .long("prog-name")
+                .value_parser(value_parser!(String))
+                .help("Name recs stores its data under, in /var/<prog-name>")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("policy")
+                .long("policy")
+                .value_parser(value_parser!(PathBuf))
+                .help("The access policy to enforce")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("max_frame_size")
+                .long("max-frame-size")
+                .value_parser(value_parser!(usize))
+                .help("Largest message in bytes read from a client")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("max_upload_size")
+                .long("max-upload-size")
+                .value_parser(value_parser!(u64))
+                .help("Largest upload in bytes that is stored, 0 means no limit")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("read_timeout")
+                .long("read-timeout")
+                .value_parser(value_parser!(u64))
+                .help("Seconds to wait on a client that stopped sending, 0 waits forever")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("write_timeout")
+                .long("write-timeout")
+                .value_parser(value_parser!(u64))
+                .help("Seconds to wait on a client that stopped reading, 0 waits forever")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("workers")
+                .long("workers")
+                .value_parser(value_parser!(usize))
+                .help("How many connections are handled at the same time")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("queue_size")
+                .long("queue-size")
+                .value_parser(value_parser!(usize))
+                .help("How many connections may wait for a worker before clients are turned away")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("max_connections_per_uid")
+                .long("max-per-uid")
+                .value_parser(value_parser!(usize))
+                .help("How many connections one uid may have open at once, 0 means no limit")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("session_idle_timeout")
+                .long("session-idle-timeout")
+                .value_parser(value_parser!(u64))
+                .help("Seconds a session may wait for its next request, 0 leaves it to the read timeout")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("shutdown_timeout")
+                .long("shutdown-timeout")
+                .value_parser(value_parser!(u64))
+                .help("Seconds to wait for running requests when asked to stop")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("metrics_socket")
+                .long("metrics-socket")
+                .value_parser(value_parser!(PathBuf))
+                .help("Serve Prometheus metrics over http on this unix socket")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("metrics_port")
+                .long("metrics-port")
+                .value_parser(value_parser!(u16))
+                .help("Serve Prometheus metrics over http on this port of 127.0.0.1")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("audit_log")
+                .long("audit-log")
+                .value_parser(value_parser!(PathBuf))
+                .help("The file every request is recorded in")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("audit_max_size")
+                .long("audit-max-size")
+                .value_parser(value_parser!(u64))
+                .help("Bytes the audit log may grow to before it's rotated, 0 never rotates it")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("audit_keep")
+                .long("audit-keep")
+                .value_parser(value_parser!(usize))
+                .help("How many rotated audit logs to keep")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("audit_hash_chain")
+                .long("audit-hash-chain")
+                .action(clap::ArgAction::SetTrue)
+                .help("Chain audit records together by hash so tampering can be detected"),
+        )
+        .arg(
+            Arg::new("legacy_temp_decrypt")
+                .long("legacy-temp-decrypt")
+                .action(clap::ArgAction::SetTrue)
+                .help("Let old clients decrypt entries to a temp file in /tmp"),
+        )
+        .arg(
+            Arg::new("verify_audit")
+                .long("verify-audit")
+                .action(clap::ArgAction::SetTrue)
+                .help("Check the hash chain of the audit log and its rotations, then exit"),
+        )
+        .arg(
+            Arg::new("man")
+                .long("man")
+                .action(clap::ArgAction::SetTrue)
+                .help("Print the dusad(8) man page and exit"),
+        )
+}
+
+/// Writes the dusad(8) man page to stdout.
+pub fn print_man() -> io::Result<()> {
+    clap_mangen::Man::new(build_cli())
+        .section("8")
+        .render(&mut io::stdout())
+}
+
+/// Reads the config file and the environment, then applies the command line on top.
+pub fn resolve_config(cmd: &ArgMatches, errors: ErrorArray) -> uf<Config> {
+    let path: PathBuf = cmd
+        .get_one::<PathBuf>("config")
+        .cloned()
+        .unwrap_or_else(config_path);
+
+    let mut config: Config = match Config::load_with_env(&path, errors).uf_unwrap() {
+        Ok(d) => d,
+        Err(e) => return uf::new(Err(e)),
+    };
+
+    if let Some(d) = cmd.get_one::<PathBuf>("socket") {
+        config.socket_path = d.clone();
+    }
+    if let Some(d) = cmd.get_one::<u64>("ttl") {
+        config.ttl = *d;
+    }
+    if let Some(d) = cmd.get_one::<String>("user") {
+        config.user = d.clone();
+    }
+    if let Some(d) = cmd.get_one::<String>("group") {
+        config.group = d.clone();
+    }
+    if let Some(d) = cmd.get_one::<String>("prog_name") {
+        config.prog_name = d.clone();
+    }
+    if let Some(d) = cmd.get_one::<PathBuf>("policy") {
+        config.policy_path = d.clone();
+    }
+    if let Some(d) = cmd.get_one::<usize>("max_frame_size") {
+        config.max_frame_size = *d;
+    }
+    if let Some(d) = cmd.get_one::<u64>("max_upload_size") {
+        config.max_upload_size = *d;
+    }
+    if let Some(d) = cmd.get_one::<u64>("read_timeout") {
+        config.read_timeout = *d;
+    }
+    if let Some(d) = cmd.get_one::<u64>("write_timeout") {
+        config.write_timeout = *d;
+    }
+    if let Some(d) = cmd.get_one::<usize>("workers") {
+        config.workers = *d;
+    }
+    if let Some(d) = cmd.get_one::<usize>("queue_size") {
+        config.queue_size = *d;
+    }
+    if let Some(d) = cmd.get_one::<usize>("max_connections_per_uid") {
+        config.max_connections_per_uid = *d;
+    }
+    if let Some(d) = cmd.get_one::<u64>("session_idle_timeout") {
+        config.session_idle_timeout = *d;
+    }
+    if let Some(d) = cmd.get_one::<u64>("shutdown_timeout") {
+        config.shutdown_timeout = *d;
+    }
+    if let Some(d) = cmd.get_one::<PathBuf>("metrics_socket") {
+        config.metrics_socket = Some(d.clone());
+    }
+    if let Some(d) = cmd.get_one::<u16>("metrics_port") {
+        config.metrics_port = Some(*d);
+    }
+    if let Some(d) = cmd.get_one::<PathBuf>("audit_log") {
+        config.audit_log = d.clone();
+    }
+    if let Some(d) = cmd.get_one::<u64>("audit_max_size") {
+        config.audit_max_size = *d;
+    }
+    if let Some(d) = cmd.get_one::<usize>("audit_keep") {
+        config.audit_keep = *d;
+    }
+    if cmd.get_flag("audit_hash_chain") {
+        config.audit_hash_chain = true;
+    }
+    if cmd.get_flag("legacy_temp_decrypt") {
+        config.legacy_temp_decrypt = true;
+    }
+    uf::new(Ok(config))
+}
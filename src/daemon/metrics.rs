@@ -0,0 +1,138 @@
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{Ipv4Addr, TcpListener},
+    os::unix::{fs::PermissionsExt, net::UnixListener},
+    path::Path,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use dusa_collection_utils::errors::{ErrorArray, ErrorArrayItem, UnifiedResult as uf};
+use dusa_common::config::config;
+use simple_pretty::notice;
+
+use crate::{stats::Stats, temp::pending_temp_files};
+
+/// How long a scraper gets to send its request or take the answer.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most we read of a request, a scraper asking for metrics sends far less.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Starts serving the metrics on the unix socket and the localhost port the config asks for,
+/// each on its own thread. Nothing is started if neither is configured.
+///
+/// # Arguments
+/// * `stats` - The counters to serve.
+/// * `errors` - An array of errors to be populated if any occur.
+pub fn serve_metrics(stats: Arc<Stats>, mut errors: ErrorArray) -> uf<()> {
+    if let Some(path) = &config().metrics_socket {
+        match bind_metrics_socket(path) {
+            Ok(listener) => {
+                notice(&format!("Serving metrics on {}", path.display()));
+                let stats = stats.clone();
+                thread::spawn(move || {
+                    for stream in listener.incoming().flatten() {
+                        let timeouts = stream
+                            .set_read_timeout(Some(SCRAPE_TIMEOUT))
+                            .and_then(|_| stream.set_write_timeout(Some(SCRAPE_TIMEOUT)));
+                        if timeouts.is_ok() {
+                            scrape(stream, &stats);
+                        }
+                    }
+                });
+            }
+            Err(e) => {
+                errors.push(ErrorArrayItem::from(e));
+                return uf::new(Err(errors));
+            }
+        }
+    }
+
+    if let Some(port) = config().metrics_port {
+        match TcpListener::bind((Ipv4Addr::LOCALHOST, port)) {
+            Ok(listener) => {
+                notice(&format!("Serving metrics on 127.0.0.1:{}", port));
+                thread::spawn(move || {
+                    for stream in listener.incoming().flatten() {
+                        let timeouts = stream
+                            .set_read_timeout(Some(SCRAPE_TIMEOUT))
+                            .and_then(|_| stream.set_write_timeout(Some(SCRAPE_TIMEOUT)));
+                        if timeouts.is_ok() {
+                            scrape(stream, &stats);
+                        }
+                    }
+                });
+            }
+            Err(e) => {
+                errors.push(ErrorArrayItem::from(e));
+                return uf::new(Err(errors));
+            }
+        }
+    }
+
+    uf::new(Ok(()))
+}
+
+/// Creates the metrics socket, replacing a stale one, readable by the dusa group only.
+fn bind_metrics_socket(path: &Path) -> std::io::Result<UnixListener> {
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o660))?;
+    Ok(listener)
+}
+
+/// Answers a single http request, `GET /metrics` gets the counters and everything else an error.
+fn scrape<S: Read + Write>(mut stream: S, stats: &Stats) {
+    let request: String = match read_request(&mut stream) {
+        Some(d) => d,
+        None => return,
+    };
+
+    let mut words = request.lines().next().unwrap_or("").split_whitespace();
+    let (status, body): (&str, String) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", stats.prometheus(pending_temp_files())),
+        (Some("GET"), Some(_)) => ("404 Not Found", String::from("Metrics are at /metrics\n")),
+        _ => (
+            "405 Method Not Allowed",
+            String::from("Only GET is supported\n"),
+        ),
+    };
+
+    let head: String = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    let _ = stream
+        .write_all(head.as_bytes())
+        .and_then(|_| stream.write_all(body.as_bytes()))
+        .and_then(|_| stream.flush());
+}
+
+/// Reads the request head, `None` if the scraper hung up, took too long or sent too much.
+fn read_request<S: Read>(stream: &mut S) -> Option<String> {
+    let mut request: Vec<u8> = Vec::new();
+    let mut buffer = [0u8; 1024];
+
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let read: usize = stream.read(&mut buffer).ok().filter(|n| *n > 0)?;
+        request.extend_from_slice(&buffer[..read]);
+        if request.len() > MAX_REQUEST_SIZE {
+            return None;
+        }
+    }
+
+    Some(String::from_utf8_lossy(&request).into_owned())
+}
+
+/// Removes the metrics socket if we created one.
+pub fn remove_metrics_socket() {
+    if let Some(path) = &config().metrics_socket {
+        let _ = fs::remove_file(path);
+    }
+}
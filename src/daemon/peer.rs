@@ -0,0 +1,34 @@
+use std::os::unix::{io::AsRawFd, net::UnixStream};
+
+use dusa_collection_utils::errors::{ErrorArray, ErrorArrayItem, UnifiedResult as uf};
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials as PeerCred};
+
+/// Credentials of the process on the other end of a connection, as reported by the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: i32,
+}
+
+/// Reads the peer credentials of a connected unix stream using `SO_PEERCRED`.
+///
+/// # Arguments
+/// * `stream` - The connected client stream.
+/// * `errors` - An array of errors to be populated if any occur.
+///
+/// # Returns
+/// A unified result containing the uid, gid and pid of the connected process.
+pub fn get_peer_credentials(stream: &UnixStream, mut errors: ErrorArray) -> uf<PeerCredentials> {
+    match getsockopt(stream.as_raw_fd(), PeerCred) {
+        Ok(cred) => uf::new(Ok(PeerCredentials {
+            uid: cred.uid(),
+            gid: cred.gid(),
+            pid: cred.pid(),
+        })),
+        Err(e) => {
+            errors.push(ErrorArrayItem::from(e));
+            uf::new(Err(errors))
+        }
+    }
+}
@@ -0,0 +1,432 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    thread,
+};
+
+use dusa_collection_utils::errors::{ErrorArray, ErrorArrayItem, Errors, UnifiedResult as uf};
+use dusa_common::{Commands, RequestPayload};
+use serde::{Deserialize, Serialize};
+use signal_hook::{consts::SIGHUP, iterator::Signals};
+use simple_pretty::{notice, warn};
+use users::{get_group_by_gid, get_user_by_uid, get_user_groups};
+
+use crate::{peer::PeerCredentials, temp::RAW_STAGING_ENTRY};
+
+/// Placeholder that can be used in `owners` to mean "the owner named after the caller".
+const SELF_OWNER: &str = "$user";
+
+/// Policy shared between the worker threads and the SIGHUP reloader.
+pub type SharedPolicy = Arc<RwLock<Policy>>;
+
+/// The kind of access a request needs on an owner.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Read,
+    Write,
+    Remove,
+}
+
+impl std::fmt::Display for Access {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::Remove => write!(f, "remove"),
+        }
+    }
+}
+
+/// A single grant of access. A rule applies to a caller if their user name (or uid) is listed in
+/// `users`, or if they are a member of one of `groups`. `*` matches everyone.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rule {
+    #[serde(default)]
+    pub users: Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    pub owners: Vec<String>,
+    pub access: Vec<Access>,
+}
+
+/// The set of rules dusad enforces before touching stored data.
+///
+/// Root may access everything and every user may access the owner named after them, anything else
+/// has to be granted by a rule.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Policy {
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+}
+
+/// The identity of a caller resolved from its peer credentials.
+struct Identity {
+    uid: u32,
+    name: Option<String>,
+    groups: Vec<String>,
+}
+
+impl Identity {
+    fn resolve(peer: &PeerCredentials) -> Self {
+        let name: Option<String> =
+            get_user_by_uid(peer.uid).map(|u| u.name().to_string_lossy().into_owned());
+
+        let mut groups: Vec<String> = Vec::new();
+        if let Some(group) = get_group_by_gid(peer.gid) {
+            groups.push(group.name().to_string_lossy().into_owned());
+        }
+        if let Some(user) = &name {
+            for group in get_user_groups(user, peer.gid).unwrap_or_default() {
+                let group_name = group.name().to_string_lossy().into_owned();
+                if !groups.contains(&group_name) {
+                    groups.push(group_name);
+                }
+            }
+        }
+
+        Identity {
+            uid: peer.uid,
+            name,
+            groups,
+        }
+    }
+}
+
+impl Rule {
+    fn applies_to(&self, id: &Identity) -> bool {
+        let user_match = self.users.iter().any(|u| {
+            u == "*" || *u == id.uid.to_string() || id.name.as_deref() == Some(u.as_str())
+        });
+        let group_match = self
+            .groups
+            .iter()
+            .any(|g| g == "*" || id.groups.contains(g));
+        user_match || group_match
+    }
+
+    fn covers(&self, id: &Identity, owner: &str, access: Access) -> bool {
+        let owner_match = self.owners.iter().any(|o| {
+            o == "*" || o == owner || (o == SELF_OWNER && id.name.as_deref() == Some(owner))
+        });
+        owner_match && self.access.contains(&access)
+    }
+}
+
+impl Policy {
+    /// Reads and parses a policy file.
+    ///
+    /// # Arguments
+    /// * `path` - The toml file to read the rules from.
+    /// * `errors` - An array of errors to be populated if any occur.
+    ///
+    /// # Returns
+    /// A unified result containing the parsed policy.
+    pub fn load(path: &Path, mut errors: ErrorArray) -> uf<Policy> {
+        let data: String = match fs::read_to_string(path) {
+            Ok(d) => d,
+            Err(e) => {
+                errors.push(ErrorArrayItem::from(e));
+                return uf::new(Err(errors));
+            }
+        };
+
+        match toml::from_str(&data) {
+            Ok(d) => uf::new(Ok(d)),
+            Err(e) => {
+                errors.push(ErrorArrayItem::new(
+                    Errors::InvalidType,
+                    format!("Invalid policy file {}: {}", path.display(), e),
+                ));
+                uf::new(Err(errors))
+            }
+        }
+    }
+
+    /// Loads the policy used at startup. A missing file is not fatal, the daemon falls back to
+    /// the built in rules (root and per-user owners only).
+    pub fn load_or_default(path: &Path, errors: ErrorArray) -> uf<Policy> {
+        if !path.exists() {
+            warn(&format!(
+                "No access policy found at {}, only root and per-user owners are allowed",
+                path.display()
+            ));
+            return uf::new(Ok(Policy::default()));
+        }
+        Policy::load(path, errors)
+    }
+
+    /// Checks whether the peer may perform `access` on entries stored under `owner`.
+    pub fn allows(&self, peer: &PeerCredentials, owner: &str, access: Access) -> bool {
+        if peer.uid == 0 {
+            return true;
+        }
+
+        let id = Identity::resolve(peer);
+        if id.name.as_deref() == Some(owner) {
+            return true;
+        }
+
+        self.rules
+            .iter()
+            .any(|rule| rule.applies_to(&id) && rule.covers(&id, owner, access))
+    }
+}
+
+/// Returns the owner and the access level a request needs, or `None` if the request doesn't touch
+/// stored entries.
+pub fn required_access(request: &RequestPayload) -> Option<(&str, Access)> {
+    match request {
+        RequestPayload::Write(req) => Some((&req.owner, Access::Write)),
+        RequestPayload::Upload(req) => Some((&req.owner, Access::Write)),
+        RequestPayload::PlainText(_) | RequestPayload::Batch(_) => None,
+        // Queries are answered with whatever the caller may read
+        RequestPayload::Query(_) => None,
+        RequestPayload::Simple(req) => match req.command {
+            Commands::DecryptFile
+            | Commands::DecryptFileFd
+            | Commands::StreamFile
+            | Commands::PingFile => Some((&req.owner, Access::Read)),
+            Commands::RemoveFile => Some((&req.owner, Access::Remove)),
+            Commands::EncryptRawText | Commands::DecryptRawText | Commands::List => None,
+        },
+    }
+}
+
+/// The owner and name a request is about, either is `None` if the request doesn't carry it.
+pub fn request_names(request: &RequestPayload) -> (Option<&str>, Option<&str>) {
+    match request {
+        RequestPayload::Write(req) => (Some(&req.owner), Some(&req.name)),
+        RequestPayload::Upload(req) => (Some(&req.owner), Some(&req.name)),
+        RequestPayload::Simple(req) => match req.command {
+            Commands::EncryptRawText | Commands::DecryptRawText | Commands::List => (None, None),
+            _ => (Some(&req.owner), Some(&req.name)),
+        },
+        RequestPayload::Query(req) => (req.owner.as_deref(), None),
+        RequestPayload::PlainText(_) | RequestPayload::Batch(_) => (None, None),
+    }
+}
+
+/// Checks the owners and names a request carries before anything is decided on them.
+///
+/// Owners and names end up in recs' file names, so a `/` or nul byte is refused and a leading
+/// `-` could be taken for an option. The entry recs stages raw text under is reserved, it's
+/// forgotten before every raw text request.
+///
+/// # Returns
+/// Why the request can't be handled, `None` if its owner and name are fine.
+pub fn invalid_names(request: &RequestPayload) -> Option<String> {
+    let (owner, name): (Option<&str>, Option<&str>) = request_names(request);
+
+    if (owner, name) == (Some(RAW_STAGING_ENTRY.0), Some(RAW_STAGING_ENTRY.1)) {
+        return Some(format!(
+            "{}/{} is where recs stages raw text, it can't be used",
+            RAW_STAGING_ENTRY.0, RAW_STAGING_ENTRY.1
+        ));
+    }
+
+    [("owner", owner), ("name", name)]
+        .into_iter()
+        .find_map(|(field, value)| match value {
+            Some("") => Some(format!("The {} can't be empty", field)),
+            Some(d) if d.contains(['/', '\0']) => Some(format!(
+                "The {} {:?} can't contain '/' or a nul byte",
+                field, d
+            )),
+            Some(d) if d.starts_with('-') => {
+                Some(format!("The {} {:?} can't start with '-'", field, d))
+            }
+            _ => None,
+        })
+}
+
+/// Reloads the policy from `path` whenever the daemon receives SIGHUP. If the new file can't be
+/// read the previous policy stays in effect.
+pub fn reload_on_sighup(policy: SharedPolicy, path: PathBuf) {
+    let mut signals = match Signals::new([SIGHUP]) {
+        Ok(d) => d,
+        Err(e) => {
+            warn(&format!("Policy reloading is disabled: {}", e));
+            return;
+        }
+    };
+
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            match Policy::load(&path, ErrorArray::new_container()).uf_unwrap() {
+                Ok(new_policy) => {
+                    if let Ok(mut current) = policy.write() {
+                        *current = new_policy;
+                        notice("Access policy reloaded");
+                    }
+                }
+                Err(e) => {
+                    warn("Failed to reload the access policy, keeping the previous one");
+                    e.display(false);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dusa_common::{RequestRecsQuery, RequestRecsSimple};
+
+    fn simple(command: Commands, owner: &str, name: &str) -> RequestPayload {
+        RequestPayload::Simple(RequestRecsSimple {
+            command,
+            owner: owner.to_owned(),
+            name: name.to_owned(),
+            uid: 1000,
+        })
+    }
+
+    fn rule(users: &[&str], groups: &[&str], owners: &[&str], access: &[Access]) -> Rule {
+        Rule {
+            users: users.iter().map(|d| d.to_string()).collect(),
+            groups: groups.iter().map(|d| d.to_string()).collect(),
+            owners: owners.iter().map(|d| d.to_string()).collect(),
+            access: access.to_vec(),
+        }
+    }
+
+    fn alice() -> Identity {
+        Identity {
+            uid: 1002,
+            name: Some(String::from("alice")),
+            groups: vec![String::from("alice"), String::from("dusa")],
+        }
+    }
+
+    #[test]
+    fn rules_apply_by_user_uid_group_or_wildcard() {
+        let id: Identity = alice();
+        assert!(rule(&["alice"], &[], &["*"], &[Access::Read]).applies_to(&id));
+        assert!(rule(&["1002"], &[], &["*"], &[Access::Read]).applies_to(&id));
+        assert!(rule(&[], &["dusa"], &["*"], &[Access::Read]).applies_to(&id));
+        assert!(rule(&["*"], &[], &["*"], &[Access::Read]).applies_to(&id));
+        assert!(rule(&[], &["*"], &["*"], &[Access::Read]).applies_to(&id));
+
+        assert!(!rule(&["bob", "1003"], &["wheel"], &["*"], &[Access::Read]).applies_to(&id));
+        assert!(!rule(&[], &[], &["*"], &[Access::Read]).applies_to(&id));
+    }
+
+    #[test]
+    fn rules_cover_their_owners_and_access_only() {
+        let id: Identity = alice();
+        let shared: Rule = rule(&[], &["dusa"], &["system"], &[Access::Read, Access::Write]);
+        assert!(shared.covers(&id, "system", Access::Read));
+        assert!(shared.covers(&id, "system", Access::Write));
+        assert!(!shared.covers(&id, "system", Access::Remove));
+        assert!(!shared.covers(&id, "billing", Access::Read));
+
+        let everything: Rule = rule(&["alice"], &[], &["*"], &[Access::Remove]);
+        assert!(everything.covers(&id, "billing", Access::Remove));
+
+        // $user only stands for the caller's own name
+        let own: Rule = rule(&["*"], &[], &[SELF_OWNER], &[Access::Read]);
+        assert!(own.covers(&id, "alice", Access::Read));
+        assert!(!own.covers(&id, "bob", Access::Read));
+        let nameless = Identity {
+            uid: 4242,
+            name: None,
+            groups: Vec::new(),
+        };
+        assert!(!own.covers(&nameless, "4242", Access::Read));
+    }
+
+    #[test]
+    fn root_may_do_anything_even_without_rules() {
+        let root = PeerCredentials {
+            uid: 0,
+            gid: 0,
+            pid: 1,
+        };
+        let policy = Policy::default();
+        assert!(policy.allows(&root, "billing", Access::Remove));
+    }
+
+    #[test]
+    fn policy_files_parse_into_rules() {
+        let policy: Policy = toml::from_str(
+            r#"
+            [[rule]]
+            groups = ["dusa"]
+            owners = ["system"]
+            access = ["read", "write", "remove"]
+
+            [[rule]]
+            users = ["*"]
+            owners = ["$user"]
+            access = ["read"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(policy.rules.len(), 2);
+        assert!(policy.rules[0].users.is_empty());
+        assert_eq!(policy.rules[1].owners, [SELF_OWNER]);
+        assert_eq!(policy.rules[1].access, [Access::Read]);
+
+        let unknown = toml::from_str::<Policy>("[[rule]]\nowners = [\"*\"]\naccess = [\"all\"]\n");
+        assert!(unknown.is_err());
+    }
+
+    #[test]
+    fn names_that_dont_fit_a_file_name_are_refused() {
+        assert!(invalid_names(&simple(Commands::DecryptFile, "system", "../db")).is_some());
+        assert!(invalid_names(&simple(Commands::RemoveFile, "", "db")).is_some());
+        assert!(invalid_names(&simple(Commands::RemoveFile, "system", "")).is_some());
+        assert!(invalid_names(&simple(Commands::DecryptFile, "sys\0tem", "db")).is_some());
+        assert!(invalid_names(&simple(Commands::StreamFile, "-system", "db")).is_some());
+        assert!(invalid_names(&simple(Commands::StreamFile, "system", "-db")).is_some());
+    }
+
+    #[test]
+    fn dashes_inside_owners_and_names_pass() {
+        assert_eq!(
+            invalid_names(&simple(Commands::StreamFile, "system", "prod-db")),
+            None
+        );
+        assert_eq!(
+            invalid_names(&simple(Commands::PingFile, "svc-backup", "db")),
+            None
+        );
+    }
+
+    #[test]
+    fn the_raw_text_staging_entry_is_reserved() {
+        assert!(invalid_names(&simple(Commands::StreamFile, "owner", "temp")).is_some());
+        assert!(invalid_names(&simple(Commands::RemoveFile, "owner", "temp")).is_some());
+        assert_eq!(
+            invalid_names(&simple(Commands::StreamFile, "owner", "temps")),
+            None
+        );
+    }
+
+    #[test]
+    fn plain_names_pass() {
+        assert_eq!(
+            invalid_names(&simple(Commands::DecryptFile, "system", "prod_db.v2")),
+            None
+        );
+        let query = RequestPayload::Query(RequestRecsQuery {
+            command: Commands::List,
+            owner: None,
+            uid: 1000,
+        });
+        assert_eq!(invalid_names(&query), None);
+    }
+
+    #[test]
+    fn queries_check_their_owner() {
+        let query = RequestPayload::Query(RequestRecsQuery {
+            command: Commands::List,
+            owner: Some(String::from("-system")),
+            uid: 1000,
+        });
+        assert!(invalid_names(&query).is_some());
+    }
+}
@@ -0,0 +1,391 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    os::unix::net::UnixStream,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use dusa_collection_utils::errors::{ErrorArray, ErrorArrayItem, UnifiedResult as uf};
+use simple_pretty::warn;
+
+use crate::peer::PeerCredentials;
+
+/// A connection waiting for a worker.
+pub struct Job {
+    pub stream: UnixStream,
+    pub peer: PeerCredentials,
+    /// Held until the connection is done with, it counts against the peer's uid.
+    slot: Slot,
+}
+
+impl Job {
+    pub fn new(stream: UnixStream, peer: PeerCredentials, slot: Slot) -> Self {
+        Job { stream, peer, slot }
+    }
+}
+
+/// A fixed number of threads handling connections, with a bounded queue in front of them.
+pub struct WorkerPool {
+    queue: SyncSender<Job>,
+    /// How many workers are still running, with a condvar signalled when one stops.
+    running: Arc<(Mutex<usize>, Condvar)>,
+    heartbeat: Arc<Heartbeat>,
+}
+
+impl WorkerPool {
+    /// Starts the workers.
+    ///
+    /// # Arguments
+    /// * `workers` - How many connections are handled at the same time, at least one.
+    /// * `queue_size` - How many accepted connections may wait for a worker.
+    /// * `handler` - What a worker does with a connection.
+    /// * `errors` - An array of errors to be populated if any occur.
+    ///
+    /// # Returns
+    /// A unified result containing the pool, an error if a worker couldn't be started.
+    pub fn new<F>(workers: usize, queue_size: usize, handler: F, mut errors: ErrorArray) -> uf<Self>
+    where
+        F: Fn(UnixStream, PeerCredentials) + Send + Sync + 'static,
+    {
+        let (queue, jobs) = mpsc::sync_channel::<Job>(queue_size);
+        let jobs: Arc<Mutex<Receiver<Job>>> = Arc::new(Mutex::new(jobs));
+        let handler = Arc::new(handler);
+        let running = Arc::new((Mutex::new(0), Condvar::new()));
+        let heartbeat = Arc::new(Heartbeat::new(workers.max(1)));
+
+        for id in 0..workers.max(1) {
+            let jobs = jobs.clone();
+            let handler = handler.clone();
+            let worker = Worker::start(running.clone(), heartbeat.clone(), id);
+            let spawned = thread::Builder::new()
+                .name(format!("dusad-worker-{}", id))
+                .spawn(move || work(jobs, handler, worker));
+            if let Err(e) = spawned {
+                errors.push(ErrorArrayItem::from(e));
+                return uf::new(Err(errors));
+            }
+        }
+
+        uf::new(Ok(WorkerPool {
+            queue,
+            running,
+            heartbeat,
+        }))
+    }
+
+    /// Queues a connection for the next free worker. When the queue is full the job is handed
+    /// back so the caller can turn the client away.
+    pub fn submit(&self, job: Job) -> Result<(), Job> {
+        match self.queue.try_send(job) {
+            Ok(_) => Ok(()),
+            Err(TrySendError::Full(job)) | Err(TrySendError::Disconnected(job)) => Err(job),
+        }
+    }
+
+    /// What the accept loop and the workers report their progress to.
+    pub fn heartbeat(&self) -> Arc<Heartbeat> {
+        self.heartbeat.clone()
+    }
+
+    /// Stops taking connections and waits for the workers to finish the ones already queued.
+    ///
+    /// # Returns
+    /// `true` if every worker finished within `deadline`.
+    pub fn shutdown(self, deadline: Duration) -> bool {
+        // Workers stop once the queue is empty and nothing can be added to it anymore
+        drop(self.queue);
+
+        let (count, stopped) = &*self.running;
+        match count.lock() {
+            Ok(running) => match stopped.wait_timeout_while(running, deadline, |n| *n > 0) {
+                Ok((_, timeout)) => !timeout.timed_out(),
+                Err(_) => false,
+            },
+            Err(_) => false,
+        }
+    }
+}
+
+/// Counts a worker as running for as long as it lives, and marks it dead in the heartbeat once
+/// it's gone.
+struct Worker {
+    running: Arc<(Mutex<usize>, Condvar)>,
+    heartbeat: Arc<Heartbeat>,
+    id: usize,
+}
+
+impl Worker {
+    fn start(running: Arc<(Mutex<usize>, Condvar)>, heartbeat: Arc<Heartbeat>, id: usize) -> Self {
+        if let Ok(mut count) = running.0.lock() {
+            *count += 1;
+        }
+        Worker {
+            running,
+            heartbeat,
+            id,
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.heartbeat.workers[self.id]
+            .alive
+            .store(false, Ordering::SeqCst);
+        let (count, stopped) = &*self.running;
+        if let Ok(mut count) = count.lock() {
+            *count = count.saturating_sub(1);
+        }
+        stopped.notify_all();
+    }
+}
+
+/// Shows whether the daemon is getting anywhere, so the watchdog isn't fed by a daemon whose
+/// accept loop hangs or whose workers died or are stuck.
+pub struct Heartbeat {
+    started: Instant,
+    /// Milliseconds since `started` the accept loop began on the current connection, 0 while
+    /// it waits for one.
+    accepting_since: AtomicU64,
+    /// One for every worker of the pool.
+    workers: Vec<WorkerBeat>,
+}
+
+/// What a worker last reported.
+struct WorkerBeat {
+    /// Cleared when the worker's thread ends, the pool never starts another one.
+    alive: AtomicBool,
+    /// Milliseconds since `started` the worker last got somewhere with its connection, 0 while
+    /// it waits for one.
+    busy_since: AtomicU64,
+}
+
+thread_local! {
+    /// The heartbeat and number of the worker running on this thread, unset on any other.
+    static CURRENT_WORKER: RefCell<Option<(Arc<Heartbeat>, usize)>> = const { RefCell::new(None) };
+}
+
+impl Heartbeat {
+    fn new(workers: usize) -> Self {
+        Heartbeat {
+            started: Instant::now(),
+            accepting_since: AtomicU64::new(0),
+            workers: (0..workers)
+                .map(|_| WorkerBeat {
+                    alive: AtomicBool::new(true),
+                    busy_since: AtomicU64::new(0),
+                })
+                .collect(),
+        }
+    }
+
+    /// Milliseconds since `started`, never 0 as that means waiting.
+    fn now(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64 + 1
+    }
+
+    /// Marks the accept loop as busy with a connection until the guard is dropped.
+    pub fn accepting(&self) -> Accepting<'_> {
+        self.accepting_since.store(self.now(), Ordering::SeqCst);
+        Accepting { heartbeat: self }
+    }
+
+    /// Whether the daemon is alive. The accept loop must not spend `limit` on one connection,
+    /// every worker has to be running and a busy one must have got somewhere within
+    /// `worker_limit`. How many connections wait in the queue doesn't matter, a daemon whose
+    /// workers all serve long requests is busy, not hung.
+    pub fn alive(&self, limit: Duration, worker_limit: Option<Duration>) -> bool {
+        let now: u64 = self.now();
+        let stuck = |since: u64, limit: Duration| -> bool {
+            since != 0 && now.saturating_sub(since) > limit.as_millis() as u64
+        };
+
+        if stuck(self.accepting_since.load(Ordering::SeqCst), limit) {
+            return false;
+        }
+        self.workers.iter().all(|worker| {
+            worker.alive.load(Ordering::SeqCst)
+                && !worker_limit
+                    .is_some_and(|limit| stuck(worker.busy_since.load(Ordering::SeqCst), limit))
+        })
+    }
+
+    fn worker_busy(&self, id: usize, busy: bool) {
+        let since: u64 = if busy { self.now() } else { 0 };
+        self.workers[id].busy_since.store(since, Ordering::SeqCst);
+    }
+}
+
+/// Tells the heartbeat the worker running on this thread got somewhere with its connection, a
+/// session or a long transfer calls it for every request or chunk. Does nothing outside the
+/// pool.
+pub fn beat() {
+    CURRENT_WORKER.with(|current| {
+        if let Some((heartbeat, id)) = &*current.borrow() {
+            heartbeat.worker_busy(*id, true);
+        }
+    });
+}
+
+/// The accept loop working on a connection, see [`Heartbeat::accepting`].
+pub struct Accepting<'a> {
+    heartbeat: &'a Heartbeat,
+}
+
+impl Drop for Accepting<'_> {
+    fn drop(&mut self) {
+        self.heartbeat.accepting_since.store(0, Ordering::SeqCst);
+    }
+}
+
+/// Runs queued connections until the pool goes away.
+fn work<F>(jobs: Arc<Mutex<Receiver<Job>>>, handler: Arc<F>, worker: Worker)
+where
+    F: Fn(UnixStream, PeerCredentials),
+{
+    let heartbeat: Arc<Heartbeat> = worker.heartbeat.clone();
+    CURRENT_WORKER.with(|current| *current.borrow_mut() = Some((heartbeat.clone(), worker.id)));
+
+    loop {
+        // The lock is only held while waiting, not while the job runs
+        let next = match jobs.lock() {
+            Ok(queue) => queue.recv(),
+            Err(_) => return,
+        };
+        let Job { stream, peer, slot } = match next {
+            Ok(d) => d,
+            Err(_) => return,
+        };
+        heartbeat.worker_busy(worker.id, true);
+
+        // A panicking request must not take the worker down with it
+        if panic::catch_unwind(AssertUnwindSafe(|| handler(stream, peer))).is_err() {
+            warn(&format!(
+                "A request from uid {} (pid {}) panicked",
+                peer.uid, peer.pid
+            ));
+        }
+        heartbeat.worker_busy(worker.id, false);
+        drop(slot);
+    }
+}
+
+/// Counts the open connections of every uid.
+#[derive(Clone)]
+pub struct ConnectionLimits {
+    per_uid: usize,
+    active: Arc<Mutex<HashMap<u32, usize>>>,
+}
+
+impl ConnectionLimits {
+    /// Allows each uid `per_uid` connections at once, 0 means no limit.
+    pub fn new(per_uid: usize) -> Self {
+        ConnectionLimits {
+            per_uid,
+            active: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Takes a connection slot for `uid`, `None` if it already has as many as it may.
+    pub fn acquire(&self, uid: u32) -> Option<Slot> {
+        let mut active = self.active.lock().ok()?;
+        let count: &mut usize = active.entry(uid).or_insert(0);
+        if self.per_uid > 0 && *count >= self.per_uid {
+            return None;
+        }
+        *count += 1;
+
+        Some(Slot {
+            uid,
+            active: self.active.clone(),
+        })
+    }
+}
+
+/// One open connection of a uid, released when dropped.
+pub struct Slot {
+    uid: u32,
+    active: Arc<Mutex<HashMap<u32, usize>>>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Ok(mut active) = self.active.lock() {
+            if let Some(count) = active.get_mut(&self.uid) {
+                *count = count.saturating_sub(1);
+                if *count == 0 {
+                    active.remove(&self.uid);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: Option<Duration> = Some(Duration::from_millis(10));
+
+    #[test]
+    fn an_idle_daemon_is_alive() {
+        let heartbeat = Heartbeat::new(2);
+        assert!(heartbeat.alive(Duration::from_secs(15), LIMIT));
+        thread::sleep(Duration::from_millis(20));
+        assert!(heartbeat.alive(Duration::from_secs(15), LIMIT));
+    }
+
+    #[test]
+    fn a_worker_that_keeps_beating_is_alive() {
+        let heartbeat = Heartbeat::new(2);
+        heartbeat.worker_busy(0, true);
+        for _ in 0..3 {
+            thread::sleep(Duration::from_millis(6));
+            heartbeat.worker_busy(0, true);
+            assert!(heartbeat.alive(Duration::from_secs(15), LIMIT));
+        }
+    }
+
+    #[test]
+    fn a_stuck_worker_stops_the_heartbeat() {
+        let heartbeat = Heartbeat::new(2);
+        heartbeat.worker_busy(1, true);
+        thread::sleep(Duration::from_millis(20));
+        assert!(!heartbeat.alive(Duration::from_secs(15), LIMIT));
+        // Unless nobody bounds how long a worker may take
+        assert!(heartbeat.alive(Duration::from_secs(15), None));
+
+        heartbeat.worker_busy(1, false);
+        assert!(heartbeat.alive(Duration::from_secs(15), LIMIT));
+    }
+
+    #[test]
+    fn a_dead_worker_stops_the_heartbeat() {
+        let heartbeat = Arc::new(Heartbeat::new(2));
+        let running = Arc::new((Mutex::new(0), Condvar::new()));
+        let worker = Worker::start(running, heartbeat.clone(), 1);
+        assert!(heartbeat.alive(Duration::from_secs(15), LIMIT));
+
+        drop(worker);
+        assert!(!heartbeat.alive(Duration::from_secs(15), LIMIT));
+    }
+
+    #[test]
+    fn an_accept_loop_stuck_on_one_connection_stops_the_heartbeat() {
+        let heartbeat = Heartbeat::new(1);
+        let accepting = heartbeat.accepting();
+        assert!(heartbeat.alive(Duration::from_secs(15), LIMIT));
+        thread::sleep(Duration::from_millis(20));
+        assert!(!heartbeat.alive(Duration::from_millis(10), LIMIT));
+
+        drop(accepting);
+        assert!(heartbeat.alive(Duration::from_millis(10), LIMIT));
+    }
+}
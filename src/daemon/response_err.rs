@@ -1,27 +1,103 @@
-use std::os::unix::net::UnixStream;
+use std::{cell::RefCell, os::unix::net::UnixStream};
 
-use dusa_common::{prefix::{send_message, GeneralMessage}, DusaError, ErrorCode, MessageType, VERSION};
 use dusa_collection_utils::errors::{ErrorArray, UnifiedResult};
+use dusa_common::{
+    prefix::{send_message, FrameError},
+    DusaError, ErrorCode, Message, MessageType, ResponsePayload, PROTOCOL_VERSION,
+};
 
-pub fn internal_error(err: &str) -> GeneralMessage {
-    let error = DusaError {
-        code: ErrorCode::InternalError,
-        message: err.to_string(),
-    };
-    return GeneralMessage {
-        version: VERSION.to_owned(),
+thread_local! {
+    /// The protocol version responses on this thread's connection carry.
+    static SPOKEN_VERSION: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Answers this thread's connection in `version` from now on, what the client negotiated or,
+/// without a handshake, the version it sent. `None` goes back to [`PROTOCOL_VERSION`].
+pub fn speak(version: Option<String>) {
+    SPOKEN_VERSION.with(|spoken| *spoken.borrow_mut() = version);
+}
+
+fn spoken_version() -> String {
+    SPOKEN_VERSION
+        .with(|spoken| spoken.borrow().clone())
+        .unwrap_or_else(|| PROTOCOL_VERSION.to_string())
+}
+
+/// Builds a successful response.
+pub fn response(payload: ResponsePayload) -> Message<ResponsePayload> {
+    Message {
+        version: spoken_version(),
+        msg_type: MessageType::Response,
+        payload,
+        error: None,
+        id: None,
+    }
+}
+
+/// Builds an error response carrying `code`.
+pub fn error_response(code: ErrorCode, err: &str) -> Message<ResponsePayload> {
+    Message {
+        version: spoken_version(),
         msg_type: MessageType::ErrorResponse,
-        payload: serde_json::json!({"error": "Internal Server Error"}),
-        error: Some(error),
+        payload: ResponsePayload::Error(code.to_string()),
+        error: Some(DusaError {
+            code,
+            message: err.to_string(),
+        }),
+        id: None,
+    }
+}
+
+pub fn internal_error(err: &str) -> Message<ResponsePayload> {
+    error_response(ErrorCode::InternalError, err)
+}
+
+pub fn permission_denied(err: &str) -> Message<ResponsePayload> {
+    error_response(ErrorCode::InvalidPermissions, err)
+}
+
+pub fn invalid_payload(err: &str) -> Message<ResponsePayload> {
+    error_response(ErrorCode::InvalidPayload, err)
+}
+
+pub fn invalid_encoding(err: &str) -> Message<ResponsePayload> {
+    error_response(ErrorCode::InvalidEncoding, err)
+}
+
+pub fn busy(err: &str) -> Message<ResponsePayload> {
+    error_response(ErrorCode::Busy, err)
+}
+
+/// The response telling a peer why its message wasn't read, `None` if the peer is gone and
+/// there's nobody to tell.
+pub fn frame_error(err: &FrameError) -> Option<Message<ResponsePayload>> {
+    let code: ErrorCode = match err {
+        FrameError::TooLarge { .. } => ErrorCode::FrameTooLarge,
+        FrameError::Malformed(_) => ErrorCode::MalformedFrame,
+        FrameError::TimedOut => ErrorCode::Timeout,
+        FrameError::Io(_) => return None,
     };
+    Some(error_response(code, &err.to_string()))
 }
 
-pub fn acknowledge(stream: &mut UnixStream, errors: ErrorArray) -> UnifiedResult<()> {
-    let ack = GeneralMessage {
-        version: VERSION.to_owned(),
+/// Sends a response, reporting but otherwise ignoring a client that went away.
+pub fn reply(stream: &mut UnixStream, message: &Message<ResponsePayload>, errors: ErrorArray) {
+    if let Err(err) = send_message(stream, message, errors).uf_unwrap() {
+        err.display(false)
+    }
+}
+
+/// Builds the ack that ends a request.
+pub fn ack() -> Message<ResponsePayload> {
+    Message {
+        version: spoken_version(),
         msg_type: MessageType::Acknowledge,
-        payload: serde_json::json!({}),
+        payload: ResponsePayload::Empty,
         error: None,
-    };
-    send_message(stream, &ack, errors.clone())
+        id: None,
+    }
+}
+
+pub fn acknowledge(stream: &mut UnixStream, errors: ErrorArray) -> UnifiedResult<()> {
+    send_message(stream, &ack(), errors.clone())
 }
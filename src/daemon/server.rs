@@ -1,52 +1,324 @@
+pub mod audit;
+pub mod catalog;
+pub mod cli;
+pub mod metrics;
+pub mod peer;
+pub mod policy;
+pub mod pool;
 pub mod response_err;
+pub mod shutdown;
+pub mod stats;
+pub mod systemd;
+pub mod temp;
+pub mod upload;
 
+use audit::{verify_audit, AuditContext, AuditLog};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use catalog::{Catalog, CatalogEntry, SharedCatalog};
+use cli::{build_cli, print_man, resolve_config};
 use dusa_collection_utils::{
-    errors::{ErrorArray, ErrorArrayItem, Errors, WarningArray},
-    functions::{del_file, truncate},
+    errors::{ErrorArray, ErrorArrayItem, Errors, UnifiedResult as uf, WarningArray},
+    functions::truncate,
     types::{ClonePath, PathType},
 };
 use dusa_common::{
-    check_version, get_id,
-    prefix::{receive_message, send_message, GeneralMessage},
-    set_file_ownership, set_socket_permission, DecryptResponseData, DusaError, ErrorCode, Message,
-    MessageType, RequestPayload, RequestRecsSimple, SOCKET_PATH, TTL, VERSION,
+    config::{config, set_config, Config},
+    get_id,
+    prefix::{read_frame, send_fd, send_message, GeneralMessage},
+    set_socket_permission, BatchResult, Commands, DaemonStatus, DataChunk, DecryptResponseData,
+    DusaError, EntryInfo, ErrorCode, Feature, FileDescriptorData, FileStat, Hello, Message,
+    MessageType, Negotiated, RequestPayload, RequestRecsBatch, RequestRecsPlainText,
+    RequestRecsQuery, RequestRecsSimple, RequestRecsUpload, ResponsePayload,
+    CHUNK_SIZE, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, SOCKET_PATH,
 };
-use nix::unistd::{setgid, setuid};
-use recs::{decrypt_raw, encrypt_raw, initialize, remove, retrieve, store};
-use serde_json::json;
-use simple_pretty::{halt, notice, output};
+use metrics::serve_metrics;
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags},
+    unistd::{setgid, setuid},
+    Error,
+};
+use peer::{get_peer_credentials, PeerCredentials};
+use policy::{
+    invalid_names, reload_on_sighup, request_names, required_access, Access, Policy, SharedPolicy,
+};
+use pool::{beat, ConnectionLimits, Job, WorkerPool};
+use recs::{decrypt_raw, encrypt_raw, initialize, ping, remove, retrieve, store};
+use response_err::{
+    ack, acknowledge, busy, error_response, frame_error, internal_error, invalid_encoding,
+    invalid_payload, permission_denied, reply, response, speak,
+};
+use shutdown::{clean_up, stop_on_signal};
+use simple_pretty::{halt, notice, output, pass, warn};
+use stats::Stats;
 use std::{
-    os::unix::net::{UnixListener, UnixStream},
+    collections::BTreeMap,
+    fs::{self, File},
+    io::Read,
+    os::unix::{
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    process::exit,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, RwLock,
+    },
     thread::{self},
     time::Duration,
 };
+use systemd::{feed_watchdog, listen_fds, notify};
+use temp::{
+    into_memfd, pending_temp_files, reset_raw_staging, schedule_cleanup, secure_delete,
+    sweep_temp_files,
+};
+use upload::{prepare_upload_dir, receive_upload, UploadError};
+
+/// How long the accept loop waits after a failed accept, and on a client it turns away.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The last part of a recs sequence whose plaintext isn't utf8, recs got it as base64.
+const BINARY_MARKER: &str = "b64";
+
+/// recs stages raw text through one fixed file and entry, two workers doing it at once would mix
+/// up each other's plaintext and keys.
+static RAW_TEXT: Mutex<()> = Mutex::new(());
+
+/// Everything the connection handlers share.
+#[derive(Clone)]
+struct DaemonState {
+    policy: SharedPolicy,
+    catalog: SharedCatalog,
+    stats: Arc<Stats>,
+    audit: Arc<AuditLog>,
+    /// Set once the daemon is asked to stop, sessions end at their next request.
+    stopping: Arc<AtomicBool>,
+}
 
 fn main() {
+    // Before anything starts a thread, taking the socket systemd may have passed us
+    let activated: Option<UnixListener> = listen_fds();
+
     // Initializing 1st errors and warnings
     let e1: ErrorArray = ErrorArray::new_container();
     let w1: WarningArray = WarningArray::new_container();
 
+    // Reading the config, everything below depends on it
+    let cmd: clap::ArgMatches = build_cli().get_matches();
+    if cmd.get_flag("man") {
+        match print_man() {
+            Ok(_) => exit(0),
+            Err(e) => {
+                halt(&format!("Couldn't write the man page: {}", e));
+                exit(1)
+            }
+        }
+    }
+    match resolve_config(&cmd, e1.clone()).uf_unwrap() {
+        Ok(d) => {
+            set_config(d);
+        }
+        Err(e) => e.display(true),
+    }
+    let config: &Config = config();
+
+    if cmd.get_flag("verify_audit") {
+        match verify_audit(e1.clone()).uf_unwrap() {
+            Ok(0) => {
+                warn("The audit log has no chained records to verify");
+                exit(1)
+            }
+            Ok(checked) => {
+                pass(&format!("The hash chain of {} records is intact", checked));
+                exit(0)
+            }
+            Err(e) => {
+                e.display(false);
+                exit(1)
+            }
+        }
+    }
+
     // Make sure we are running as the dusa user
     let (uid, gid) = get_id();
-    match (setuid(uid.into()), setgid(gid.into())) {
+    match (setuid(uid), setgid(gid)) {
         (Ok(_), Ok(_)) => (),
         _ => halt("We aren't running as the correct user, peacing out .."),
     };
 
-    pub const PROGNAME: &str = "dusa";
-    // Initializing recs
+    // Initializing recs, it wants its name for the rest of the process' life
     recs::set_debug(false);
-    recs::set_prog(PROGNAME);
+    recs::set_prog(Box::leak(config.prog_name.clone().into_boxed_str()));
 
-    if let Err(mut err) = initialize(e1.clone(), w1.clone()).uf_unwrap() {
-        err.push(ErrorArrayItem::new(
-            Errors::GeneralError,
-            "Recs failed to initialize".to_string(),
-        ));
-        err.display(true);
+    let stats: Arc<Stats> = Arc::new(Stats::default());
+    match initialize(e1.clone(), w1.clone()).uf_unwrap() {
+        Ok(_) => stats.recs_initialized(),
+        Err(mut err) => {
+            err.push(ErrorArrayItem::new(
+                Errors::GeneralError,
+                "Recs failed to initialize".to_string(),
+            ));
+            err.display(true);
+        }
+    }
+
+    // Loading the access policy, SIGHUP reloads it without restarting
+    let policy_path: PathBuf = config.policy_path.clone();
+    let policy: SharedPolicy = match Policy::load_or_default(&policy_path, e1.clone()).uf_unwrap() {
+        Ok(d) => Arc::new(RwLock::new(d)),
+        Err(e) => {
+            e.display(true);
+            unreachable!()
+        }
+    };
+    reload_on_sighup(policy.clone(), policy_path);
+
+    // Loading the catalog of stored entries
+    let catalog: SharedCatalog = match Catalog::load(&config.data_dir(), e1.clone()).uf_unwrap() {
+        Ok(d) => Arc::new(Mutex::new(d)),
+        Err(e) => {
+            e.display(true);
+            unreachable!()
+        }
+    };
+
+    // Everything a client asks for is recorded, running without a record isn't an option
+    let audit: AuditLog = match AuditLog::open(e1.clone()).uf_unwrap() {
+        Ok(d) => d,
+        Err(e) => {
+            e.display(true);
+            unreachable!()
+        }
+    };
+
+    // Plaintext from uploads that never finished must not survive a restart
+    if let Err(e) = prepare_upload_dir(e1.clone()).uf_unwrap() {
+        e.display(true);
+    }
+
+    // Same for decrypted files, anything a crashed run handed out is deleted or rescheduled
+    if let Err(e) = sweep_temp_files(e1.clone()).uf_unwrap() {
+        e.display(false);
+    }
+
+    // With socket activation systemd owns the socket and keeps it around between restarts
+    let owns_socket: bool = activated.is_none();
+    let listener: UnixListener = match activated {
+        Some(d) => {
+            notice("Using the socket passed by systemd");
+            d
+        }
+        None => bind_socket(e1.clone(), w1.clone()),
+    };
+    let socket_path: PathBuf = listener
+        .local_addr()
+        .ok()
+        .and_then(|addr| addr.as_pathname().map(PathBuf::from))
+        .unwrap_or_else(|| config.socket_path.clone());
+    let stopping: Arc<AtomicBool> = stop_on_signal(socket_path.clone());
+
+    let state = DaemonState {
+        policy,
+        catalog,
+        stats: stats.clone(),
+        audit: Arc::new(audit),
+        stopping: stopping.clone(),
+    };
+
+    // A fixed set of workers handles the connections, the rest wait in a bounded queue
+    let shared: DaemonState = state.clone();
+    let pool: WorkerPool = match WorkerPool::new(
+        config.workers,
+        config.queue_size,
+        move |stream, peer| {
+            let e2: ErrorArray = ErrorArray::new_container();
+            let w2: WarningArray = WarningArray::new_container();
+            handle_client(stream, peer, shared.clone(), e2, w2)
+        },
+        e1.clone(),
+    )
+    .uf_unwrap()
+    {
+        Ok(d) => d,
+        Err(e) => {
+            e.display(true);
+            unreachable!()
+        }
+    };
+    // Metrics are optional, the daemon works fine without them
+    if let Err(e) = serve_metrics(stats.clone(), e1.clone()).uf_unwrap() {
+        e.display(false);
+    }
+
+    let limits = ConnectionLimits::new(config.max_connections_per_uid);
+
+    let heartbeat = pool.heartbeat();
+    notify("READY=1");
+    feed_watchdog(heartbeat.clone());
+
+    for stream in listener.incoming() {
+        if stopping.load(Ordering::SeqCst) {
+            break;
+        }
+        let stream: UnixStream = match stream {
+            Ok(d) => d,
+            Err(e) => {
+                // Usually out of descriptors, give the workers a moment to close some
+                notice(&format!("Error accepting connection: {}", e));
+                thread::sleep(ACCEPT_BACKOFF);
+                continue;
+            }
+        };
+        stats.accepted();
+        let _accepting = heartbeat.accepting();
+
+        // Every authorization decision is based on what the kernel tells us about the peer
+        let peer: PeerCredentials =
+            match get_peer_credentials(&stream, ErrorArray::new_container()).uf_unwrap() {
+                Ok(d) => d,
+                Err(e) => {
+                    e.display(false);
+                    continue;
+                }
+            };
+
+        let slot = match limits.acquire(peer.uid) {
+            Some(d) => d,
+            None => {
+                let message = format!(
+                    "uid {} already has {} connections open",
+                    peer.uid, config.max_connections_per_uid
+                );
+                turn_away(stream, &peer, &state, &message);
+                continue;
+            }
+        };
+
+        if let Err(job) = pool.submit(Job::new(stream, peer, slot)) {
+            turn_away(
+                job.stream,
+                &peer,
+                &state,
+                "Too many requests are waiting, try again later",
+            );
+        }
     }
 
-    let socket_path: PathType = match SOCKET_PATH(true, e1.clone(), w1.clone()).uf_unwrap() {
+    // Nothing new is accepted, give what's running a chance to finish
+    notify("STOPPING=1");
+    drop(listener);
+    if !pool.shutdown(Duration::from_secs(config.shutdown_timeout)) {
+        warn("Requests were still running at the shutdown deadline, leaving them unfinished");
+    }
+    clean_up(owns_socket.then_some(socket_path.as_path()), e1);
+    notice("Stopped");
+}
+
+/// Creates the socket at the configured path, replacing whatever is there.
+fn bind_socket(errors: ErrorArray, warnings: WarningArray) -> UnixListener {
+    let socket_path: PathType = match SOCKET_PATH(true, errors, warnings).uf_unwrap() {
         Ok(d) => {
             d.warning.display();
             d.data
@@ -70,452 +342,1110 @@ fn main() {
     };
 
     // setting correct permissions on the socket
-    set_socket_permission(socket_path.clone()); // return an error
+    set_socket_permission(socket_path); // return an error
+    listener
+}
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let e2: ErrorArray = ErrorArray::new_container();
-                let w2: WarningArray = WarningArray::new_container();
-                // Spawn a new thread or use async/await to handle each incoming connection
-                thread::spawn(move || handle_client(stream, e2.clone(), w2.clone()));
+/// Tells a client we won't handle its connection right now. Its request is never read, the
+/// audit record only names the peer.
+fn turn_away(mut stream: UnixStream, peer: &PeerCredentials, state: &DaemonState, message: &str) {
+    state.stats.turned_away();
+    notice(&format!(
+        "Turning away uid {} (pid {}): {}",
+        peer.uid, peer.pid, message
+    ));
+    // This runs on the accept loop, it can't wait on a client that doesn't read
+    let _ = stream.set_write_timeout(Some(ACCEPT_BACKOFF));
+    let audit = AuditContext::new(peer);
+    answer(
+        &mut stream,
+        state,
+        &audit,
+        busy(message),
+        ErrorArray::new_container(),
+    );
+}
+
+fn handle_client(
+    mut stream: UnixStream,
+    peer: PeerCredentials,
+    state: DaemonState,
+    errors: ErrorArray,
+    warnings: WarningArray,
+) {
+    let _active = state.stats.connection();
+    let mut audit = AuditContext::new(&peer);
+    // Workers are reused, the last connection's version mustn't leak into this one
+    speak(None);
+
+    // A peer that stops talking or listening doesn't get to keep this thread
+    let deadlines = stream
+        .set_read_timeout(config().read_deadline())
+        .and_then(|_| stream.set_write_timeout(config().write_deadline()));
+    if let Err(e) = deadlines {
+        let mut errors = errors;
+        errors.push(ErrorArrayItem::from(e));
+        errors.display(false);
+        return;
+    }
+
+    let first: GeneralMessage =
+        match next_message(&mut stream, &peer, &state, &audit, errors.clone()) {
+            Some(d) => d,
+            None => return,
+        };
+
+    // Agreeing on a protocol version and features, clients from before the handshake send their
+    // request right away
+    let (negotiated, new_message): (Negotiated, GeneralMessage) = match first.msg_type {
+        MessageType::Hello => {
+            audit.next(first.id);
+            audit.command("Hello");
+            let hello: Hello = match first.payload_as() {
+                Ok(d) => d,
+                Err(e) => {
+                    let response = invalid_payload(&e.to_string());
+                    answer(&mut stream, &state, &audit, response, errors.clone());
+                    return;
+                }
+            };
+            let negotiated: Negotiated = match Hello::ours().negotiate(&hello) {
+                Some(d) => d,
+                None => {
+                    let message = format!(
+                        "The server speaks protocol versions {} to {}, the client {} to {}",
+                        MIN_PROTOCOL_VERSION,
+                        PROTOCOL_VERSION,
+                        hello.min_version,
+                        hello.max_version
+                    );
+                    let response = error_response(ErrorCode::InvalidVersion, &message);
+                    answer(&mut stream, &state, &audit, response, errors.clone());
+                    return;
+                }
+            };
+
+            speak(Some(negotiated.version.to_string()));
+            let agreed = response(ResponsePayload::Negotiated(negotiated.clone()));
+            answer(&mut stream, &state, &audit, agreed, errors.clone());
+            if negotiated.supports(Feature::Sessions) && !wait_for_request(&stream, &peer) {
+                return;
+            }
+            match next_message(&mut stream, &peer, &state, &audit, errors.clone()) {
+                Some(d) => (negotiated, d),
+                None => return,
             }
-            Err(e) => halt(&format!("Error accepting connection: {}", e)),
         }
+        _ => match Negotiated::legacy_version(&first.version) {
+            Some(version) if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) => {
+                speak(Some(first.version.clone()));
+                (Negotiated::legacy(), first)
+            }
+            _ => {
+                audit.next(first.id);
+                let message = format!(
+                    "The server speaks protocol versions {} to {}, the client sent {}",
+                    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, first.version
+                );
+                let response = error_response(ErrorCode::InvalidVersion, &message);
+                answer(&mut stream, &state, &audit, response, errors.clone());
+                return;
+            }
+        },
+    };
+
+    // One request, unless the client asked for a session
+    if !negotiated.supports(Feature::Sessions) {
+        audit.next(new_message.id);
+        handle_message(
+            &mut stream,
+            &peer,
+            &state,
+            &negotiated,
+            &mut audit,
+            new_message,
+            errors.clone(),
+            warnings,
+        );
+        finish(&mut stream, errors);
+        return;
     }
-}
 
-#[allow(unreachable_patterns)]
-fn handle_client(mut stream: UnixStream, mut errors: ErrorArray, warnings: WarningArray) {
-    let new_message: GeneralMessage = match receive_message(&mut stream, errors.clone()).uf_unwrap()
-    {
-        Ok(d) => d,
-        Err(e) => {
-            e.display(false);
+    // A session carries requests until the client closes it
+    let mut message: GeneralMessage = new_message;
+    loop {
+        beat();
+        audit.next(message.id);
+        if message.msg_type == MessageType::Close {
+            audit.command("Close");
+            answer(&mut stream, &state, &audit, ack(), errors);
+            return;
+        }
+        if state.stopping.load(Ordering::SeqCst) {
+            let response = busy("The server is shutting down, the session is over");
+            answer(&mut stream, &state, &audit, response, errors);
             return;
         }
-    };
 
-    // Checking the message version
-    if !check_version(&new_message.version) {
-        let error = DusaError {
-            code: ErrorCode::InvalidVersion,
-            message: format!(
-                "Client and Server out of date. Server version: {}, Client version: {}",
-                VERSION, &new_message.version
-            ),
-        };
-        let response = Message {
-            version: VERSION.to_owned(),
-            msg_type: MessageType::ErrorResponse,
-            payload: serde_json::json!({}),
-            error: Some(error),
+        let intact: bool = handle_message(
+            &mut stream,
+            &peer,
+            &state,
+            &negotiated,
+            &mut audit,
+            message,
+            errors.clone(),
+            warnings.clone(),
+        );
+        finish(&mut stream, errors.clone());
+        if !intact {
+            return;
+        }
+
+        if !wait_for_request(&stream, &peer) {
+            return;
+        }
+        message = match next_message(&mut stream, &peer, &state, &audit, errors.clone()) {
+            Some(d) => d,
+            None => return,
         };
-        if let Err(err) = send_message(&mut stream, &response, errors.clone()).uf_unwrap() {
-            err.display(false)
+    }
+}
+
+/// Waits for the next request of a session. Waiting holds a worker, so a session that has
+/// nothing to ask for within `session_idle_timeout` is closed and gives it back.
+///
+/// # Returns
+/// `false` if the session was idle for too long.
+fn wait_for_request(stream: &UnixStream, peer: &PeerCredentials) -> bool {
+    let idle: Duration = match config().session_idle_deadline() {
+        Some(d) => d,
+        // The read deadline is all there is
+        None => return true,
+    };
+    let timeout: i32 = idle.as_millis().min(i32::MAX as u128) as i32;
+    let mut fds = [PollFd::new(stream.as_raw_fd(), PollFlags::POLLIN)];
+    loop {
+        match poll(&mut fds, timeout) {
+            Ok(0) => break,
+            // Data or a hangup, either is for next_message to deal with
+            Ok(_) => return true,
+            Err(Error::Sys(Errno::EINTR)) => continue,
+            Err(_) => return true,
         }
     }
 
+    notice(&format!(
+        "Closing the idle session of uid {} (pid {})",
+        peer.uid, peer.pid
+    ));
+    false
+}
+
+/// Handles a single message of a connection.
+///
+/// # Returns
+/// Whether the connection can carry another request. It can't after a failed upload, what the
+/// client still sends of it can't be told apart from new requests.
+#[allow(clippy::too_many_arguments)]
+fn handle_message(
+    stream: &mut UnixStream,
+    peer: &PeerCredentials,
+    state: &DaemonState,
+    negotiated: &Negotiated,
+    audit: &mut AuditContext,
+    new_message: GeneralMessage,
+    errors: ErrorArray,
+    warnings: WarningArray,
+) -> bool {
     match new_message.msg_type {
         MessageType::Request => {
             // Deserialize the payload into a specific struct
-            let request_payload: RequestPayload = serde_json::from_value(new_message.payload)
-                .unwrap_or(RequestPayload::Simple(RequestRecsSimple {
-                    command: dusa_common::Commands::PingFile,
-                    owner: "system".to_string(),
-                    name: "test".to_string(),
-                    uid: 1000,
-                }));
-
-            let request_data: RequestPayload = match request_payload {
-                RequestPayload::Write(d) => RequestPayload::Write(d),
-                RequestPayload::PlainText(d) => RequestPayload::PlainText(d),
-                RequestPayload::Simple(d) => RequestPayload::Simple(d),
+            let request_data: RequestPayload = match new_message.payload_as() {
+                Ok(d) => d,
+                Err(e) => {
+                    answer(
+                        stream,
+                        state,
+                        audit,
+                        invalid_payload(&e.to_string()),
+                        errors.clone(),
+                    );
+                    return true;
+                }
             };
 
-            match request_data {
-                RequestPayload::Write(req) => {
-                    let owner = req.owner;
-                    let name = req.name;
-                    let path = req.path;
-                    let _uid = req.uid;
-                    match store(
-                        path.clone_path(),
-                        owner,
-                        name,
+            audit.request(&request_data);
+
+            // The uid in the request is only a claim, make sure the kernel agrees with it
+            if request_data.uid() != peer.uid {
+                notice(&format!(
+                    "Rejecting request from pid {}: claimed uid {} but connected as uid {}",
+                    peer.pid,
+                    request_data.uid(),
+                    peer.uid
+                ));
+                let response =
+                    permission_denied("The uid in the request does not match the connecting user");
+                answer(stream, state, audit, response, errors.clone());
+                return true;
+            }
+
+            // Owners and names recs can't store never reach the policy
+            if let Some(message) = invalid_names(&request_data) {
+                answer(
+                    stream,
+                    state,
+                    audit,
+                    invalid_payload(&message),
+                    errors.clone(),
+                );
+                return true;
+            }
+
+            // An owner and name recs keeps under the same name as another entry's
+            if let (Some(owner), Some(name)) = request_names(&request_data) {
+                let clash: Option<String> = match state.catalog.lock() {
+                    Ok(catalog) => catalog
+                        .clash(owner, name)
+                        .map(|e| format!("{}/{}", e.owner, e.name)),
+                    Err(_) => None,
+                };
+                if let Some(other) = clash {
+                    let message = format!(
+                        "{}/{} would be stored as the same entry as {}, pick another name",
+                        owner, name, other
+                    );
+                    answer(
+                        stream,
+                        state,
+                        audit,
+                        invalid_payload(&message),
+                        errors.clone(),
+                    );
+                    return true;
+                }
+            }
+
+            // Making sure the peer is allowed to touch this owner
+            if let Some((owner, access)) = required_access(&request_data) {
+                let allowed: bool = match state.policy.read() {
+                    Ok(rules) => rules.allows(peer, owner, access),
+                    Err(_) => false,
+                };
+                if !allowed {
+                    notice(&format!(
+                        "Denied {} access on owner {} to uid {} (pid {})",
+                        access, owner, peer.uid, peer.pid
+                    ));
+                    let response = permission_denied(&format!(
+                        "uid {} is not allowed {} access on owner {}",
+                        peer.uid, access, owner
+                    ));
+                    answer(stream, state, audit, response, errors.clone());
+                    return true;
+                }
+            }
+
+            // Requests needing what the two sides didn't agree on are refused before anything
+            // is done
+            let missing: Option<Feature> = request_data
+                .needs()
+                .into_iter()
+                .find(|f| !negotiated.supports(*f));
+            if let Some(feature) = missing {
+                let message = format!("This connection didn't agree on {}", feature);
+                let response = error_response(ErrorCode::Unsupported, &message);
+                answer(stream, state, audit, response, errors.clone());
+                return true;
+            }
+
+            // Timed until the transmission is over, sending attachments included
+            let _timer = state.stats.time(request_data.command());
+            let stats: &Stats = &state.stats;
+            let upload: bool = matches!(request_data, RequestPayload::Upload(_));
+            let command: &str = request_data.command();
+            // A panicking request still gets an answer, and with it an audit record
+            let dispatched = panic::catch_unwind(AssertUnwindSafe(|| match request_data {
+                // The daemon would open the path with its own rights for whoever asks
+                RequestPayload::Write(_) => (
+                    error_response(
+                        ErrorCode::Unsupported,
+                        "Storing a path is no longer supported, upload the data instead",
+                    ),
+                    None,
+                ),
+                RequestPayload::PlainText(req) => (
+                    handle_plain_text(req, stats, errors.clone(), warnings.clone()),
+                    None,
+                ),
+                RequestPayload::Simple(req) => handle_simple(
+                    req,
+                    peer,
+                    &state.catalog,
+                    stats,
+                    errors.clone(),
+                    warnings.clone(),
+                ),
+                RequestPayload::Query(req) => (handle_query(req, peer, state), None),
+                RequestPayload::Batch(req) => (
+                    handle_batch(req, stats, errors.clone(), warnings.clone()),
+                    None,
+                ),
+                RequestPayload::Upload(req) => (
+                    handle_upload(
+                        stream,
+                        req,
+                        &state.catalog,
+                        stats,
                         errors.clone(),
                         warnings.clone(),
-                    )
-                    .uf_unwrap()
+                    ),
+                    None,
+                ),
+            }));
+            let (response, attachment): (Message<ResponsePayload>, Option<Attachment>) =
+                match dispatched {
+                    Ok(d) => d,
+                    Err(_) => {
+                        warn(&format!(
+                            "A {} request from uid {} (pid {}) panicked",
+                            command, peer.uid, peer.pid
+                        ));
+                        (internal_error("The request failed unexpectedly"), None)
+                    }
+                };
+            let intact: bool = !upload || response.msg_type != MessageType::ErrorResponse;
+            answer(stream, state, audit, response, errors.clone());
+
+            // Descriptors and streams have to follow the response they belong to
+            match attachment {
+                Some(Attachment::Descriptor(file)) => {
+                    if let Err(err) = send_fd(stream, file.as_raw_fd(), errors.clone()).uf_unwrap()
                     {
-                        Ok(_) => {
-                            let response = Message {
-                                version: VERSION.to_owned(),
-                                msg_type: MessageType::Response,
-                                payload: serde_json::json!({"Ok":format!("file {} written", path)}),
-                                error: None,
-                            };
-                            if let Err(err) =
-                                send_message(&mut stream, &response, errors.clone()).uf_unwrap()
-                            {
-                                err.display(false)
-                            }
-                            output("GREEN", "done");
-                        }
-                        Err(e) => {
-                            e.display(false);
-                            let response = Message {
-                                version: VERSION.to_owned(),
-                                msg_type: MessageType::ErrorResponse,
-                                payload: serde_json::json!({"Error":"Error occurred while inserting"}),
-                                error: None,
-                            };
-                            if let Err(err) =
-                                send_message(&mut stream, &response, errors.clone()).uf_unwrap()
-                            {
-                                err.display(false)
-                            }
-                        }
+                        err.display(false)
                     }
+                }
+                Some(Attachment::Stream(file)) => {
+                    if let Err(err) = stream_file(stream, file, errors.clone()).uf_unwrap() {
+                        err.display(false)
+                    }
+                }
+                None => (),
+            }
 
-                    // Send an ACK message
-                    send_message(
-                        &mut stream,
-                        &Message {
-                            version: VERSION.to_owned(),
-                            msg_type: MessageType::Acknowledge,
-                            payload: serde_json::json!({}),
-                            error: None,
-                        },
-                        errors.clone(),
-                    );
+            intact
+        }
+        MessageType::Status => {
+            let _timer = state.stats.time("Status");
+            audit.command("Status");
+            let status = response(ResponsePayload::Status(daemon_status(state)));
+            answer(stream, state, audit, status, errors);
+            true
+        }
+        // Only wants the ack
+        MessageType::Simple => true,
+        _ => {
+            // Unknown type
+            let response = error_response(ErrorCode::UnknownMessageType, "Unknown message type");
+            answer(stream, state, audit, response, errors);
+            true
+        }
+    }
+}
 
-                    return;
+/// Reads the next message of a connection, telling the peer what was wrong with it if it can't
+/// be read.
+fn next_message(
+    stream: &mut UnixStream,
+    peer: &PeerCredentials,
+    state: &DaemonState,
+    audit: &AuditContext,
+    errors: ErrorArray,
+) -> Option<GeneralMessage> {
+    match read_frame(stream, config().max_frame_size) {
+        Ok(d) => Some(d),
+        Err(e) => {
+            notice(&format!(
+                "Dropping connection from uid {} (pid {}): {}",
+                peer.uid, peer.pid, e
+            ));
+            if let Some(response) = frame_error(&e) {
+                answer(stream, state, audit, response, errors);
+            }
+            None
+        }
+    }
+}
+
+/// Sends a response to the request `audit` is about, counting and recording it first.
+fn answer(
+    stream: &mut UnixStream,
+    state: &DaemonState,
+    audit: &AuditContext,
+    mut message: Message<ResponsePayload>,
+    errors: ErrorArray,
+) {
+    message.id = audit.id();
+    state.stats.answered(audit.asked_for(), &message);
+    state.audit.record(audit, &message, errors.clone());
+    reply(stream, &message, errors);
+}
+
+/// Ends a request with the ack the client waits for.
+fn finish(stream: &mut UnixStream, errors: ErrorArray) {
+    if let Err(err) = acknowledge(stream, errors).uf_unwrap() {
+        err.display(false)
+    }
+}
+
+/// Gathers the status, checking on what the workers depend on.
+fn daemon_status(state: &DaemonState) -> DaemonStatus {
+    let mut problems: Vec<String> = Vec::new();
+    if state.policy.read().is_err() {
+        problems.push(String::from(
+            "the access policy is poisoned by a panicked request",
+        ));
+    }
+    if state.catalog.lock().is_err() {
+        problems.push(String::from(
+            "the catalog is poisoned by a panicked request",
+        ));
+    }
+    if !config().data_dir().is_dir() {
+        problems.push(format!("{} is missing", config().data_dir().display()));
+    }
+    state.stats.status(pending_temp_files(), problems)
+}
+
+/// Receives data streamed by the client and stores it. The client waits for an ack before it
+/// starts sending.
+fn handle_upload(
+    stream: &mut UnixStream,
+    req: RequestRecsUpload,
+    catalog: &SharedCatalog,
+    stats: &Stats,
+    errors: ErrorArray,
+    warnings: WarningArray,
+) -> Message<ResponsePayload> {
+    if let Err(err) = acknowledge(stream, errors.clone()).uf_unwrap() {
+        err.display(false);
+        return internal_error("Failed to accept the upload");
+    }
+
+    let (upload_p, size) = match receive_upload(stream, &req, errors.clone()) {
+        Ok(d) => d,
+        Err(UploadError::TooLarge(limit)) => {
+            let message = format!("Uploads can't be larger than {} bytes", limit);
+            return error_response(ErrorCode::UploadTooLarge, &message);
+        }
+        Err(UploadError::Failed(e)) => {
+            e.display(false);
+            return invalid_payload("The upload was not received completely");
+        }
+    };
+
+    // recs can't encrypt nothing and panics trying
+    if size == 0 {
+        if let Err(e) = secure_delete(&upload_p, errors).uf_unwrap() {
+            e.display(false);
+        }
+        return invalid_payload("There is nothing to store, no data was sent");
+    }
+
+    let stored = store(
+        upload_p.clone(),
+        req.owner.clone(),
+        req.name.clone(),
+        errors.clone(),
+        warnings,
+    )
+    .uf_unwrap();
+
+    // The plaintext goes away whether or not recs took it
+    if let Err(e) = secure_delete(&upload_p, errors.clone()).uf_unwrap() {
+        e.display(false);
+    }
+
+    match stored {
+        Ok(_) => {
+            stats.encrypted(size);
+            let entry = CatalogEntry::new(&req.owner, &req.name, req.orig_p, size);
+            record(catalog, entry, errors);
+            output("GREEN", "done");
+            response(ResponsePayload::Stored(format!(
+                "{} bytes stored as {}/{}",
+                size, req.owner, req.name
+            )))
+        }
+        Err(e) => {
+            e.display(false);
+            internal_error("Error occurred while inserting")
+        }
+    }
+}
+
+/// Answers questions about stored entries in general.
+fn handle_query(
+    req: RequestRecsQuery,
+    peer: &PeerCredentials,
+    state: &DaemonState,
+) -> Message<ResponsePayload> {
+    match req.command {
+        Commands::List => {
+            let entries: Vec<EntryInfo> = match state.catalog.lock() {
+                Ok(catalog) => catalog.list(req.owner.as_deref()),
+                Err(_) => return internal_error("The catalog is unavailable"),
+            };
+            let policy = match state.policy.read() {
+                Ok(d) => d,
+                Err(_) => return internal_error("The access policy is unavailable"),
+            };
+
+            // Only what the caller could read is listed, decisions are made once per owner
+            let mut allowed: BTreeMap<String, bool> = BTreeMap::new();
+            let visible: Vec<EntryInfo> = entries
+                .into_iter()
+                .filter(|e| {
+                    *allowed
+                        .entry(e.owner.clone())
+                        .or_insert_with(|| policy.allows(peer, &e.owner, Access::Read))
+                })
+                .collect();
+            response(ResponsePayload::Entries(visible))
+        }
+        _ => internal_error("Invalid command parsing"),
+    }
+}
+
+/// Adds an entry to the catalog. Failing to do so is logged but doesn't fail the request, the data
+/// itself is already stored.
+fn record(catalog: &SharedCatalog, entry: CatalogEntry, errors: ErrorArray) {
+    if let Ok(mut catalog) = catalog.lock() {
+        if let Err(e) = catalog.record(entry, errors).uf_unwrap() {
+            e.display(false);
+        }
+    }
+}
+
+/// Looks up where an entry originally came from. Entries missing from the catalog were stored
+/// from a path, which recs remembers itself.
+fn original_path(
+    catalog: &SharedCatalog,
+    owner: &str,
+    name: &str,
+    recs_p: PathType,
+) -> Option<PathType> {
+    match catalog.lock() {
+        Ok(catalog) => match catalog.get(owner, name) {
+            Some(entry) => entry.orig_p.clone(),
+            None => Some(recs_p),
+        },
+        Err(_) => Some(recs_p),
+    }
+}
+
+/// Encrypts or decrypts raw text.
+fn handle_plain_text(
+    req: RequestRecsPlainText,
+    stats: &Stats,
+    mut errors: ErrorArray,
+    warnings: WarningArray,
+) -> Message<ResponsePayload> {
+    let data = req.data;
+
+    // recs divides the data into chunks by its length, there's nothing to divide in nothing
+    if data.is_empty() {
+        return invalid_payload("There is no data to work on");
+    }
+
+    match req.command {
+        Commands::EncryptRawText => {
+            let plain: Vec<u8> = match req.encoding.decode(&data) {
+                Ok(d) => d,
+                Err(e) => return invalid_encoding(&e),
+            };
+            if plain.is_empty() {
+                return invalid_payload("The data decodes to no bytes at all");
+            }
+            let size: u64 = plain.len() as u64;
+
+            let (text, binary): (String, bool) = to_recs_text(plain);
+            let _staging = raw_text_lock();
+            match encrypt_raw(text, errors.clone(), warnings).uf_unwrap() {
+                Ok((key, cipher, chunks)) => {
+                    stats.encrypted(size);
+                    let data: String = recs_sequence(&cipher, &key, chunks, binary);
+                    response(ResponsePayload::Text(data))
                 }
-                RequestPayload::PlainText(req) => {
-                    let command = req.command;
-                    let data = req.data;
-                    let _uid = req.uid;
-
-                    match command {
-                        dusa_common::Commands::EncryptRawText => {
-                            match encrypt_raw(data, errors.clone(), warnings.clone()).uf_unwrap() {
-                                Ok((key, cipher, chunks)) => {
-                                    let data: String = format!("{}-{}-{}", cipher, key, chunks);
-                                    let response = Message {
-                                        version: VERSION.to_string(),
-                                        msg_type: MessageType::Response,
-                                        payload: json!({"value":data}),
-                                        error: None,
-                                    };
-                                    if let Err(err) =
-                                        send_message(&mut stream, &response, errors.clone())
-                                            .uf_unwrap()
-                                    {
-                                        err.display(false)
-                                    }
-                                }
-                                Err(e) => {
-                                    e.display(false);
-                                    let response = Message {
-                                        version: VERSION.to_owned(),
-                                        msg_type: MessageType::ErrorResponse,
-                                        payload: serde_json::json!({"Error":"Error occurred while encrypting the data"}),
-                                        error: None,
-                                    };
-                                    if let Err(err) =
-                                        send_message(&mut stream, &response, errors.clone())
-                                            .uf_unwrap()
-                                    {
-                                        err.display(false)
-                                    };
-                                }
-                            }
-                        }
-                        dusa_common::Commands::DecryptRawText => {
-                            let recs_check: &str = truncate(&data, 10);
-                            let mut error_vec: ErrorArray = errors.clone();
-
-                            if !recs_check.contains("30312d") {
-                                errors.push(ErrorArrayItem::new(
-                                    Errors::InvalidBlockData,
-                                    format!("The data given is not a valid recs sequence {}", data),
-                                ));
-
-                                let response = Message {
-                                    version: VERSION.to_owned(),
-                                    msg_type: MessageType::ErrorResponse,
-                                    payload: serde_json::json!({"Error":"The data given was not encrypted by recs"}),
-                                    error: None,
-                                };
-
-                                if let Err(err) =
-                                    send_message(&mut stream, &response, errors.clone()).uf_unwrap()
-                                {
-                                    error_vec.append(err);
-                                    error_vec.display(false);
-                                } else {
-                                    error_vec.display(false);
-                                }
-                            }
-
-                            let parts: Vec<&str> = data.split('-').collect();
-                            let recs_data = parts.get(0).unwrap_or(&"").to_string();
-                            let recs_key = parts.get(1).unwrap_or(&"").to_string();
-                            let recs_chunks =
-                                parts.get(2).unwrap_or(&"1").parse::<usize>().unwrap_or(1);
-
-                            match decrypt_raw(
-                                recs_data,
-                                recs_key,
-                                recs_chunks,
-                                errors.clone(),
-                                warnings,
+                Err(e) => {
+                    e.display(false);
+                    internal_error("Error occurred while encrypting the data")
+                }
+            }
+        }
+        Commands::DecryptRawText => {
+            let recs_check: &str = truncate(&data, 10);
+
+            if !recs_check.contains("30312d") {
+                errors.push(ErrorArrayItem::new(
+                    Errors::InvalidBlockData,
+                    format!("The data given is not a valid recs sequence {}", data),
+                ));
+                errors.display(false);
+                return invalid_payload("The data given was not encrypted by recs");
+            }
+
+            let (recs_data, recs_key, recs_chunks, binary) = parse_sequence(&data);
+            if recs_chunks == 0 {
+                return invalid_payload("The recs sequence can't have 0 chunks");
+            }
+
+            let _staging = raw_text_lock();
+            match decrypt_raw(recs_data, recs_key, recs_chunks, errors.clone(), warnings)
+                .uf_unwrap()
+            {
+                Ok(d) => {
+                    d.warning.display();
+                    let plain: Vec<u8> = match from_recs_text(d.data, binary) {
+                        Ok(d) => d,
+                        Err(_) => {
+                            return internal_error(
+                                "The decrypted data is not the base64 it should be",
                             )
-                            .uf_unwrap()
-                            {
-                                Ok(d) => {
-                                    let data = d.data.clone();
-                                    let message = String::from_utf8(data).unwrap();
-                                    d.warning.display();
-                                    let response = Message {
-                                        version: VERSION.to_owned(),
-                                        msg_type: MessageType::Response,
-                                        payload: serde_json::json!({"value":message}),
-                                        error: None,
-                                    };
-                                    if let Err(err) =
-                                        send_message(&mut stream, &response, errors.clone())
-                                            .uf_unwrap()
-                                    {
-                                        err.display(false)
-                                    };
-                                }
-                                Err(e) => {
-                                    let response = Message {
-                                        version: VERSION.to_owned(),
-                                        msg_type: MessageType::ErrorResponse,
-                                        payload: serde_json::json!({"Error":"Error occurred while decrypting the data"}),
-                                        error: None,
-                                    };
-                                    if let Err(err) =
-                                        send_message(&mut stream, &response, errors.clone())
-                                            .uf_unwrap()
-                                    {
-                                        err.display(false)
-                                    } else {
-                                        e.display(false)
-                                    }
-                                }
-                            }
-                        }
-                        _ => {
-                            let error = DusaError {
-                                code: ErrorCode::InternalError,
-                                message: "Invalid command parsing".to_string(),
-                            };
-                            let response = Message {
-                                version: VERSION.to_owned(),
-                                msg_type: MessageType::ErrorResponse,
-                                payload: serde_json::json!({}),
-                                error: Some(error),
-                            };
-                            if let Err(err) =
-                                send_message(&mut stream, &response, errors.clone()).uf_unwrap()
-                            {
-                                err.display(false)
-                            }
                         }
+                    };
+                    stats.decrypted(plain.len() as u64);
+                    match req.encoding.encode(plain) {
+                        Ok(message) => response(ResponsePayload::Text(message)),
+                        Err(e) => invalid_encoding(&e),
                     }
                 }
-                RequestPayload::Simple(req) => {
-                    let owner = req.owner;
-                    let name = req.name;
-                    let _uid = req.uid;
+                Err(e) => {
+                    e.display(false);
+                    internal_error("Error occurred while decrypting the data")
+                }
+            }
+        }
+        _ => internal_error("Invalid command parsing"),
+    }
+}
+
+/// What recs is given for `plain`. recs only takes text, bytes that aren't utf8 go in as base64
+/// and the returned flag says so.
+fn to_recs_text(plain: Vec<u8>) -> (String, bool) {
+    match String::from_utf8(plain) {
+        Ok(d) => (d, false),
+        Err(e) => (BASE64.encode(e.into_bytes()), true),
+    }
+}
+
+/// The sequence handed to the client for what recs encrypted, marked when recs got base64.
+fn recs_sequence(cipher: &str, key: &str, chunks: usize, binary: bool) -> String {
+    match binary {
+        true => format!("{}-{}-{}-{}", cipher, key, chunks, BINARY_MARKER),
+        false => format!("{}-{}-{}", cipher, key, chunks),
+    }
+}
+
+/// Splits a sequence from [`recs_sequence`] into the cipher, the key, the chunk count and
+/// whether recs got base64.
+fn parse_sequence(data: &str) -> (String, String, usize, bool) {
+    let parts: Vec<&str> = data.split('-').collect();
+    let recs_data = parts.first().unwrap_or(&"").to_string();
+    let recs_key = parts.get(1).unwrap_or(&"").to_string();
+    let recs_chunks = parts.get(2).unwrap_or(&"1").parse::<usize>().unwrap_or(1);
+    let binary: bool = parts.get(3) == Some(&BINARY_MARKER);
+    (recs_data, recs_key, recs_chunks, binary)
+}
+
+/// The plaintext of what recs decrypted, undoing [`to_recs_text`].
+fn from_recs_text(decrypted: Vec<u8>, binary: bool) -> Result<Vec<u8>, base64::DecodeError> {
+    match binary {
+        true => BASE64.decode(&decrypted),
+        false => Ok(decrypted),
+    }
+}
+
+/// Holds the raw text staging of recs, see [`RAW_TEXT`]. The staging is reset when it's taken and
+/// again when it's dropped, so neither a worker that panicked halfway nor one that finished leaves
+/// plaintext or a half written entry for the next.
+struct RawText {
+    _guard: MutexGuard<'static, ()>,
+}
+
+fn raw_text_lock() -> RawText {
+    let guard: MutexGuard<'static, ()> = RAW_TEXT.lock().unwrap_or_else(PoisonError::into_inner);
+    reset_raw_staging();
+    RawText { _guard: guard }
+}
+
+impl Drop for RawText {
+    fn drop(&mut self) {
+        reset_raw_staging();
+    }
+}
+
+/// Runs the text operations of a batch one after the other. An item that fails gets its error in
+/// its place of the results, the rest still run.
+fn handle_batch(
+    req: RequestRecsBatch,
+    stats: &Stats,
+    errors: ErrorArray,
+    warnings: WarningArray,
+) -> Message<ResponsePayload> {
+    let results: Vec<BatchResult> = req
+        .items
+        .into_iter()
+        .map(|item| {
+            if !matches!(
+                item.command,
+                Commands::EncryptRawText | Commands::DecryptRawText
+            ) {
+                return BatchResult::Error(DusaError {
+                    code: ErrorCode::InvalidPayload,
+                    message: format!("{} can't be part of a batch", item.command),
+                });
+            }
+
+            let request = RequestRecsPlainText {
+                command: item.command,
+                data: item.data,
+                encoding: item.encoding,
+                uid: req.uid,
+            };
+            let answered = handle_plain_text(request, stats, errors.clone(), warnings.clone());
+            match (answered.payload, answered.error) {
+                (_, Some(e)) => BatchResult::Error(e),
+                (ResponsePayload::Text(d), None) => BatchResult::Text(d),
+                (other, None) => BatchResult::Error(DusaError {
+                    code: ErrorCode::InternalError,
+                    message: format!("Unexpected result {:?}", other),
+                }),
+            }
+        })
+        .collect();
+
+    response(ResponsePayload::Batch(results))
+}
+
+/// Data that is sent to the client right after the response it belongs to.
+enum Attachment {
+    /// Passed as a descriptor with `SCM_RIGHTS`.
+    Descriptor(File),
+    /// Read and sent as a series of chunks.
+    Stream(File),
+}
+
+/// Handles the requests that only name a stored entry. Requests that decrypt into an anonymous
+/// file also return what has to be sent after the response.
+fn handle_simple(
+    req: RequestRecsSimple,
+    peer: &PeerCredentials,
+    catalog: &SharedCatalog,
+    stats: &Stats,
+    errors: ErrorArray,
+    warnings: WarningArray,
+) -> (Message<ResponsePayload>, Option<Attachment>) {
+    let owner = req.owner;
+    let name = req.name;
+
+    match req.command {
+        Commands::DecryptFile if !config().legacy_temp_decrypt => (
+            error_response(
+                ErrorCode::Unsupported,
+                "Decrypting to a temp file is turned off, the entry can be opened or streamed",
+            ),
+            None,
+        ),
+        Commands::DecryptFile => {
+            match retrieve(owner.clone(), name.clone(), peer.uid, errors, warnings).uf_unwrap() {
+                Ok(d) => {
+                    let temp_p = d.data.0;
+                    let recs_p = d.data.1;
+                    d.warning.display();
+
+                    // This response can't say "no path", data without one gets recs' upload path
+                    let orig_p =
+                        original_path(catalog, &owner, &name, recs_p.clone()).unwrap_or(recs_p);
+
+                    stats.decrypted(fs::metadata(&temp_p).map(|m| m.len()).unwrap_or(0));
+
+                    // The temp path will be deleted after the ttl time
+                    schedule_cleanup(temp_p.clone());
+
+                    let data = DecryptResponseData {
+                        temp_p,
+                        orig_p,
+                        ttl: Duration::from_secs(config().ttl),
+                    };
+                    (response(ResponsePayload::File(data)), None)
+                }
+                Err(e) => {
+                    e.display(false);
+                    (
+                        internal_error("Error occurred while decrypting the data"),
+                        None,
+                    )
+                }
+            }
+        }
+        Commands::DecryptFileFd | Commands::StreamFile => {
+            // The temp file stays ours, the client only ever sees the descriptor or the bytes
+            let (uid, _) = get_id();
+            let (temp_p, recs_p) = match retrieve(
+                owner.clone(),
+                name.clone(),
+                uid.as_raw(),
+                errors.clone(),
+                warnings,
+            )
+            .uf_unwrap()
+            {
+                Ok(d) => {
+                    d.warning.display();
+                    d.data
+                }
+                Err(e) => {
+                    e.display(false);
+                    return (
+                        internal_error("Error occurred while decrypting the data"),
+                        None,
+                    );
+                }
+            };
 
+            match into_memfd(&temp_p, errors).uf_unwrap() {
+                Ok((file, size)) => {
+                    stats.decrypted(size);
                     match req.command {
-                        dusa_common::Commands::DecryptFile => {
-                            match retrieve(owner, name, _uid, errors.clone(), warnings.clone())
-                                .uf_unwrap()
-                            {
-                                Ok(d) => {
-                                    let data = d.data;
-                                    let temp_p = data.0.clone();
-                                    let temp_p_clone = data.0.clone();
-                                    let orig_p = data.1;
-                                    // The temp path will be deleted after the ttl time
-                                    let ttl = Duration::from_secs(TTL);
-                                    let decrypt_response = DecryptResponseData {
-                                        temp_p,
-                                        orig_p,
-                                        ttl,
-                                    };
-                                    d.warning.display();
-
-                                    let response = Message {
-                                        version: VERSION.to_owned(),
-                                        msg_type: MessageType::Response,
-                                        payload: decrypt_response,
-                                        error: None,
-                                    };
-
-                                    if let Err(err) =
-                                        send_message(&mut stream, &response, errors.clone())
-                                            .uf_unwrap()
-                                    {
-                                        err.display(false)
-                                    }
-
-                                    thread::spawn(move || {
-                                        thread::sleep(Duration::from_secs(TTL));
-                                        // taking back ownership
-                                        let (uid, gid) = get_id();
-                                        if let Err(err) = set_file_ownership(
-                                            &temp_p_clone.to_path_buf(),
-                                            uid,
-                                            gid,
-                                            ErrorArray::new_container(),
-                                        )
-                                        .uf_unwrap()
-                                        {
-                                            err.display(false);
-                                            return;
-                                        }
-                                        match del_file(
-                                            temp_p_clone,
-                                            ErrorArray::new_container(),
-                                            WarningArray::new_container(),
-                                        )
-                                        .uf_unwrap()
-                                        {
-                                            Ok(_) => notice("Cleaning up temp files"),
-                                            Err(e) => e.display(false),
-                                        }
-                                    });
-                                }
-                                Err(e) => {
-                                    let response = Message {
-                                        version: VERSION.to_owned(),
-                                        msg_type: MessageType::ErrorResponse,
-                                        payload: serde_json::json!({"Error":"Error occurred while decrypting the data"}),
-                                        error: None,
-                                    };
-                                    if let Err(err) =
-                                        send_message(&mut stream, &response, errors.clone())
-                                            .uf_unwrap()
-                                    {
-                                        err.display(false)
-                                    } else {
-                                        e.display(false)
-                                    }
-                                }
-                            }
-                        }
-                        dusa_common::Commands::RemoveFile => {
-                            match remove(owner, name, errors.clone(), warnings.clone()).uf_unwrap()
-                            {
-                                Ok(_) => {
-                                    let ack = Message {
-                                        version: new_message.version,
-                                        msg_type: MessageType::Response,
-                                        payload: serde_json::json!({"value":"Ok"}),
-                                        error: None,
-                                    };
-                                    if let Err(err) =
-                                        send_message(&mut stream, &ack, errors.clone()).uf_unwrap()
-                                    {
-                                        err.display(false)
-                                    }
-                                }
-                                Err(e) => {
-                                    let response = Message {
-                                        version: VERSION.to_owned(),
-                                        msg_type: MessageType::ErrorResponse,
-                                        payload: serde_json::json!({"Error":"Error occurred while decrypting the data"}),
-                                        error: None,
-                                    };
-                                    if let Err(err) =
-                                        send_message(&mut stream, &response, errors.clone())
-                                            .uf_unwrap()
-                                    {
-                                        err.display(false)
-                                    } else {
-                                        e.display(false)
-                                    }
-                                }
-                            }
-                        }
-                        dusa_common::Commands::PingFile => {
-                            let ack = Message {
-                                version: VERSION.to_owned(),
-                                msg_type: MessageType::Response,
-                                payload: serde_json::json!({"value":"Not implemented"}),
-                                error: None,
-                            };
-                            if let Err(err) =
-                                send_message(&mut stream, &ack, errors.clone()).uf_unwrap()
-                            {
-                                err.display(false)
-                            }
-                        }
+                        Commands::StreamFile => (
+                            response(ResponsePayload::StreamStart(size)),
+                            Some(Attachment::Stream(file)),
+                        ),
                         _ => {
-                            let error = DusaError {
-                                code: ErrorCode::InternalError,
-                                message: "Invalid command parsing".to_string(),
-                            };
-                            let response = Message {
-                                version: VERSION.to_owned(),
-                                msg_type: MessageType::ErrorResponse,
-                                payload: serde_json::json!({}),
-                                error: Some(error),
-                            };
-                            if let Err(err) =
-                                send_message(&mut stream, &response, errors.clone()).uf_unwrap()
-                            {
-                                err.display(false)
-                            }
+                            let orig_p = original_path(catalog, &owner, &name, recs_p);
+                            let data = FileDescriptorData { orig_p, size };
+                            (
+                                response(ResponsePayload::FileDescriptor(data)),
+                                Some(Attachment::Descriptor(file)),
+                            )
+                        }
+                    }
+                }
+                Err(e) => {
+                    e.display(false);
+                    (
+                        internal_error("Error occurred while decrypting the data"),
+                        None,
+                    )
+                }
+            }
+        }
+        Commands::RemoveFile => {
+            match remove(owner.clone(), name.clone(), errors.clone(), warnings).uf_unwrap() {
+                Ok(_) => {
+                    if let Ok(mut catalog) = catalog.lock() {
+                        if let Err(e) = catalog.forget(&owner, &name, errors).uf_unwrap() {
+                            e.display(false);
                         }
                     }
+                    (response(ResponsePayload::Removed), None)
+                }
+                Err(e) => {
+                    e.display(false);
+                    (
+                        internal_error("Error occurred while removing the data"),
+                        None,
+                    )
                 }
             }
+        }
+        Commands::PingFile => match ping(owner.clone(), name.clone(), errors).uf_unwrap() {
+            Ok(exists) => {
+                let entry: Option<CatalogEntry> = match exists {
+                    true => catalog
+                        .lock()
+                        .ok()
+                        .and_then(|c| c.get(&owner, &name).cloned()),
+                    false => None,
+                };
+                let stat = FileStat {
+                    exists,
+                    size: entry.as_ref().map(|e| e.size),
+                    created: entry.as_ref().map(|e| e.stored),
+                    orig_p: entry.and_then(|e| e.orig_p),
+                };
+                (response(ResponsePayload::Stat(stat)), None)
+            }
+            Err(e) => {
+                e.display(false);
+                (
+                    internal_error("Error occurred while looking up the entry"),
+                    None,
+                )
+            }
+        },
+        _ => (internal_error("Invalid command parsing"), None),
+    }
+}
 
-            // At the end of any transmission we expect an ack to be sent and received to ensure all data was captured
-
-            // Send an ACK message
-            send_message(
-                &mut stream,
-                &Message {
-                    version: VERSION.to_owned(),
-                    msg_type: MessageType::Acknowledge,
-                    payload: serde_json::json!({}),
-                    error: None,
-                },
-                errors.clone(),
+/// Sends the contents of a file as a series of chunks followed by an end of stream marker.
+fn stream_file(stream: &mut UnixStream, mut file: File, mut errors: ErrorArray) -> uf<u64> {
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut sent: u64 = 0;
+
+    loop {
+        let read = match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(d) => d,
+            Err(e) => {
+                errors.push(ErrorArrayItem::from(e));
+                return uf::new(Err(errors));
+            }
+        };
+
+        let chunk = response(ResponsePayload::Chunk(DataChunk::new(&buffer[..read])));
+        if let Err(err) = send_message(stream, &chunk, errors.clone()).uf_unwrap() {
+            return uf::new(Err(err));
+        }
+        sent += read as u64;
+        beat();
+    }
+
+    let end = response(ResponsePayload::EndOfStream(sent));
+    match send_message(stream, &end, errors).uf_unwrap() {
+        Ok(_) => uf::new(Ok(sent)),
+        Err(err) => uf::new(Err(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dusa_common::{BatchItem, Encoding};
+
+    fn text_request(command: Commands, data: &str) -> RequestRecsPlainText {
+        RequestRecsPlainText {
+            command,
+            data: data.to_owned(),
+            encoding: Encoding::Utf8,
+            uid: 1000,
+        }
+    }
+
+    fn error_code(message: &Message<ResponsePayload>) -> Option<ErrorCode> {
+        message.error.as_ref().map(|e| e.code)
+    }
+
+    #[test]
+    fn empty_text_is_refused_before_recs() {
+        for command in [Commands::EncryptRawText, Commands::DecryptRawText] {
+            let answered = handle_plain_text(
+                text_request(command, ""),
+                &Stats::default(),
+                ErrorArray::new_container(),
+                WarningArray::new_container(),
             );
+            assert_eq!(error_code(&answered), Some(ErrorCode::InvalidPayload));
         }
-        MessageType::Simple => {
-            // Send an ACK message
-            let ack = Message {
-                version: new_message.version,
-                msg_type: MessageType::Acknowledge,
-                payload: serde_json::json!({}),
-                error: None,
-            };
-            send_message(&mut stream, &ack, errors.clone());
+    }
+
+    #[test]
+    fn data_that_decodes_to_nothing_is_refused() {
+        for (encoding, data) in [(Encoding::Hex, "  "), (Encoding::Base64, " ")] {
+            let mut request = text_request(Commands::EncryptRawText, data);
+            request.encoding = encoding;
+            let answered = handle_plain_text(
+                request,
+                &Stats::default(),
+                ErrorArray::new_container(),
+                WarningArray::new_container(),
+            );
+            assert_eq!(error_code(&answered), Some(ErrorCode::InvalidPayload));
         }
-        _ => {
-            // Unknown type
-            let error = DusaError {
-                code: ErrorCode::UnknownMessageType,
-                message: "Unknown message type".to_string(),
-            };
-            let response = Message {
-                version: VERSION.to_owned(),
-                msg_type: MessageType::ErrorResponse,
-                payload: serde_json::json!({}),
-                error: Some(error),
-            };
-            send_message(&mut stream, &response, errors.clone());
+    }
+
+    /// What encrypting and then decrypting `plain` would do, recs left out.
+    fn round_trip(plain: &[u8]) -> (String, Vec<u8>) {
+        let (text, binary) = to_recs_text(plain.to_vec());
+        let sequence: String = recs_sequence("30312d2e", "abcdef", 1, binary);
+        let (cipher, key, chunks, binary) = parse_sequence(&sequence);
+        assert_eq!(
+            (cipher.as_str(), key.as_str(), chunks),
+            ("30312d2e", "abcdef", 1)
+        );
+        let back: Vec<u8> = from_recs_text(text.into_bytes(), binary).unwrap();
+        (sequence, back)
+    }
+
+    #[test]
+    fn binary_plaintext_round_trips_through_the_marker() {
+        let plain: [u8; 6] = [0x00, 0xff, 0x10, 0xc3, 0x28, 0x7f];
+        let (sequence, back) = round_trip(&plain);
+        assert!(sequence.ends_with("-b64"));
+        assert_eq!(back, plain);
+    }
+
+    #[test]
+    fn utf8_ending_like_the_marker_is_left_alone() {
+        let (sequence, back) = round_trip("hunter2-b64".as_bytes());
+        assert_eq!(sequence, "30312d2e-abcdef-1");
+        assert_eq!(back, b"hunter2-b64");
+
+        let (text, binary) = to_recs_text(b"-b64".to_vec());
+        assert_eq!((text.as_str(), binary), ("-b64", false));
+    }
+
+    #[test]
+    fn sequences_from_before_the_marker_are_text() {
+        let (_, _, chunks, binary) = parse_sequence("30312d2e-abcdef-3");
+        assert_eq!((chunks, binary), (3, false));
+    }
+
+    #[test]
+    fn sequences_with_no_chunks_are_refused() {
+        let answered = handle_plain_text(
+            text_request(Commands::DecryptRawText, "30312d2e-abcdef-0"),
+            &Stats::default(),
+            ErrorArray::new_container(),
+            WarningArray::new_container(),
+        );
+        assert_eq!(error_code(&answered), Some(ErrorCode::InvalidPayload));
+    }
+
+    #[test]
+    fn a_bad_item_only_fails_itself() {
+        let item = |command: Commands, data: &str| BatchItem {
+            command,
+            data: data.to_owned(),
+            encoding: Encoding::Utf8,
+        };
+        let batch = RequestRecsBatch {
+            items: vec![
+                item(Commands::EncryptRawText, ""),
+                item(Commands::List, "data"),
+                item(Commands::DecryptRawText, "not a sequence"),
+            ],
+            uid: 1000,
+        };
+
+        let answered = handle_batch(
+            batch,
+            &Stats::default(),
+            ErrorArray::new_container(),
+            WarningArray::new_container(),
+        );
+        let results: Vec<BatchResult> = match answered.payload {
+            ResponsePayload::Batch(d) => d,
+            other => panic!("expected a batch, got {:?}", other),
+        };
+        assert_eq!(results.len(), 3);
+        for result in results {
+            match result {
+                BatchResult::Error(e) => assert_eq!(e.code, ErrorCode::InvalidPayload),
+                BatchResult::Text(d) => panic!("expected an error, got {}", d),
+            }
         }
     }
 }
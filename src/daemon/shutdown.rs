@@ -0,0 +1,66 @@
+use std::{
+    fs,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use dusa_collection_utils::errors::{ErrorArray, ErrorArrayItem};
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use simple_pretty::{notice, warn};
+
+use crate::{metrics::remove_metrics_socket, temp::cleanup_pending, upload::wipe_uploads};
+
+/// Watches for SIGTERM and SIGINT. The returned flag is set when one arrives, and the accept
+/// loop is woken up by connecting to `socket_path` so it gets to check it.
+pub fn stop_on_signal(socket_path: PathBuf) -> Arc<AtomicBool> {
+    let stopping = Arc::new(AtomicBool::new(false));
+
+    let mut signals = match Signals::new([SIGTERM, SIGINT]) {
+        Ok(d) => d,
+        Err(e) => {
+            warn(&format!("Graceful shutdown is disabled: {}", e));
+            return stopping;
+        }
+    };
+
+    let flag = stopping.clone();
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if flag.swap(true, Ordering::SeqCst) {
+                notice("Already shutting down, waiting for running requests");
+                continue;
+            }
+            notice(&format!("Received signal {}, shutting down", signal));
+            // accept() only returns for a connection, so we make one
+            let _ = UnixStream::connect(&socket_path);
+        }
+    });
+
+    stopping
+}
+
+/// Removes everything a stopped daemon would otherwise leave behind: decrypted temp files still
+/// waiting for their ttl, unfinished uploads and the sockets. `socket_path` is `None` when the
+/// socket isn't ours to remove.
+pub fn clean_up(socket_path: Option<&Path>, mut errors: ErrorArray) {
+    cleanup_pending(errors.clone());
+
+    if let Err(e) = wipe_uploads(errors.clone()).uf_unwrap() {
+        e.display(false)
+    }
+
+    remove_metrics_socket();
+
+    if let Some(Err(e)) = socket_path.map(fs::remove_file) {
+        errors.push(ErrorArrayItem::from(e));
+        errors.display(false)
+    }
+}
@@ -0,0 +1,424 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+use dusa_common::{DaemonStatus, Message, MessageType, ResponsePayload, VERSION};
+
+/// Upper bounds in seconds of the request latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0, 30.0];
+
+/// Requests of one command and how long they took.
+#[derive(Default)]
+struct Latency {
+    /// Requests per bucket of [`LATENCY_BUCKETS`], not cumulative.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    seconds: f64,
+}
+
+/// Counters kept while the daemon runs, shared by the accept loop and the workers.
+pub struct Stats {
+    started: Instant,
+    recs_initialized: AtomicBool,
+    active: AtomicUsize,
+    accepted: AtomicU64,
+    turned_away: AtomicU64,
+    succeeded: AtomicU64,
+    failed: AtomicU64,
+    encrypted: AtomicU64,
+    decrypted: AtomicU64,
+    /// Error responses by [`dusa_common::ErrorCode`].
+    errors: Mutex<BTreeMap<String, u64>>,
+    /// Answered requests by command and outcome, rejected ones included.
+    requests: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    /// Requests by command that got as far as being handled.
+    latencies: Mutex<BTreeMap<&'static str, Latency>>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            started: Instant::now(),
+            recs_initialized: AtomicBool::new(false),
+            active: AtomicUsize::new(0),
+            accepted: AtomicU64::new(0),
+            turned_away: AtomicU64::new(0),
+            succeeded: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            encrypted: AtomicU64::new(0),
+            decrypted: AtomicU64::new(0),
+            errors: Mutex::new(BTreeMap::new()),
+            requests: Mutex::new(BTreeMap::new()),
+            latencies: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Stats {
+    pub fn recs_initialized(&self) {
+        self.recs_initialized.store(true, Ordering::Relaxed);
+    }
+
+    pub fn accepted(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn turned_away(&self) {
+        self.turned_away.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a connection as active until the returned guard is dropped.
+    pub fn connection(&self) -> ActiveConnection<'_> {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(self)
+    }
+
+    /// Counts plaintext bytes that went into recs.
+    pub fn encrypted(&self, bytes: u64) {
+        self.encrypted.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts plaintext bytes that came out of recs.
+    pub fn decrypted(&self, bytes: u64) {
+        self.decrypted.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts a response by whether it reports success or an error, errors by their code. Every
+    /// request that is read gets a response, so this counts them whether or not they made it past
+    /// the checks.
+    ///
+    /// # Arguments
+    /// * `command` - What was asked for, `None` if the request couldn't be read.
+    /// * `response` - What it was answered with.
+    pub fn answered(&self, command: Option<&'static str>, response: &Message<ResponsePayload>) {
+        let failed: bool = response.msg_type == MessageType::ErrorResponse;
+        if let Ok(mut requests) = self.requests.lock() {
+            let outcome: &str = if failed { "failure" } else { "success" };
+            *requests
+                .entry((command.unwrap_or("unknown"), outcome))
+                .or_insert(0) += 1;
+        }
+
+        if !failed {
+            self.succeeded.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.failed.fetch_add(1, Ordering::Relaxed);
+        if let (Some(error), Ok(mut errors)) = (&response.error, self.errors.lock()) {
+            *errors.entry(format!("{:?}", error.code)).or_insert(0) += 1;
+        }
+    }
+
+    /// Times a request of `command` until the returned guard is dropped.
+    pub fn time(&self, command: &'static str) -> RequestTimer<'_> {
+        RequestTimer {
+            stats: self,
+            command,
+            started: Instant::now(),
+        }
+    }
+
+    fn timed(&self, command: &'static str, seconds: f64) {
+        if let Ok(mut latencies) = self.latencies.lock() {
+            let latency: &mut Latency = latencies.entry(command).or_default();
+            if let Some(bucket) = LATENCY_BUCKETS.iter().position(|le| seconds <= *le) {
+                latency.buckets[bucket] += 1;
+            }
+            latency.count += 1;
+            latency.seconds += seconds;
+        }
+    }
+
+    /// The status as it is right now.
+    ///
+    /// # Arguments
+    /// * `pending_temp_files` - How many decrypted temp files wait for their ttl.
+    /// * `problems` - Whatever the caller found wrong with the daemon.
+    pub fn status(&self, pending_temp_files: usize, mut problems: Vec<String>) -> DaemonStatus {
+        let recs_initialized: bool = self.recs_initialized.load(Ordering::Relaxed);
+        if !recs_initialized {
+            problems.push(String::from("recs is not initialized"));
+        }
+
+        let counters: BTreeMap<String, u64> = [
+            ("connections_accepted", &self.accepted),
+            ("connections_turned_away", &self.turned_away),
+            ("requests_succeeded", &self.succeeded),
+            ("requests_failed", &self.failed),
+            ("bytes_encrypted", &self.encrypted),
+            ("bytes_decrypted", &self.decrypted),
+        ]
+        .into_iter()
+        .map(|(name, counter)| (name.to_owned(), counter.load(Ordering::Relaxed)))
+        .collect();
+
+        DaemonStatus {
+            version: VERSION.to_owned(),
+            uptime: self.started.elapsed().as_secs(),
+            recs_initialized,
+            active_connections: self.active.load(Ordering::Relaxed),
+            pending_temp_files,
+            counters,
+            problems,
+        }
+    }
+}
+
+impl Stats {
+    /// The counters in the Prometheus text exposition format.
+    pub fn prometheus(&self, pending_temp_files: usize) -> String {
+        let mut text = String::new();
+
+        let gauges: [(&str, &str, u64); 3] = [
+            (
+                "uptime_seconds",
+                "Seconds since dusad started.",
+                self.started.elapsed().as_secs(),
+            ),
+            (
+                "active_connections",
+                "Connections being handled right now.",
+                self.active.load(Ordering::Relaxed) as u64,
+            ),
+            (
+                "pending_temp_files",
+                "Decrypted temp files waiting for their ttl to pass.",
+                pending_temp_files as u64,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            metric_header(&mut text, name, help, "gauge");
+            let _ = writeln!(text, "dusad_{} {}", name, value);
+        }
+
+        let counters: [(&str, &str, &AtomicU64); 4] = [
+            (
+                "connections_accepted_total",
+                "Connections accepted.",
+                &self.accepted,
+            ),
+            (
+                "connections_turned_away_total",
+                "Connections answered with Busy instead of being handled.",
+                &self.turned_away,
+            ),
+            (
+                "bytes_encrypted_total",
+                "Plaintext bytes encrypted.",
+                &self.encrypted,
+            ),
+            (
+                "bytes_decrypted_total",
+                "Plaintext bytes decrypted.",
+                &self.decrypted,
+            ),
+        ];
+        for (name, help, value) in counters {
+            metric_header(&mut text, name, help, "counter");
+            let _ = writeln!(text, "dusad_{} {}", name, value.load(Ordering::Relaxed));
+        }
+
+        metric_header(
+            &mut text,
+            "errors_total",
+            "Error responses sent, by error code.",
+            "counter",
+        );
+        if let Ok(errors) = self.errors.lock() {
+            for (code, count) in errors.iter() {
+                let _ = writeln!(
+                    text,
+                    "dusad_errors_total{{code=\"{}\"}} {}",
+                    label(code),
+                    count
+                );
+            }
+        }
+
+        metric_header(
+            &mut text,
+            "requests_total",
+            "Requests answered, by command and outcome. Rejected ones count too.",
+            "counter",
+        );
+        if let Ok(requests) = self.requests.lock() {
+            for ((command, outcome), count) in requests.iter() {
+                let _ = writeln!(
+                    text,
+                    "dusad_requests_total{{command=\"{}\",outcome=\"{}\"}} {}",
+                    label(command),
+                    outcome,
+                    count
+                );
+            }
+        }
+
+        let latencies = match self.latencies.lock() {
+            Ok(d) => d,
+            Err(_) => return text,
+        };
+        metric_header(
+            &mut text,
+            "request_duration_seconds",
+            "Time spent handling a request, by command.",
+            "histogram",
+        );
+        for (command, latency) in latencies.iter() {
+            let command: String = label(command);
+            let mut cumulative: u64 = 0;
+            for (le, count) in LATENCY_BUCKETS.iter().zip(latency.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    text,
+                    "dusad_request_duration_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}",
+                    command, le, cumulative
+                );
+            }
+            let _ = writeln!(
+                text,
+                "dusad_request_duration_seconds_bucket{{command=\"{}\",le=\"+Inf\"}} {}",
+                command, latency.count
+            );
+            let _ = writeln!(
+                text,
+                "dusad_request_duration_seconds_sum{{command=\"{}\"}} {}",
+                command, latency.seconds
+            );
+            let _ = writeln!(
+                text,
+                "dusad_request_duration_seconds_count{{command=\"{}\"}} {}",
+                command, latency.count
+            );
+        }
+        text
+    }
+}
+
+/// Escapes a label value the way the text format wants it.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn metric_header(text: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(text, "# HELP dusad_{} {}", name, help);
+    let _ = writeln!(text, "# TYPE dusad_{} {}", name, kind);
+}
+
+/// A request being timed, see [`Stats::time`].
+pub struct RequestTimer<'a> {
+    stats: &'a Stats,
+    command: &'static str,
+    started: Instant,
+}
+
+impl Drop for RequestTimer<'_> {
+    fn drop(&mut self) {
+        let seconds: f64 = self.started.elapsed().as_secs_f64();
+        self.stats.timed(self.command, seconds);
+    }
+}
+
+/// A connection counted as active, see [`Stats::connection`].
+pub struct ActiveConnection<'a>(&'a Stats);
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use dusa_common::ErrorCode;
+
+    use super::*;
+    use crate::response_err::{ack, error_response, response};
+
+    #[test]
+    fn the_prometheus_text_is_exact() {
+        let stats = Stats {
+            started: Instant::now() - Duration::from_secs(42),
+            ..Stats::default()
+        };
+        let _active = stats.connection();
+        stats.accepted();
+        stats.accepted();
+        stats.turned_away();
+        stats.encrypted(100);
+        stats.decrypted(40);
+
+        stats.answered(Some("EncryptFile"), &response(ResponsePayload::Empty));
+        stats.answered(Some("EncryptFile"), &response(ResponsePayload::Empty));
+        stats.answered(
+            Some("EncryptFile"),
+            &error_response(ErrorCode::InvalidPermissions, "no"),
+        );
+        stats.answered(None, &error_response(ErrorCode::MalformedFrame, "no"));
+        stats.answered(Some("odd\"cmd\\\n"), &ack());
+
+        stats.timed("EncryptFile", 0.5);
+        stats.timed("EncryptFile", 0.25);
+        stats.timed("EncryptFile", 60.0);
+
+        let expected: &str = "\
+# HELP dusad_uptime_seconds Seconds since dusad started.
+# TYPE dusad_uptime_seconds gauge
+dusad_uptime_seconds 42
+# HELP dusad_active_connections Connections being handled right now.
+# TYPE dusad_active_connections gauge
+dusad_active_connections 1
+# HELP dusad_pending_temp_files Decrypted temp files waiting for their ttl to pass.
+# TYPE dusad_pending_temp_files gauge
+dusad_pending_temp_files 3
+# HELP dusad_connections_accepted_total Connections accepted.
+# TYPE dusad_connections_accepted_total counter
+dusad_connections_accepted_total 2
+# HELP dusad_connections_turned_away_total Connections answered with Busy instead of being handled.
+# TYPE dusad_connections_turned_away_total counter
+dusad_connections_turned_away_total 1
+# HELP dusad_bytes_encrypted_total Plaintext bytes encrypted.
+# TYPE dusad_bytes_encrypted_total counter
+dusad_bytes_encrypted_total 100
+# HELP dusad_bytes_decrypted_total Plaintext bytes decrypted.
+# TYPE dusad_bytes_decrypted_total counter
+dusad_bytes_decrypted_total 40
+# HELP dusad_errors_total Error responses sent, by error code.
+# TYPE dusad_errors_total counter
+dusad_errors_total{code=\"InvalidPermissions\"} 1
+dusad_errors_total{code=\"MalformedFrame\"} 1
+# HELP dusad_requests_total Requests answered, by command and outcome. Rejected ones count too.
+# TYPE dusad_requests_total counter
+dusad_requests_total{command=\"EncryptFile\",outcome=\"failure\"} 1
+dusad_requests_total{command=\"EncryptFile\",outcome=\"success\"} 2
+dusad_requests_total{command=\"odd\\\"cmd\\\\\\n\",outcome=\"success\"} 1
+dusad_requests_total{command=\"unknown\",outcome=\"failure\"} 1
+# HELP dusad_request_duration_seconds Time spent handling a request, by command.
+# TYPE dusad_request_duration_seconds histogram
+dusad_request_duration_seconds_bucket{command=\"EncryptFile\",le=\"0.005\"} 0
+dusad_request_duration_seconds_bucket{command=\"EncryptFile\",le=\"0.01\"} 0
+dusad_request_duration_seconds_bucket{command=\"EncryptFile\",le=\"0.025\"} 0
+dusad_request_duration_seconds_bucket{command=\"EncryptFile\",le=\"0.05\"} 0
+dusad_request_duration_seconds_bucket{command=\"EncryptFile\",le=\"0.1\"} 0
+dusad_request_duration_seconds_bucket{command=\"EncryptFile\",le=\"0.25\"} 1
+dusad_request_duration_seconds_bucket{command=\"EncryptFile\",le=\"0.5\"} 2
+dusad_request_duration_seconds_bucket{command=\"EncryptFile\",le=\"1\"} 2
+dusad_request_duration_seconds_bucket{command=\"EncryptFile\",le=\"5\"} 2
+dusad_request_duration_seconds_bucket{command=\"EncryptFile\",le=\"30\"} 2
+dusad_request_duration_seconds_bucket{command=\"EncryptFile\",le=\"+Inf\"} 3
+dusad_request_duration_seconds_sum{command=\"EncryptFile\"} 60.75
+dusad_request_duration_seconds_count{command=\"EncryptFile\"} 3
+";
+        assert_eq!(stats.prometheus(3), expected);
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    env,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            io::{FromRawFd, RawFd},
+            net::{SocketAddr, UnixDatagram, UnixListener},
+        },
+    },
+    process,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use dusa_common::config::config;
+use nix::{
+    fcntl::{fcntl, FcntlArg, FdFlag},
+    sys::socket::{getsockname, getsockopt, sockopt::AcceptConn, SockAddr},
+};
+use simple_pretty::warn;
+
+use crate::pool::Heartbeat;
+
+/// The first descriptor systemd passes with socket activation, see sd_listen_fds(3).
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Takes over the listening socket systemd passed us with socket activation.
+///
+/// Has to be called before any threads are started, the activation variables are removed from
+/// the environment so children don't think the socket is meant for them.
+///
+/// # Returns
+/// The listener, `None` if we weren't socket activated or what we got isn't a listening unix
+/// socket.
+pub fn listen_fds() -> Option<UnixListener> {
+    let pid: Option<u32> = env::var("LISTEN_PID").ok().and_then(|d| d.parse().ok());
+    let fds: Option<i32> = env::var("LISTEN_FDS").ok().and_then(|d| d.parse().ok());
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let fds: i32 = match (pid, fds) {
+        (Some(pid), Some(fds)) if pid == process::id() && fds > 0 => fds,
+        _ => return None,
+    };
+    if fds > 1 {
+        warn(&format!(
+            "systemd passed {} sockets, only the first one is used",
+            fds
+        ));
+    }
+
+    let fd: RawFd = LISTEN_FDS_START;
+    let listening: bool = matches!(getsockopt(fd, AcceptConn), Ok(true));
+    if !listening || !matches!(getsockname(fd), Ok(SockAddr::Unix(_))) {
+        warn("The socket systemd passed isn't a listening unix socket, ignoring it");
+        return None;
+    }
+    // systemd leaves it inheritable, our children have no business with it
+    let _ = fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC));
+
+    // systemd handed this descriptor to us and nothing else in the process uses it
+    Some(unsafe { UnixListener::from_raw_fd(fd) })
+}
+
+/// Tells systemd about our state, like `READY=1` or `STOPPING=1`, see sd_notify(3). Does
+/// nothing when we weren't started with a notify socket.
+pub fn notify(state: &str) {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(d) => d,
+        None => return,
+    };
+
+    let sent = UnixDatagram::unbound().and_then(|socket| {
+        match path.as_bytes().strip_prefix(b"@") {
+            // A leading @ stands for the abstract namespace
+            Some(name) => SocketAddr::from_abstract_name(name)
+                .and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr)),
+            None => socket.send_to(state.as_bytes(), &path),
+        }
+    });
+
+    if let Err(e) = sent {
+        warn(&format!("Couldn't notify systemd of {}: {}", state, e));
+    }
+}
+
+/// Sends watchdog keepalives at half the interval the unit's `WatchdogSec` asks for, as long as
+/// the heartbeat shows the accept loop and every worker getting somewhere. A hung daemon stops
+/// sending them and systemd restarts it. Does nothing when the watchdog isn't enabled for us.
+pub fn feed_watchdog(heartbeat: Arc<Heartbeat>) {
+    let usec: u64 = match env::var("WATCHDOG_USEC").ok().and_then(|d| d.parse().ok()) {
+        Some(d) if d > 0 => d,
+        _ => return,
+    };
+    let pid: Option<u32> = env::var("WATCHDOG_PID").ok().and_then(|d| d.parse().ok());
+    if pid.is_some_and(|pid| pid != process::id()) {
+        return;
+    }
+
+    let interval = Duration::from_micros(usec / 2);
+    thread::spawn(move || loop {
+        if heartbeat.alive(interval, config().worker_deadline()) {
+            notify("WATCHDOG=1");
+        } else {
+            warn("The daemon looks hung, not feeding the watchdog");
+        }
+        thread::sleep(interval);
+    });
+}
@@ -0,0 +1,423 @@
+use std::{
+    collections::BTreeMap,
+    ffi::CString,
+    fs::{self, File, OpenOptions},
+    io::{self, Seek, SeekFrom, Write},
+    os::unix::{fs::MetadataExt, io::FromRawFd},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use dusa_collection_utils::{
+    errors::{ErrorArray, ErrorArrayItem, UnifiedResult as uf, WarningArray},
+    functions::{create_hash, del_file},
+    types::PathType,
+};
+use dusa_common::{config::config, get_id, set_file_ownership};
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use recs::remove;
+use simple_pretty::{notice, warn};
+
+/// Name of the journal of issued temp files in the data directory. Recs decrypts files to
+/// `/tmp/dusa_{name}{secs}` whatever the prog name is, the journal is the only way to tell
+/// which of them are ours.
+const JOURNAL_FILE: &str = "temp_journal.json";
+
+/// Where recs decrypts files to, and what it starts their names with.
+const TEMP_DIR: &str = "/tmp";
+const TEMP_PREFIX: &str = "dusa_";
+
+/// The file recs writes raw text to before encrypting it, whatever the prog name is.
+pub const RAW_STAGING_FILE: &str = "/tmp/dummy.recs";
+
+/// The owner and name recs stores raw text under while it encrypts it.
+pub const RAW_STAGING_ENTRY: (&str, &str) = ("owner", "temp");
+
+/// Decrypted temp files still waiting for their ttl to pass, with the unix time they expire at.
+/// Every change is written to the journal so a restarted daemon knows about them.
+static PENDING: Mutex<BTreeMap<PathBuf, u64>> = Mutex::new(BTreeMap::new());
+
+/// Takes back a decrypted temp file from the client, overwrites it and deletes it.
+///
+/// # Arguments
+/// * `temp_p` - The temporary file recs decrypted into.
+/// * `errors` - An array of errors to be populated if any occur.
+///
+/// # Returns
+/// A unified result indicating if the file is gone.
+pub fn secure_delete(temp_p: &PathType, mut errors: ErrorArray) -> uf<()> {
+    // taking back ownership
+    let (uid, gid) = get_id();
+    if let Err(err) =
+        set_file_ownership(&temp_p.to_path_buf(), uid, gid, errors.clone()).uf_unwrap()
+    {
+        return uf::new(Err(err));
+    }
+
+    if let Err(e) = overwrite(temp_p) {
+        errors.push(ErrorArrayItem::from(e));
+        return uf::new(Err(errors));
+    }
+
+    match del_file(temp_p.clone(), errors, WarningArray::new_container()).uf_unwrap() {
+        Ok(_) => uf::new(Ok(())),
+        Err(e) => uf::new(Err(e)),
+    }
+}
+
+/// Zeroes the contents of a file before it's unlinked.
+fn overwrite(path: &PathType) -> io::Result<()> {
+    let mut file: File = OpenOptions::new().write(true).open(path)?;
+    let mut remaining: u64 = file.metadata()?.len();
+    let zeros = [0u8; 8192];
+
+    while remaining > 0 {
+        let len = remaining.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..len])?;
+        remaining -= len as u64;
+    }
+    file.sync_all()
+}
+
+/// Deletes a decrypted temp file once its ttl has passed.
+pub fn schedule_cleanup(temp_p: PathType) {
+    let expires: u64 = now() + config().ttl;
+    if let Ok(mut pending) = PENDING.lock() {
+        pending.insert(temp_p.to_path_buf(), expires);
+        save_journal(&journal_path(), &pending);
+    }
+    delete_at(temp_p.to_path_buf(), expires);
+}
+
+/// How many decrypted temp files are waiting for their ttl to pass.
+pub fn pending_temp_files() -> usize {
+    PENDING.lock().map(|pending| pending.len()).unwrap_or(0)
+}
+
+/// Deletes every decrypted temp file right away instead of waiting for its ttl, for when the
+/// daemon won't be around to do it later.
+pub fn cleanup_pending(errors: ErrorArray) {
+    let pending: BTreeMap<PathBuf, u64> = match PENDING.lock() {
+        Ok(mut d) => {
+            let taken = std::mem::take(&mut *d);
+            save_journal(&journal_path(), &d);
+            taken
+        }
+        Err(_) => return,
+    };
+
+    for path in pending.into_keys() {
+        notice(&format!("Deleting temp file {} early", path.display()));
+        if let Err(e) = secure_delete(&PathType::PathBuf(path), errors.clone()).uf_unwrap() {
+            e.display(false)
+        }
+    }
+}
+
+/// Goes through the journal after a restart. Temp files still within their ttl get their
+/// cleanup scheduled again, expired ones are securely deleted.
+///
+/// A `/tmp/dusa_*` file the journal doesn't know about, because the daemon died before it could
+/// record it, is securely deleted as well once it's older than the ttl. Only if the dusa user
+/// owns it, recs uses the same names for every prog name and another dusad on the machine runs
+/// as another user.
+///
+/// # Arguments
+/// * `errors` - An array of errors to be populated if any occur.
+///
+/// # Returns
+/// A unified result indicating if the sweep ran.
+pub fn sweep_temp_files(errors: ErrorArray) -> uf<()> {
+    let journal: BTreeMap<PathBuf, u64> = load_journal(&journal_path());
+
+    let mut pending = match PENDING.lock() {
+        Ok(d) => d,
+        Err(_) => return uf::new(Ok(())),
+    };
+    let time: u64 = now();
+
+    for (path, expires) in journal {
+        // Whatever the journal had and isn't on disk anymore is forgotten here
+        if !path.is_file() {
+            continue;
+        }
+        if expires > time {
+            pending.insert(path.clone(), expires);
+            delete_at(path, expires);
+            continue;
+        }
+        notice(&format!("Deleting expired temp file {}", path.display()));
+        if let Err(e) = secure_delete(&PathType::PathBuf(path), errors.clone()).uf_unwrap() {
+            e.display(false)
+        }
+    }
+
+    save_journal(&journal_path(), &pending);
+
+    let (uid, _) = get_id();
+    let cutoff: SystemTime = SystemTime::now() - Duration::from_secs(config().ttl);
+    for path in stray_temp_files(Path::new(TEMP_DIR), &pending, uid.as_raw(), cutoff) {
+        notice(&format!("Deleting unrecorded temp file {}", path.display()));
+        if let Err(e) = secure_delete(&PathType::PathBuf(path), errors.clone()).uf_unwrap() {
+            e.display(false)
+        }
+    }
+    uf::new(Ok(()))
+}
+
+/// The recs temp files in `dir` that aren't `known`, are owned by `uid` and were last written
+/// before `cutoff`.
+fn stray_temp_files(
+    dir: &Path,
+    known: &BTreeMap<PathBuf, u64>,
+    uid: u32,
+    cutoff: SystemTime,
+) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(dir) {
+        Ok(d) => d,
+        Err(e) => {
+            warn(&format!(
+                "Couldn't look for temp files in {}: {}",
+                dir.display(),
+                e
+            ));
+            return Vec::new();
+        }
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(TEMP_PREFIX))
+        .filter(|entry| !known.contains_key(&entry.path()))
+        // Not followed, a link planted in /tmp must not get us to delete what it points to
+        .filter(|entry| {
+            entry.metadata().is_ok_and(|meta| {
+                meta.is_file()
+                    && meta.uid() == uid
+                    && meta.modified().is_ok_and(|modified| modified < cutoff)
+            })
+        })
+        .map(|entry| entry.path())
+        .collect()
+}
+
+/// Waits until `expires` and deletes `path`, unless something else deleted it first.
+fn delete_at(path: PathBuf, expires: u64) {
+    thread::spawn(move || {
+        thread::sleep(Duration::from_secs(expires.saturating_sub(now())));
+        // A shutdown may have deleted it already
+        if !take_pending(&path) {
+            return;
+        }
+        match secure_delete(&PathType::PathBuf(path), ErrorArray::new_container()).uf_unwrap() {
+            Ok(_) => notice("Cleaning up temp files"),
+            Err(e) => e.display(false),
+        }
+    });
+}
+
+/// Removes `path` from the pending temp files, `false` if it wasn't pending anymore.
+fn take_pending(path: &Path) -> bool {
+    match PENDING.lock() {
+        Ok(mut pending) => {
+            let taken: bool = pending.remove(path).is_some();
+            save_journal(&journal_path(), &pending);
+            taken
+        }
+        Err(_) => true,
+    }
+}
+
+fn journal_path() -> PathBuf {
+    config().data_dir().join(JOURNAL_FILE)
+}
+
+/// Reads the journal, an unreadable one is treated as empty so everything gets swept.
+fn load_journal(path: &Path) -> BTreeMap<PathBuf, u64> {
+    let data: String = match fs::read_to_string(path) {
+        Ok(d) => d,
+        Err(_) => return BTreeMap::new(),
+    };
+    match serde_json::from_str(&data) {
+        Ok(d) => d,
+        Err(e) => {
+            warn(&format!("Ignoring the unreadable temp file journal: {}", e));
+            BTreeMap::new()
+        }
+    }
+}
+
+/// Writes the journal, the previous one stays in place if that fails.
+fn save_journal(path: &Path, pending: &BTreeMap<PathBuf, u64>) {
+    let temp_path: PathBuf = path.with_extension("json.tmp");
+    let written = serde_json::to_vec_pretty(pending)
+        .map_err(io::Error::from)
+        .and_then(|data| fs::write(&temp_path, data))
+        .and_then(|_| fs::rename(&temp_path, path));
+
+    if let Err(e) = written {
+        warn(&format!("Couldn't write the temp file journal: {}", e));
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Moves a decrypted temp file into an anonymous memory backed file and deletes the original,
+/// so the plaintext no longer has a path anyone could open.
+///
+/// # Returns
+/// The memfd, rewound to the start, and the size of the plaintext.
+pub fn into_memfd(temp_p: &PathType, mut errors: ErrorArray) -> uf<(File, u64)> {
+    let name = CString::new("dusa").unwrap_or_default();
+    let mut memfd: File = match memfd_create(&name, MemFdCreateFlag::MFD_CLOEXEC) {
+        // memfd_create just returned this descriptor, nothing else owns it
+        Ok(fd) => unsafe { File::from_raw_fd(fd) },
+        Err(e) => {
+            errors.push(ErrorArrayItem::from(e));
+            return uf::new(Err(errors));
+        }
+    };
+
+    let copied: io::Result<u64> = File::open(temp_p).and_then(|mut plain| {
+        let size = io::copy(&mut plain, &mut memfd)?;
+        memfd.seek(SeekFrom::Start(0))?;
+        Ok(size)
+    });
+
+    // The temp file goes away whether or not the copy worked
+    if let Err(err) = secure_delete(temp_p, errors.clone()).uf_unwrap() {
+        return uf::new(Err(err));
+    }
+
+    match copied {
+        Ok(size) => uf::new(Ok((memfd, size))),
+        Err(e) => {
+            errors.push(ErrorArrayItem::from(e));
+            uf::new(Err(errors))
+        }
+    }
+}
+
+/// Removes whatever an earlier raw text encryption left of its staging: the plaintext in
+/// [`RAW_STAGING_FILE`], and the entry and secret file recs keeps under [`RAW_STAGING_ENTRY`].
+/// Recs only forgets the entry when it finishes, and refuses to stage over a secret file that is
+/// still there.
+pub fn reset_raw_staging() {
+    let staging = PathType::Str(RAW_STAGING_FILE.into());
+    if staging.exists() {
+        if let Err(e) = secure_delete(&staging, ErrorArray::new_container()).uf_unwrap() {
+            e.display(false)
+        }
+    }
+
+    let (owner, name) = RAW_STAGING_ENTRY;
+    let data_dir: PathBuf = config().data_dir();
+    let meta: PathBuf = data_dir
+        .join("meta")
+        .join(format!("{}-{}.meta", owner, name));
+    if meta.exists() {
+        let forgotten = remove(
+            owner.to_string(),
+            name.to_string(),
+            ErrorArray::new_container(),
+            WarningArray::new_container(),
+        );
+        if let Err(e) = forgotten.uf_unwrap() {
+            e.display(false)
+        }
+    }
+
+    // Whatever forget couldn't get to, recs names the secret after the hash of the staging path
+    let hash: String = create_hash(RAW_STAGING_FILE.to_string());
+    let secret_id: String = hash
+        .bytes()
+        .take(10)
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let secret: PathBuf = data_dir.join("secrets").join(format!("{}.recs", secret_id));
+    for path in [meta, secret] {
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                warn(&format!("Couldn't remove {}: {}", path.display(), e))
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir: PathBuf =
+            std::env::temp_dir().join(format!("dusa-temp-{}-{}", std::process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Creates `name` in `dir`, last written `age` ago.
+    fn temp_file(dir: &Path, name: &str, age: Duration) -> PathBuf {
+        let path: PathBuf = dir.join(name);
+        let file: File = File::create(&path).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+        path
+    }
+
+    #[test]
+    fn the_journal_survives_a_round_trip() {
+        let dir: PathBuf = temp_dir("journal");
+        let path: PathBuf = dir.join(JOURNAL_FILE);
+        let pending: BTreeMap<PathBuf, u64> = BTreeMap::from([
+            (PathBuf::from("/tmp/dusa_a1700000000"), 1_700_000_030),
+            (PathBuf::from("/tmp/dusa_b1700000005"), 1_700_000_035),
+        ]);
+
+        save_journal(&path, &pending);
+        assert_eq!(load_journal(&path), pending);
+        assert!(!path.with_extension("json.tmp").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_missing_or_unreadable_journal_is_empty() {
+        let dir: PathBuf = temp_dir("unreadable");
+        let path: PathBuf = dir.join(JOURNAL_FILE);
+        assert!(load_journal(&path).is_empty());
+
+        fs::write(&path, "{ not json").unwrap();
+        assert!(load_journal(&path).is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn only_old_unrecorded_temp_files_of_ours_are_stray() {
+        let dir: PathBuf = temp_dir("stray");
+        let old = Duration::from_secs(120);
+        let stray: PathBuf = temp_file(&dir, "dusa_lost1700000000", old);
+        let known: PathBuf = temp_file(&dir, "dusa_known1700000000", old);
+        temp_file(&dir, "dusa_fresh1700000000", Duration::ZERO);
+        temp_file(&dir, "other_file", old);
+        fs::create_dir(dir.join("dusa_dir")).unwrap();
+        std::os::unix::fs::symlink(dir.join("other_file"), dir.join("dusa_link")).unwrap();
+
+        let uid: u32 = nix::unistd::getuid().as_raw();
+        let journal: BTreeMap<PathBuf, u64> = BTreeMap::from([(known, u64::MAX)]);
+        let cutoff: SystemTime = SystemTime::now() - Duration::from_secs(60);
+        assert_eq!(stray_temp_files(&dir, &journal, uid, cutoff), vec![stray]);
+        // Another user's files are never ours to delete
+        assert!(stray_temp_files(&dir, &journal, uid + 1, cutoff).is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    os::unix::{
+        fs::{OpenOptionsExt, PermissionsExt},
+        net::UnixStream,
+    },
+    path::PathBuf,
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use dusa_collection_utils::{
+    errors::{ErrorArray, ErrorArrayItem, Errors, UnifiedResult as uf},
+    types::PathType,
+};
+use dusa_common::{
+    config::config,
+    prefix::{receive_message, GeneralMessage},
+    MessageType, RequestRecsUpload, UploadPayload,
+};
+use simple_pretty::notice;
+
+use crate::{pool::beat, temp::secure_delete};
+
+/// Where uploaded plaintext is kept until recs has encrypted it. Only the dusa user can enter it.
+pub fn upload_dir() -> PathBuf {
+    config().data_dir().join("uploads")
+}
+
+/// Creates the upload directory and wipes anything a previous run left behind in it.
+pub fn prepare_upload_dir(mut errors: ErrorArray) -> uf<()> {
+    let dir: PathBuf = upload_dir();
+    if let Err(e) = fs::create_dir_all(&dir)
+        .and_then(|_| fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)))
+    {
+        errors.push(ErrorArrayItem::from(e));
+        return uf::new(Err(errors));
+    }
+
+    wipe_uploads(errors)
+}
+
+/// Secure-deletes everything in the upload directory.
+pub fn wipe_uploads(mut errors: ErrorArray) -> uf<()> {
+    let dir: PathBuf = upload_dir();
+    let leftovers: Vec<PathBuf> = match fs::read_dir(&dir) {
+        Ok(d) => d.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+        Err(e) => {
+            errors.push(ErrorArrayItem::from(e));
+            return uf::new(Err(errors));
+        }
+    };
+
+    for path in leftovers {
+        notice(&format!("Wiping unfinished upload {}", path.display()));
+        if let Err(err) = secure_delete(&PathType::PathBuf(path), errors.clone()).uf_unwrap() {
+            return uf::new(Err(err));
+        }
+    }
+    uf::new(Ok(()))
+}
+
+/// Why an upload wasn't received.
+pub enum UploadError {
+    /// The client sent more than the `max_upload_size` bytes given here, the rest of its data
+    /// is left unread.
+    TooLarge(u64),
+    /// Reading the data or writing it down failed.
+    Failed(ErrorArray),
+}
+
+/// Receives the data of an accepted upload into a new file in the upload directory.
+///
+/// # Arguments
+/// * `stream` - The connection the chunks arrive on.
+/// * `req` - The upload request the data belongs to.
+/// * `errors` - An array of errors to be populated if any occur.
+///
+/// # Returns
+/// The path of the received file and its size. If receiving fails, or the data grows past
+/// `max_upload_size`, the partial file is deleted before returning.
+pub fn receive_upload(
+    stream: &mut UnixStream,
+    req: &RequestRecsUpload,
+    errors: ErrorArray,
+) -> Result<(PathType, u64), UploadError> {
+    let nanos: u128 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let upload_p =
+        PathType::PathBuf(upload_dir().join(format!("{}-{}.upload", process::id(), nanos)));
+
+    let file: File = match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&upload_p)
+    {
+        Ok(d) => d,
+        Err(e) => {
+            let mut errors = errors;
+            errors.push(ErrorArrayItem::from(e));
+            return Err(UploadError::Failed(errors));
+        }
+    };
+
+    let limit: Option<u64> = config().upload_limit();
+    match write_chunks(stream, file, limit, errors.clone()) {
+        Ok(size) => {
+            notice(&format!(
+                "Received {} bytes for {}/{}",
+                size, req.owner, req.name
+            ));
+            Ok((upload_p, size))
+        }
+        Err(err) => {
+            if let Err(e) = secure_delete(&upload_p, errors).uf_unwrap() {
+                e.display(false);
+            }
+            Err(err)
+        }
+    }
+}
+
+/// Writes chunks into `file` until the client signals the end of the data.
+fn write_chunks(
+    stream: &mut UnixStream,
+    mut file: File,
+    limit: Option<u64>,
+    mut errors: ErrorArray,
+) -> Result<u64, UploadError> {
+    let mut received: u64 = 0;
+
+    loop {
+        beat();
+        let message: GeneralMessage = match receive_message(stream, errors.clone()).uf_unwrap() {
+            Ok(d) => d,
+            Err(e) => return Err(UploadError::Failed(e)),
+        };
+
+        let payload: UploadPayload = match message.msg_type {
+            MessageType::Request => match message.payload_as() {
+                Ok(d) => d,
+                Err(e) => {
+                    errors.push(ErrorArrayItem::from(e));
+                    return Err(UploadError::Failed(errors));
+                }
+            },
+            other => {
+                errors.push(ErrorArrayItem::new(
+                    Errors::GeneralError,
+                    format!("Expected upload data but got a {} message", other),
+                ));
+                return Err(UploadError::Failed(errors));
+            }
+        };
+
+        match payload {
+            UploadPayload::Chunk(chunk) => {
+                let bytes: Vec<u8> = match chunk.bytes() {
+                    Ok(d) => d,
+                    Err(e) => {
+                        errors.push(ErrorArrayItem::new(Errors::InvalidType, e.to_string()));
+                        return Err(UploadError::Failed(errors));
+                    }
+                };
+                received += bytes.len() as u64;
+                if let Some(limit) = limit.filter(|limit| received > *limit) {
+                    return Err(UploadError::TooLarge(limit));
+                }
+                if let Err(e) = file.write_all(&bytes) {
+                    errors.push(ErrorArrayItem::from(e));
+                    return Err(UploadError::Failed(errors));
+                }
+            }
+            UploadPayload::EndOfStream(sent) => {
+                if sent != received {
+                    errors.push(ErrorArrayItem::new(
+                        Errors::GeneralError,
+                        format!("Client sent {} bytes but {} arrived", sent, received),
+                    ));
+                    return Err(UploadError::Failed(errors));
+                }
+                break;
+            }
+        }
+    }
+
+    match file.sync_all() {
+        Ok(_) => Ok(received),
+        Err(e) => {
+            errors.push(ErrorArrayItem::from(e));
+            Err(UploadError::Failed(errors))
+        }
+    }
+}
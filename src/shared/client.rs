@@ -0,0 +1,864 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+    net::Shutdown,
+    os::unix::net::UnixStream,
+    path::Path,
+};
+
+use dusa_collection_utils::{
+    errors::{ErrorArray, ErrorArrayItem, Errors, WarningArray},
+    types::PathType,
+};
+use nix::unistd::geteuid;
+use serde::Serialize;
+
+use crate::{
+    config::config,
+    prefix::{receive_fd, receive_message, send_message, GeneralMessage},
+    BatchItem, BatchResult, Commands, DaemonStatus, DataChunk, DecryptResponseData, DusaError,
+    Encoding, EntryInfo, ErrorCode, Feature, FileStat, Hello, Message, MessageType, Negotiated,
+    RequestPayload, RequestRecsBatch, RequestRecsPlainText, RequestRecsQuery, RequestRecsSimple,
+    RequestRecsUpload, ResponsePayload, UploadPayload, CHUNK_SIZE, PROTOCOL_VERSION, SOCKET_PATH,
+};
+
+/// Errors returned by [`DusaClient`].
+#[derive(Debug)]
+pub enum ClientError {
+    /// The daemon isn't running or the caller can't open its socket.
+    Connection(std::io::Error),
+    /// Reading local files or talking over the socket failed.
+    Io(ErrorArray),
+    /// Writing received data to its destination failed.
+    Output(std::io::Error),
+    /// The daemon refused or failed the request.
+    Server(DusaError),
+    /// The daemon answered with something we didn't expect.
+    UnexpectedResponse(String),
+}
+
+/// Result type returned by [`DusaClient`].
+pub type Result<T> = std::result::Result<T, ClientError>;
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Connection(e) => write!(
+                f,
+                "The server is not running or You do not have access to this application: {}",
+                e
+            ),
+            ClientError::Io(errors) => {
+                let items = errors.0.read().map(|e| e.clone()).unwrap_or_default();
+                let messages: Vec<String> = items.iter().map(|e| e.err_mesg.clone()).collect();
+                write!(f, "{}", messages.join(", "))
+            }
+            ClientError::Output(e) => write!(f, "Failed to write the received data: {}", e),
+            ClientError::Server(e) => write!(f, "{}", e),
+            ClientError::UnexpectedResponse(e) => {
+                write!(f, "Server responded in an unexpected way: {}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl ClientError {
+    /// Converts the error into the `ErrorArray` the rest of the dusa tooling reports with.
+    pub fn into_errors(self, mut errors: ErrorArray) -> ErrorArray {
+        match self {
+            ClientError::Io(e) => errors.append(e),
+            ClientError::Connection(e) => errors.push(ErrorArrayItem::new(
+                Errors::ConnectionError,
+                ClientError::Connection(e).to_string(),
+            )),
+            ClientError::Output(_) => {
+                errors.push(ErrorArrayItem::new(Errors::InputOutput, self.to_string()))
+            }
+            ClientError::Server(e) => {
+                let kind = match e.code {
+                    ErrorCode::InvalidPermissions => Errors::PermissionDenied,
+                    _ => Errors::GeneralError,
+                };
+                errors.push(ErrorArrayItem::new(kind, e.to_string()))
+            }
+            ClientError::UnexpectedResponse(_) => {
+                errors.push(ErrorArrayItem::new(Errors::GeneralError, self.to_string()))
+            }
+        }
+        errors
+    }
+}
+
+/// A decrypted file received from the daemon as an open descriptor.
+#[derive(Debug)]
+pub struct DecryptedFile {
+    /// Anonymous file holding the plaintext, positioned at the start.
+    pub file: File,
+    /// Where the client that stored it says the file was, `None` if it wasn't stored from a
+    /// file. Unchecked, never write to it.
+    pub orig_p: Option<PathType>,
+    /// Size of the plaintext in bytes.
+    pub size: u64,
+}
+
+/// A client for talking to dusad over its unix socket.
+///
+/// Every call opens its own connection, the daemon handles exactly one request per connection.
+/// Each connection starts with a [`Hello`], requests the daemon doesn't support fail with
+/// [`ErrorCode::Unsupported`] before they are sent. Many small requests are cheaper over a
+/// [`Session`].
+#[derive(Debug, Clone)]
+pub struct DusaClient {
+    socket_path: PathType,
+    uid: u32,
+}
+
+impl DusaClient {
+    /// Creates a client for the default socket path.
+    pub fn new() -> Result<Self> {
+        let socket_path: PathType = match SOCKET_PATH(
+            false,
+            ErrorArray::new_container(),
+            WarningArray::new_container(),
+        )
+        .uf_unwrap()
+        {
+            Ok(d) => d.data,
+            Err(e) => return Err(ClientError::Io(e)),
+        };
+        Ok(Self::with_socket(socket_path))
+    }
+
+    /// Creates a client for a specific socket path.
+    pub fn with_socket(socket_path: PathType) -> Self {
+        DusaClient {
+            socket_path,
+            uid: u32::from(geteuid()),
+        }
+    }
+
+    /// Encrypts a string, returning the recs sequence that can be given to [`Self::decrypt_text`].
+    pub fn encrypt_text(&self, data: &str) -> Result<String> {
+        self.encrypt_encoded(data, Encoding::Utf8)
+    }
+
+    /// Decrypts a recs sequence created by [`Self::encrypt_text`]. Plaintext that isn't utf8
+    /// fails with [`ErrorCode::InvalidEncoding`].
+    pub fn decrypt_text(&self, data: &str) -> Result<String> {
+        self.decrypt_encoded(data, Encoding::Utf8)
+    }
+
+    /// Encrypts arbitrary bytes, returning the recs sequence that can be given to
+    /// [`Self::decrypt_bytes`].
+    pub fn encrypt_bytes(&self, data: &[u8]) -> Result<String> {
+        let encoded: String = Encoding::Base64
+            .encode(data.to_vec())
+            .map_err(ClientError::UnexpectedResponse)?;
+        self.encrypt_encoded(&encoded, Encoding::Base64)
+    }
+
+    /// Decrypts a recs sequence back into the bytes it was created from.
+    pub fn decrypt_bytes(&self, data: &str) -> Result<Vec<u8>> {
+        let encoded: String = self.decrypt_encoded(data, Encoding::Base64)?;
+        Encoding::Base64
+            .decode(&encoded)
+            .map_err(ClientError::UnexpectedResponse)
+    }
+
+    /// Encrypts the bytes `data` carries in `encoding`.
+    pub fn encrypt_encoded(&self, data: &str, encoding: Encoding) -> Result<String> {
+        let request = RequestRecsPlainText {
+            command: Commands::EncryptRawText,
+            data: data.to_owned(),
+            encoding,
+            uid: self.uid,
+        };
+        match self.request(RequestPayload::PlainText(request))? {
+            ResponsePayload::Text(d) => Ok(d),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Decrypts a recs sequence, returning the plaintext in `encoding`.
+    pub fn decrypt_encoded(&self, data: &str, encoding: Encoding) -> Result<String> {
+        let request = RequestRecsPlainText {
+            command: Commands::DecryptRawText,
+            data: data.to_owned(),
+            encoding,
+            uid: self.uid,
+        };
+        match self.request(RequestPayload::PlainText(request))? {
+            ResponsePayload::Text(d) => Ok(d),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Runs many text operations in one request.
+    ///
+    /// # Returns
+    /// A result for every item in the order they were given, items fail on their own.
+    pub fn batch(&self, items: Vec<BatchItem>) -> Result<Vec<BatchResult>> {
+        let request = RequestRecsBatch {
+            items,
+            uid: self.uid,
+        };
+        match self.request(RequestPayload::Batch(request))? {
+            ResponsePayload::Batch(d) => Ok(d),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Encrypts and stores the file at `path` as `owner`/`name`.
+    ///
+    /// The file is read here and streamed to the daemon, its ownership and permissions are left
+    /// alone.
+    pub fn store_file(&self, path: &Path, owner: &str, name: &str) -> Result<String> {
+        let opened = path
+            .canonicalize()
+            .and_then(|p| File::open(&p).map(|f| (p, f)));
+        let (file_path, mut file) = match opened {
+            Ok(d) => d,
+            Err(e) => {
+                return Err(ClientError::Io(ErrorArray::new(vec![
+                    ErrorArrayItem::from(e),
+                ])))
+            }
+        };
+
+        self.store_reader(&mut file, owner, name, Some(PathType::PathBuf(file_path)))
+    }
+
+    /// Encrypts and stores everything read from `reader` as `owner`/`name`.
+    ///
+    /// `orig_p` is remembered as the place the data came from, it is where decrypted files are
+    /// written back to by default. Data without a path can only be read back as a stream or a
+    /// descriptor.
+    pub fn store_reader<R: Read>(
+        &self,
+        reader: &mut R,
+        owner: &str,
+        name: &str,
+        orig_p: Option<PathType>,
+    ) -> Result<String> {
+        let request = RequestRecsUpload {
+            owner: owner.to_owned(),
+            name: name.to_owned(),
+            orig_p,
+            uid: self.uid,
+        };
+        let mut stream: UnixStream = self.send(RequestPayload::Upload(request))?;
+
+        // The daemon acks the request before any data is sent, or refuses it outright
+        let result = match Self::accepted(&mut stream) {
+            Ok(_) => match Self::write_stream(&mut stream, reader) {
+                Ok(_) => match Self::receive(&mut stream) {
+                    Ok(ResponsePayload::Stored(d)) => Ok(d),
+                    Ok(other) => Err(unexpected(other)),
+                    Err(e) => Err(e),
+                },
+                // The daemon stops reading an upload it won't take, its reply says why
+                Err(e) => match Self::receive(&mut stream) {
+                    Err(ClientError::Server(refused)) => Err(ClientError::Server(refused)),
+                    _ => Err(e),
+                },
+            },
+            Err(e) => Err(e),
+        };
+        Self::finish(&mut stream);
+        result
+    }
+
+    /// Waits for the daemon to accept an upload.
+    fn accepted(stream: &mut UnixStream) -> Result<()> {
+        let response: GeneralMessage = receive_message(stream, ErrorArray::new_container())
+            .uf_unwrap()
+            .map_err(ClientError::Io)?;
+
+        match response.msg_type {
+            MessageType::Acknowledge => Ok(()),
+            _ => Self::parse(response).and_then(|payload| Err(unexpected(payload))),
+        }
+    }
+
+    /// Sends everything read from `reader` as upload chunks followed by the end of the stream.
+    fn write_stream<R: Read>(stream: &mut UnixStream, reader: &mut R) -> Result<u64> {
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        let mut sent: u64 = 0;
+
+        loop {
+            let read = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(d) => d,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    return Err(ClientError::Io(ErrorArray::new(vec![
+                        ErrorArrayItem::from(e),
+                    ])))
+                }
+            };
+            Self::upload(
+                stream,
+                UploadPayload::Chunk(DataChunk::new(&buffer[..read])),
+            )?;
+            sent += read as u64;
+        }
+
+        Self::upload(stream, UploadPayload::EndOfStream(sent))?;
+        Ok(sent)
+    }
+
+    /// Sends a single message of an upload.
+    fn upload(stream: &mut UnixStream, payload: UploadPayload) -> Result<()> {
+        let msg = Message {
+            version: PROTOCOL_VERSION.to_string(),
+            msg_type: MessageType::Request,
+            payload,
+            error: None,
+            id: None,
+        };
+        send_message(stream, &msg, ErrorArray::new_container())
+            .uf_unwrap()
+            .map_err(ClientError::Io)
+    }
+
+    /// Decrypts `owner`/`name` into a temporary file owned by the caller.
+    ///
+    /// The temporary file is deleted by the daemon once `ttl` has passed. Daemons only do this
+    /// with `legacy_temp_decrypt` turned on, otherwise it fails with [`ErrorCode::Unsupported`],
+    /// [`Self::open_file`] does the same without a path.
+    pub fn retrieve_file(&self, owner: &str, name: &str) -> Result<DecryptResponseData> {
+        let request = RequestRecsSimple {
+            command: Commands::DecryptFile,
+            owner: owner.to_owned(),
+            name: name.to_owned(),
+            uid: self.uid,
+        };
+        match self.request(RequestPayload::Simple(request))? {
+            ResponsePayload::File(d) => Ok(d),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Decrypts `owner`/`name` into an anonymous file. The plaintext never has a path, the daemon
+    /// passes the open descriptor over the socket.
+    pub fn open_file(&self, owner: &str, name: &str) -> Result<DecryptedFile> {
+        let request = RequestRecsSimple {
+            command: Commands::DecryptFileFd,
+            owner: owner.to_owned(),
+            name: name.to_owned(),
+            uid: self.uid,
+        };
+        let mut stream: UnixStream = self.send(RequestPayload::Simple(request))?;
+
+        let result = match Self::receive(&mut stream) {
+            Ok(ResponsePayload::FileDescriptor(data)) => {
+                match receive_fd(&mut stream, ErrorArray::new_container()).uf_unwrap() {
+                    Ok(file) => Ok(DecryptedFile {
+                        file,
+                        orig_p: data.orig_p,
+                        size: data.size,
+                    }),
+                    Err(e) => Err(ClientError::Io(e)),
+                }
+            }
+            Ok(other) => Err(unexpected(other)),
+            Err(e) => Err(e),
+        };
+        Self::finish(&mut stream);
+        result
+    }
+
+    /// Decrypts `owner`/`name` and writes the plaintext to `out` as it arrives, without it ever
+    /// being written to a file on the client side.
+    ///
+    /// # Returns
+    /// The number of bytes written.
+    pub fn stream_file<W: Write>(&self, owner: &str, name: &str, out: &mut W) -> Result<u64> {
+        let request = RequestRecsSimple {
+            command: Commands::StreamFile,
+            owner: owner.to_owned(),
+            name: name.to_owned(),
+            uid: self.uid,
+        };
+        let mut stream: UnixStream = self.send(RequestPayload::Simple(request))?;
+
+        let result = match Self::receive(&mut stream) {
+            Ok(ResponsePayload::StreamStart(_)) => Self::read_stream(&mut stream, out),
+            Ok(other) => Err(unexpected(other)),
+            Err(e) => Err(e),
+        };
+        Self::finish(&mut stream);
+        result
+    }
+
+    /// Copies chunks into `out` until the end of the stream.
+    fn read_stream<W: Write>(stream: &mut UnixStream, out: &mut W) -> Result<u64> {
+        let mut written: u64 = 0;
+        loop {
+            match Self::receive(stream)? {
+                ResponsePayload::Chunk(chunk) => {
+                    let bytes = chunk
+                        .bytes()
+                        .map_err(|e| ClientError::UnexpectedResponse(e.to_string()))?;
+                    out.write_all(&bytes).map_err(ClientError::Output)?;
+                    written += bytes.len() as u64;
+                }
+                ResponsePayload::EndOfStream(_) => break,
+                other => return Err(unexpected(other)),
+            }
+        }
+
+        match out.flush() {
+            Ok(_) => Ok(written),
+            Err(e) => Err(ClientError::Io(ErrorArray::new(vec![
+                ErrorArrayItem::from(e),
+            ]))),
+        }
+    }
+
+    /// Removes `owner`/`name` from the store.
+    pub fn remove(&self, owner: &str, name: &str) -> Result<()> {
+        let request = RequestRecsSimple {
+            command: Commands::RemoveFile,
+            owner: owner.to_owned(),
+            name: name.to_owned(),
+            uid: self.uid,
+        };
+        match self.request(RequestPayload::Simple(request))? {
+            ResponsePayload::Removed => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Looks up `owner`/`name` without decrypting it.
+    pub fn stat(&self, owner: &str, name: &str) -> Result<FileStat> {
+        let request = RequestRecsSimple {
+            command: Commands::PingFile,
+            owner: owner.to_owned(),
+            name: name.to_owned(),
+            uid: self.uid,
+        };
+        match self.request(RequestPayload::Simple(request))? {
+            ResponsePayload::Stat(d) => Ok(d),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Lists the stored entries the caller may read, optionally only those of `owner`.
+    pub fn list(&self, owner: Option<&str>) -> Result<Vec<EntryInfo>> {
+        let request = RequestRecsQuery {
+            command: Commands::List,
+            owner: owner.map(str::to_owned),
+            uid: self.uid,
+        };
+        match self.request(RequestPayload::Query(request))? {
+            ResponsePayload::Entries(d) => Ok(d),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Asks the daemon how it's doing.
+    pub fn status(&self) -> Result<DaemonStatus> {
+        let mut stream: UnixStream = self.open(MessageType::Status, (), Vec::new())?;
+        let response = Self::receive(&mut stream);
+        Self::finish(&mut stream);
+        match response? {
+            ResponsePayload::Status(d) => Ok(d),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Opens a session, a connection that carries any number of requests until it's closed.
+    pub fn session(&self) -> Result<Session> {
+        let mut stream: UnixStream = self.connect()?;
+
+        let negotiated: Negotiated = Self::hello(&mut stream, Hello::ours())?;
+        if !negotiated.supports(Feature::Sessions) {
+            return Err(unsupported(Feature::Sessions));
+        }
+
+        Ok(Session {
+            stream,
+            uid: self.uid,
+            next_id: 1,
+            open: true,
+        })
+    }
+
+    /// Connects to the daemon. A daemon that stops answering or reading runs into the configured
+    /// read and write deadlines instead of hanging the caller.
+    fn connect(&self) -> Result<UnixStream> {
+        let stream: UnixStream =
+            UnixStream::connect(&self.socket_path).map_err(ClientError::Connection)?;
+        stream
+            .set_read_timeout(config().read_deadline())
+            .and_then(|_| stream.set_write_timeout(config().write_deadline()))
+            .map_err(ClientError::Connection)?;
+        Ok(stream)
+    }
+
+    /// Sends a request and returns the payload of the daemon's response.
+    fn request(&self, payload: RequestPayload) -> Result<ResponsePayload> {
+        let mut stream: UnixStream = self.send(payload)?;
+        let response = Self::receive(&mut stream);
+        Self::finish(&mut stream);
+        response
+    }
+
+    /// Opens a connection and sends a request over it.
+    fn send(&self, payload: RequestPayload) -> Result<UnixStream> {
+        let needs: Vec<Feature> = payload.needs();
+        self.open(MessageType::Request, payload, needs)
+    }
+
+    /// Opens a connection and sends a message of any type over it, once the daemon agreed to
+    /// what it `needs`.
+    fn open<T: Serialize>(
+        &self,
+        msg_type: MessageType,
+        payload: T,
+        needs: Vec<Feature>,
+    ) -> Result<UnixStream> {
+        let mut stream: UnixStream = self.connect()?;
+
+        let hello: Hello = Hello::ours().without(Feature::Sessions);
+        let negotiated: Negotiated = Self::hello(&mut stream, hello)?;
+        if let Some(feature) = needs.into_iter().find(|f| !negotiated.supports(*f)) {
+            return Err(unsupported(feature));
+        }
+
+        let msg = Message {
+            version: PROTOCOL_VERSION.to_string(),
+            msg_type,
+            payload,
+            error: None,
+            id: None,
+        };
+
+        match send_message(&mut stream, &msg, ErrorArray::new_container()).uf_unwrap() {
+            Ok(_) => Ok(stream),
+            // The daemon hangs up on requests it refuses to read, its reply says why
+            Err(e) => match Self::receive(&mut stream) {
+                Err(ClientError::Server(refused)) => Err(ClientError::Server(refused)),
+                _ => Err(ClientError::Io(e)),
+            },
+        }
+    }
+
+    /// Tells the daemon what we support and learns what the connection will use.
+    fn hello(stream: &mut UnixStream, hello: Hello) -> Result<Negotiated> {
+        let msg = Message {
+            version: PROTOCOL_VERSION.to_string(),
+            msg_type: MessageType::Hello,
+            payload: hello,
+            error: None,
+            id: None,
+        };
+        send_message(stream, &msg, ErrorArray::new_container())
+            .uf_unwrap()
+            .map_err(ClientError::Io)?;
+
+        match Self::receive(stream)? {
+            ResponsePayload::Negotiated(d) => Ok(d),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Reads the daemon's response, turning error responses into [`ClientError::Server`].
+    fn receive(stream: &mut UnixStream) -> Result<ResponsePayload> {
+        let response: GeneralMessage = receive_message(stream, ErrorArray::new_container())
+            .uf_unwrap()
+            .map_err(ClientError::Io)?;
+        Self::parse(response)
+    }
+
+    /// Extracts the payload of a response message.
+    fn parse(response: GeneralMessage) -> Result<ResponsePayload> {
+        match response.msg_type {
+            MessageType::Response => response
+                .payload_as()
+                .map_err(|e| ClientError::UnexpectedResponse(e.to_string())),
+            MessageType::ErrorResponse => Err(ClientError::Server(match response.error {
+                Some(e) => e,
+                None => DusaError {
+                    code: ErrorCode::InternalError,
+                    message: response.payload.to_string(),
+                },
+            })),
+            other => Err(ClientError::UnexpectedResponse(other.to_string())),
+        }
+    }
+
+    /// The server closes every request with an ACK, it carries no data.
+    fn finish(stream: &mut UnixStream) {
+        let _ = receive_message(stream, ErrorArray::new_container());
+    }
+}
+
+/// Many requests over one connection, see [`DusaClient::session`].
+///
+/// Every request carries an id the daemon sends its response back with, responses arrive in the
+/// order the requests were sent. Uploads and downloads still take a connection of their own. The
+/// session ends with [`Session::close`], or when it's dropped. The daemon closes a session that
+/// waits longer than its `session_idle_timeout` for a request, later requests on it fail with a
+/// connection error. So do the requests after one that failed halfway or got a response that
+/// wasn't its own, what's left of it on the connection can't be told apart from the next.
+#[derive(Debug)]
+pub struct Session {
+    stream: UnixStream,
+    uid: u32,
+    next_id: u64,
+    open: bool,
+}
+
+impl Session {
+    /// Encrypts a string, see [`DusaClient::encrypt_text`].
+    pub fn encrypt_text(&mut self, data: &str) -> Result<String> {
+        self.encrypt_encoded(data, Encoding::Utf8)
+    }
+
+    /// Decrypts a recs sequence, see [`DusaClient::decrypt_text`].
+    pub fn decrypt_text(&mut self, data: &str) -> Result<String> {
+        self.decrypt_encoded(data, Encoding::Utf8)
+    }
+
+    /// Encrypts the bytes `data` carries in `encoding`.
+    pub fn encrypt_encoded(&mut self, data: &str, encoding: Encoding) -> Result<String> {
+        let request = RequestRecsPlainText {
+            command: Commands::EncryptRawText,
+            data: data.to_owned(),
+            encoding,
+            uid: self.uid,
+        };
+        match self.request(MessageType::Request, RequestPayload::PlainText(request))? {
+            ResponsePayload::Text(d) => Ok(d),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Decrypts a recs sequence, returning the plaintext in `encoding`.
+    pub fn decrypt_encoded(&mut self, data: &str, encoding: Encoding) -> Result<String> {
+        let request = RequestRecsPlainText {
+            command: Commands::DecryptRawText,
+            data: data.to_owned(),
+            encoding,
+            uid: self.uid,
+        };
+        match self.request(MessageType::Request, RequestPayload::PlainText(request))? {
+            ResponsePayload::Text(d) => Ok(d),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Runs many text operations in one request, see [`DusaClient::batch`].
+    pub fn batch(&mut self, items: Vec<BatchItem>) -> Result<Vec<BatchResult>> {
+        let request = RequestRecsBatch {
+            items,
+            uid: self.uid,
+        };
+        match self.request(MessageType::Request, RequestPayload::Batch(request))? {
+            ResponsePayload::Batch(d) => Ok(d),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Decrypts `owner`/`name` into a temporary file, see [`DusaClient::retrieve_file`].
+    pub fn retrieve_file(&mut self, owner: &str, name: &str) -> Result<DecryptResponseData> {
+        let request = RequestRecsSimple {
+            command: Commands::DecryptFile,
+            owner: owner.to_owned(),
+            name: name.to_owned(),
+            uid: self.uid,
+        };
+        match self.request(MessageType::Request, RequestPayload::Simple(request))? {
+            ResponsePayload::File(d) => Ok(d),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Removes `owner`/`name` from the store.
+    pub fn remove(&mut self, owner: &str, name: &str) -> Result<()> {
+        let request = RequestRecsSimple {
+            command: Commands::RemoveFile,
+            owner: owner.to_owned(),
+            name: name.to_owned(),
+            uid: self.uid,
+        };
+        match self.request(MessageType::Request, RequestPayload::Simple(request))? {
+            ResponsePayload::Removed => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Looks up `owner`/`name` without decrypting it.
+    pub fn stat(&mut self, owner: &str, name: &str) -> Result<FileStat> {
+        let request = RequestRecsSimple {
+            command: Commands::PingFile,
+            owner: owner.to_owned(),
+            name: name.to_owned(),
+            uid: self.uid,
+        };
+        match self.request(MessageType::Request, RequestPayload::Simple(request))? {
+            ResponsePayload::Stat(d) => Ok(d),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Lists the stored entries the caller may read, optionally only those of `owner`.
+    pub fn list(&mut self, owner: Option<&str>) -> Result<Vec<EntryInfo>> {
+        let request = RequestRecsQuery {
+            command: Commands::List,
+            owner: owner.map(str::to_owned),
+            uid: self.uid,
+        };
+        match self.request(MessageType::Request, RequestPayload::Query(request))? {
+            ResponsePayload::Entries(d) => Ok(d),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Asks the daemon how it's doing.
+    pub fn status(&mut self) -> Result<DaemonStatus> {
+        match self.request(MessageType::Status, ())? {
+            ResponsePayload::Status(d) => Ok(d),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Ends the session once the daemon acknowledged it.
+    pub fn close(mut self) -> Result<()> {
+        self.open = false;
+        self.send(MessageType::Close, (), None)?;
+        DusaClient::finish(&mut self.stream);
+        Ok(())
+    }
+
+    /// Sends a message with the next id and returns the payload of the response to it.
+    fn request<T: Serialize>(
+        &mut self,
+        msg_type: MessageType,
+        payload: T,
+    ) -> Result<ResponsePayload> {
+        if !self.open {
+            return Err(ClientError::Connection(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "the session is closed",
+            )));
+        }
+        let id: u64 = self.next_id;
+        self.next_id += 1;
+        if let Err(e) = self.send(msg_type, payload, Some(id)) {
+            self.poison();
+            return Err(e);
+        }
+
+        let response: GeneralMessage =
+            match receive_message(&mut self.stream, ErrorArray::new_container()).uf_unwrap() {
+                Ok(d) => d,
+                Err(e) => {
+                    self.poison();
+                    return Err(ClientError::Io(e));
+                }
+            };
+        if response.id != Some(id) {
+            // Its ack, or whatever else the daemon sends for it, would be read as ours next
+            self.poison();
+            return Err(ClientError::UnexpectedResponse(format!(
+                "a response to request {:?} instead of {}",
+                response.id, id
+            )));
+        }
+        let result = DusaClient::parse(response);
+        DusaClient::finish(&mut self.stream);
+        result
+    }
+
+    /// Gives up on a session whose requests and responses may be out of step, the daemon sees
+    /// it hang up.
+    fn poison(&mut self) {
+        self.open = false;
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    fn send<T: Serialize>(
+        &mut self,
+        msg_type: MessageType,
+        payload: T,
+        id: Option<u64>,
+    ) -> Result<()> {
+        let msg = Message {
+            version: PROTOCOL_VERSION.to_string(),
+            msg_type,
+            payload,
+            error: None,
+            id,
+        };
+        send_message(&mut self.stream, &msg, ErrorArray::new_container())
+            .uf_unwrap()
+            .map_err(ClientError::Io)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if self.open && self.send(MessageType::Close, (), None).is_ok() {
+            DusaClient::finish(&mut self.stream);
+        }
+    }
+}
+
+/// Builds the error for a feature the daemon didn't agree to.
+fn unsupported(feature: Feature) -> ClientError {
+    ClientError::Server(DusaError {
+        code: ErrorCode::Unsupported,
+        message: format!("The server doesn't support {}", feature),
+    })
+}
+
+/// Builds the error for a response variant the caller didn't ask for.
+fn unexpected(payload: ResponsePayload) -> ClientError {
+    ClientError::UnexpectedResponse(format!("{:?}", payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prefix::read_frame;
+
+    #[test]
+    fn a_response_to_another_request_ends_the_session() {
+        let (ours, mut daemon) = UnixStream::pair().unwrap();
+        let answering = std::thread::spawn(move || {
+            read_frame(&mut daemon, 1 << 20).unwrap();
+            let stray = Message {
+                version: PROTOCOL_VERSION.to_string(),
+                msg_type: MessageType::Response,
+                payload: ResponsePayload::Entries(Vec::new()),
+                error: None,
+                id: Some(7),
+            };
+            send_message(&mut daemon, &stray, ErrorArray::new_container())
+                .uf_unwrap()
+                .unwrap();
+        });
+
+        let mut session = Session {
+            stream: ours,
+            uid: 1000,
+            next_id: 1,
+            open: true,
+        };
+        assert!(matches!(
+            session.list(None),
+            Err(ClientError::UnexpectedResponse(_))
+        ));
+        answering.join().unwrap();
+        // The ack of request 7 must not be taken for the answer to the next one
+        assert!(matches!(
+            session.list(None),
+            Err(ClientError::Connection(_))
+        ));
+    }
+}
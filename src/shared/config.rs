@@ -0,0 +1,483 @@
+use std::{
+    env,
+    ffi::OsString,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+    time::Duration,
+};
+
+use dusa_collection_utils::errors::{ErrorArray, ErrorArrayItem, Errors, UnifiedResult as uf};
+use serde::{Deserialize, Serialize};
+
+use crate::{IO_TIMEOUT, MAX_FRAME_SIZE, TTL};
+
+/// Default location of the configuration file, `DUSA_CONFIG` points somewhere else.
+pub const CONFIG_PATH: &str = "/etc/dusa/dusa.toml";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Settings shared by dusad and its clients.
+///
+/// Values are taken from the defaults, then the config file, then `DUSA_*` environment variables
+/// and finally whatever the binaries accept on their command line.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The unix socket dusad listens on.
+    pub socket_path: PathBuf,
+    /// Seconds a decrypted temp file lives before it's deleted.
+    pub ttl: u64,
+    /// Whether entries may still be decrypted to a file in /tmp handed over to the caller, as
+    /// clients from before descriptor passing do. The plaintext has a path there until the ttl
+    /// runs out.
+    pub legacy_temp_decrypt: bool,
+    /// The user dusad runs as and that owns the stored data.
+    pub user: String,
+    /// The group dusad runs as, its members can reach the socket.
+    pub group: String,
+    /// Name recs works under, stored data lives in `/var/<prog_name>`.
+    pub prog_name: String,
+    /// The access policy dusad enforces.
+    pub policy_path: PathBuf,
+    /// Largest message in bytes that is read from a peer, larger ones are refused unread.
+    pub max_frame_size: usize,
+    /// Largest upload in bytes dusad stores, 0 means no limit.
+    pub max_upload_size: u64,
+    /// Seconds to wait for a peer to send something before giving up on it, 0 waits forever.
+    pub read_timeout: u64,
+    /// Seconds to wait for a peer to take what we send before giving up on it, 0 waits forever.
+    pub write_timeout: u64,
+    /// How many connections dusad handles at the same time.
+    pub workers: usize,
+    /// How many connections may wait for a worker before new ones are turned away.
+    pub queue_size: usize,
+    /// How many connections a single uid may have open at once, 0 means no limit.
+    pub max_connections_per_uid: usize,
+    /// Seconds a session may wait for its next request before it's closed, it holds a worker
+    /// all the while. 0 leaves it to `read_timeout`.
+    pub session_idle_timeout: u64,
+    /// Seconds a worker may spend on one step of a connection, a request or a chunk of a
+    /// transfer, before the watchdog takes dusad for hung. 0 never does.
+    pub worker_timeout: u64,
+    /// Seconds dusad waits for running requests to finish when it's asked to stop.
+    pub shutdown_timeout: u64,
+    /// A unix socket serving metrics over http, none are served if it's not set.
+    pub metrics_socket: Option<PathBuf>,
+    /// A port on 127.0.0.1 serving metrics over http, none are served if it's not set.
+    pub metrics_port: Option<u16>,
+    /// The JSON lines file every request is recorded in.
+    pub audit_log: PathBuf,
+    /// Size in bytes the audit log may grow to before it's rotated, 0 never rotates it.
+    pub audit_max_size: u64,
+    /// How many rotated audit logs are kept next to the current one.
+    pub audit_keep: usize,
+    /// Whether every audit record carries the hash of the one before it.
+    pub audit_hash_chain: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            socket_path: PathBuf::from("/var/run/dusa/dusa.sock"),
+            ttl: TTL,
+            legacy_temp_decrypt: false,
+            user: String::from("dusa"),
+            group: String::from("dusa"),
+            prog_name: String::from("dusa"),
+            policy_path: PathBuf::from("/etc/dusa/policy.toml"),
+            max_frame_size: MAX_FRAME_SIZE,
+            max_upload_size: 1024 * 1024 * 1024,
+            read_timeout: IO_TIMEOUT,
+            write_timeout: IO_TIMEOUT,
+            workers: 16,
+            queue_size: 64,
+            max_connections_per_uid: 8,
+            session_idle_timeout: 10,
+            worker_timeout: 300,
+            shutdown_timeout: 30,
+            metrics_socket: None,
+            metrics_port: None,
+            audit_log: PathBuf::from("/var/log/dusa/audit.jsonl"),
+            audit_max_size: 10 * 1024 * 1024,
+            audit_keep: 5,
+            audit_hash_chain: false,
+        }
+    }
+}
+
+impl Config {
+    /// Reads a config file. A missing file is not an error, the defaults are used instead.
+    ///
+    /// # Arguments
+    /// * `path` - The toml file to read the settings from.
+    /// * `errors` - An array of errors to be populated if any occur.
+    ///
+    /// # Returns
+    /// A unified result containing the parsed config.
+    pub fn load(path: &Path, mut errors: ErrorArray) -> uf<Config> {
+        if !path.exists() {
+            return uf::new(Ok(Config::default()));
+        }
+
+        let data: String = match fs::read_to_string(path) {
+            Ok(d) => d,
+            Err(e) => {
+                errors.push(ErrorArrayItem::from(e));
+                return uf::new(Err(errors));
+            }
+        };
+
+        match toml::from_str(&data) {
+            Ok(d) => uf::new(Ok(d)),
+            Err(e) => {
+                errors.push(ErrorArrayItem::new(
+                    Errors::InvalidType,
+                    format!("Invalid config file {}: {}", path.display(), e),
+                ));
+                uf::new(Err(errors))
+            }
+        }
+    }
+
+    /// Reads the config file at `path` and applies the environment overrides on top of it.
+    pub fn load_with_env(path: &Path, errors: ErrorArray) -> uf<Config> {
+        match Config::load(path, errors.clone()).uf_unwrap() {
+            Ok(mut config) => match config.apply_env(errors).uf_unwrap() {
+                Ok(_) => uf::new(Ok(config)),
+                Err(e) => uf::new(Err(e)),
+            },
+            Err(e) => uf::new(Err(e)),
+        }
+    }
+
+    /// Overrides every setting whose environment variable is set. The variables are named
+    /// `DUSA_` and the setting in upper case, e.g. `DUSA_READ_TIMEOUT`, except for `DUSA_SOCKET`,
+    /// `DUSA_PROG` and `DUSA_POLICY`. An empty `DUSA_METRICS_SOCKET` or `DUSA_METRICS_PORT`
+    /// turns those metrics off.
+    pub fn apply_env(&mut self, errors: ErrorArray) -> uf<()> {
+        self.apply_vars(|name| env::var_os(name), errors)
+    }
+
+    /// Does what [`Config::apply_env`] does, looking the variables up with `var`.
+    fn apply_vars(
+        &mut self,
+        var: impl Fn(&str) -> Option<OsString>,
+        mut errors: ErrorArray,
+    ) -> uf<()> {
+        match self.override_with(&var) {
+            Ok(_) => uf::new(Ok(())),
+            Err(e) => {
+                errors.push(ErrorArrayItem::new(Errors::InvalidType, e));
+                uf::new(Err(errors))
+            }
+        }
+    }
+
+    fn override_with(&mut self, var: &impl Fn(&str) -> Option<OsString>) -> Result<(), String> {
+        override_path(var, "DUSA_SOCKET", &mut self.socket_path);
+        override_value(var, "DUSA_TTL", &mut self.ttl)?;
+        override_value(
+            var,
+            "DUSA_LEGACY_TEMP_DECRYPT",
+            &mut self.legacy_temp_decrypt,
+        )?;
+        override_value(var, "DUSA_USER", &mut self.user)?;
+        override_value(var, "DUSA_GROUP", &mut self.group)?;
+        override_value(var, "DUSA_PROG", &mut self.prog_name)?;
+        override_path(var, "DUSA_POLICY", &mut self.policy_path);
+        override_value(var, "DUSA_MAX_FRAME_SIZE", &mut self.max_frame_size)?;
+        override_value(var, "DUSA_MAX_UPLOAD_SIZE", &mut self.max_upload_size)?;
+        override_value(var, "DUSA_READ_TIMEOUT", &mut self.read_timeout)?;
+        override_value(var, "DUSA_WRITE_TIMEOUT", &mut self.write_timeout)?;
+        override_value(var, "DUSA_WORKERS", &mut self.workers)?;
+        override_value(var, "DUSA_QUEUE_SIZE", &mut self.queue_size)?;
+        override_value(
+            var,
+            "DUSA_MAX_CONNECTIONS_PER_UID",
+            &mut self.max_connections_per_uid,
+        )?;
+        override_value(
+            var,
+            "DUSA_SESSION_IDLE_TIMEOUT",
+            &mut self.session_idle_timeout,
+        )?;
+        override_value(var, "DUSA_WORKER_TIMEOUT", &mut self.worker_timeout)?;
+        override_value(var, "DUSA_SHUTDOWN_TIMEOUT", &mut self.shutdown_timeout)?;
+        override_option(var, "DUSA_METRICS_SOCKET", &mut self.metrics_socket)?;
+        override_option(var, "DUSA_METRICS_PORT", &mut self.metrics_port)?;
+        override_path(var, "DUSA_AUDIT_LOG", &mut self.audit_log);
+        override_value(var, "DUSA_AUDIT_MAX_SIZE", &mut self.audit_max_size)?;
+        override_value(var, "DUSA_AUDIT_KEEP", &mut self.audit_keep)?;
+        override_value(var, "DUSA_AUDIT_HASH_CHAIN", &mut self.audit_hash_chain)?;
+        Ok(())
+    }
+
+    /// The largest upload that is stored, `None` if there is no limit.
+    pub fn upload_limit(&self) -> Option<u64> {
+        Some(self.max_upload_size).filter(|size| *size > 0)
+    }
+
+    /// The read deadline to put on a stream, `None` if there is none.
+    pub fn read_deadline(&self) -> Option<Duration> {
+        Some(self.read_timeout)
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    /// The write deadline to put on a stream, `None` if there is none.
+    pub fn write_deadline(&self) -> Option<Duration> {
+        Some(self.write_timeout)
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    /// How long a session may sit between requests, `None` if only the read deadline applies.
+    pub fn session_idle_deadline(&self) -> Option<Duration> {
+        Some(self.session_idle_timeout)
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    /// How long a worker may go without getting anywhere, `None` if it may take forever.
+    pub fn worker_deadline(&self) -> Option<Duration> {
+        Some(self.worker_timeout)
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    /// Where recs keeps its data for this prog name.
+    pub fn data_dir(&self) -> PathBuf {
+        PathBuf::from(format!("/var/{}", self.prog_name))
+    }
+}
+
+/// Sets `setting` to the path in `name` if it's set.
+fn override_path(var: &impl Fn(&str) -> Option<OsString>, name: &str, setting: &mut PathBuf) {
+    if let Some(d) = var(name) {
+        *setting = PathBuf::from(d);
+    }
+}
+
+/// Sets `setting` to the value in `name` if it's set.
+fn override_value<T: FromStr>(
+    var: &impl Fn(&str) -> Option<OsString>,
+    name: &str,
+    setting: &mut T,
+) -> Result<(), String>
+where
+    T::Err: Display,
+{
+    if let Some(d) = read_var(var, name)? {
+        *setting = parse_var(name, &d)?;
+    }
+    Ok(())
+}
+
+/// Sets `setting` to the value in `name` if it's set, or to `None` if it's set but empty.
+fn override_option<T: FromStr>(
+    var: &impl Fn(&str) -> Option<OsString>,
+    name: &str,
+    setting: &mut Option<T>,
+) -> Result<(), String>
+where
+    T::Err: Display,
+{
+    match read_var(var, name)? {
+        Some(d) if d.trim().is_empty() => *setting = None,
+        Some(d) => *setting = Some(parse_var(name, &d)?),
+        None => (),
+    }
+    Ok(())
+}
+
+fn read_var(var: &impl Fn(&str) -> Option<OsString>, name: &str) -> Result<Option<String>, String> {
+    var(name)
+        .map(|d| {
+            d.into_string()
+                .map_err(|d| format!("{} isn't valid unicode: {:?}", name, d))
+        })
+        .transpose()
+}
+
+fn parse_var<T: FromStr>(name: &str, value: &str) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    value
+        .trim()
+        .parse()
+        .map_err(|e| format!("{} can't be {:?}: {}", name, value, e))
+}
+
+/// The config file to read, `DUSA_CONFIG` if it's set.
+pub fn config_path() -> PathBuf {
+    env::var_os("DUSA_CONFIG")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(CONFIG_PATH))
+}
+
+/// Makes `config` the one returned by [`config`]. Has to be called before anything reads the
+/// config, later calls are ignored and return `false`.
+pub fn set_config(config: Config) -> bool {
+    CONFIG.set(config).is_ok()
+}
+
+/// The config of this process. If none was set, it's read from the file and the environment, and
+/// falls back to the defaults if that fails.
+pub fn config() -> &'static Config {
+    CONFIG.get_or_init(|| {
+        Config::load_with_env(&config_path(), ErrorArray::new_container())
+            .uf_unwrap()
+            .unwrap_or_else(|e| {
+                e.display(false);
+                Config::default()
+            })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn config_file(test: &str, contents: &str) -> PathBuf {
+        let path: PathBuf =
+            env::temp_dir().join(format!("dusa-config-{}-{}.toml", std::process::id(), test));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn vars(pairs: &[(&str, &str)]) -> impl Fn(&str) -> Option<OsString> {
+        let vars: BTreeMap<String, OsString> = pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), OsString::from(value)))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn a_missing_file_gives_the_defaults() {
+        let path: PathBuf = env::temp_dir().join("dusa-config-that-does-not-exist.toml");
+        let config: Config = Config::load(&path, ErrorArray::new_container())
+            .uf_unwrap()
+            .unwrap();
+        assert_eq!(config.ttl, TTL);
+        assert_eq!(config.workers, 16);
+        assert_eq!(config.metrics_port, None);
+    }
+
+    #[test]
+    fn settings_left_out_of_the_file_keep_their_defaults() {
+        let path: PathBuf = config_file(
+            "partial",
+            "ttl = 5\nmetrics_port = 9100\naudit_log = \"/tmp/audit.jsonl\"\n",
+        );
+        let config: Config = Config::load(&path, ErrorArray::new_container())
+            .uf_unwrap()
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.ttl, 5);
+        assert_eq!(config.metrics_port, Some(9100));
+        assert_eq!(config.audit_log, PathBuf::from("/tmp/audit.jsonl"));
+        assert_eq!(config.user, "dusa");
+        assert_eq!(config.max_upload_size, 1024 * 1024 * 1024);
+    }
+
+    #[test]
+    fn unknown_settings_are_refused() {
+        let path: PathBuf = config_file("unknown", "ttl = 5\nttl_seconds = 5\n");
+        let result = Config::load(&path, ErrorArray::new_container()).uf_unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+
+        let path: PathBuf = config_file("mistyped", "workers = \"many\"\n");
+        let result = Config::load(&path, ErrorArray::new_container()).uf_unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn the_environment_wins_over_the_file() {
+        let path: PathBuf = config_file("env", "ttl = 5\nworkers = 2\nmetrics_port = 9100\n");
+        let mut config: Config = Config::load(&path, ErrorArray::new_container())
+            .uf_unwrap()
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let env = vars(&[
+            ("DUSA_TTL", "9"),
+            ("DUSA_SOCKET", "/tmp/dusa.sock"),
+            ("DUSA_METRICS_PORT", ""),
+        ]);
+        config
+            .apply_vars(env, ErrorArray::new_container())
+            .uf_unwrap()
+            .unwrap();
+
+        assert_eq!(config.ttl, 9);
+        assert_eq!(config.socket_path, PathBuf::from("/tmp/dusa.sock"));
+        assert_eq!(config.metrics_port, None);
+        // Not in the environment, the file still decides
+        assert_eq!(config.workers, 2);
+    }
+
+    #[test]
+    fn every_setting_has_an_environment_variable() {
+        let defaults = serde_json::to_value(Config::default()).unwrap();
+        let settings = defaults.as_object().unwrap();
+
+        let pairs: Vec<(String, String)> = settings
+            .iter()
+            .map(|(setting, default)| {
+                let name: String = match setting.as_str() {
+                    "socket_path" => String::from("DUSA_SOCKET"),
+                    "prog_name" => String::from("DUSA_PROG"),
+                    "policy_path" => String::from("DUSA_POLICY"),
+                    _ => format!("DUSA_{}", setting.to_uppercase()),
+                };
+                let value: &str = match default {
+                    serde_json::Value::Bool(d) => ["true", "false"][*d as usize],
+                    serde_json::Value::Number(_) | serde_json::Value::Null => "7",
+                    _ => "/elsewhere",
+                };
+                (name, value.to_owned())
+            })
+            .collect();
+        let pairs: Vec<(&str, &str)> = pairs.iter().map(|(n, v)| (&**n, &**v)).collect();
+
+        let mut config: Config = Config::default();
+        config
+            .apply_vars(vars(&pairs), ErrorArray::new_container())
+            .uf_unwrap()
+            .unwrap();
+        let overridden = serde_json::to_value(config).unwrap();
+        for (setting, default) in settings {
+            assert_ne!(
+                &overridden[setting], default,
+                "{} wasn't overridden",
+                setting
+            );
+        }
+    }
+
+    #[test]
+    fn values_that_dont_parse_are_an_error() {
+        for (name, value) in [
+            ("DUSA_TTL", "soon"),
+            ("DUSA_WORKERS", "-1"),
+            ("DUSA_AUDIT_HASH_CHAIN", "yes"),
+            ("DUSA_METRICS_PORT", "70000"),
+        ] {
+            let mut config: Config = Config::default();
+            let result = config
+                .apply_vars(vars(&[(name, value)]), ErrorArray::new_container())
+                .uf_unwrap();
+            assert!(result.is_err(), "{}={} was accepted", name, value);
+        }
+    }
+}
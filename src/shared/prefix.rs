@@ -1,9 +1,25 @@
-use std::{io::{Read, Write}, os::unix::net::UnixStream};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read, Write},
+    os::unix::{
+        io::{AsRawFd, FromRawFd, RawFd},
+        net::UnixStream,
+    },
+    time::{Duration, Instant},
+};
 
-use serde::{Deserialize, Serialize};
-use dusa_collection_utils::errors::{ErrorArray, ErrorArrayItem, UnifiedResult};
+use nix::{
+    cmsg_space,
+    sys::{
+        socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags},
+        uio::IoVec,
+    },
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use dusa_collection_utils::errors::{ErrorArray, ErrorArrayItem, Errors, UnifiedResult};
 
-use crate::{DusaError, MessageType};
+use crate::{config::config, DusaError, MessageType};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GeneralMessage {
@@ -11,6 +27,63 @@ pub struct GeneralMessage {
     pub msg_type: MessageType,
     pub payload: serde_json::Value,
     pub error: Option<DusaError>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+}
+
+impl GeneralMessage {
+    /// Deserializes the payload into one of the typed payload enums.
+    pub fn payload_as<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        serde_json::from_value(self.payload.clone())
+    }
+}
+
+/// Why a length-prefixed message couldn't be read.
+#[derive(Debug)]
+pub enum FrameError {
+    /// The peer announced more bytes than we accept, none of them were read.
+    TooLarge { length: usize, limit: usize },
+    /// The peer didn't send the whole message before the read deadline.
+    TimedOut,
+    /// The announced bytes arrived but they aren't a message.
+    Malformed(serde_json::Error),
+    /// Anything else, a peer that hung up included.
+    Io(io::Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLarge { length, limit } => write!(
+                f,
+                "A message of {} bytes was announced, at most {} are accepted",
+                length, limit
+            ),
+            FrameError::TimedOut => write!(f, "The peer went silent, gave up waiting"),
+            FrameError::Malformed(e) => write!(f, "Received a malformed message: {}", e),
+            FrameError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for FrameError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            // A read deadline shows up as EAGAIN on unix sockets
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => FrameError::TimedOut,
+            _ => FrameError::Io(err),
+        }
+    }
+}
+
+impl From<FrameError> for ErrorArrayItem {
+    fn from(err: FrameError) -> Self {
+        match err {
+            FrameError::Io(e) => ErrorArrayItem::from(e),
+            FrameError::Malformed(e) => ErrorArrayItem::from(e),
+            other => ErrorArrayItem::new(Errors::InputOutput, other.to_string()),
+        }
+    }
 }
 
 /// Encodes a message with a length prefix and sends it over the stream.
@@ -22,7 +95,16 @@ pub fn send_message<T: Serialize>(stream: &mut UnixStream, message: &T, mut erro
             return UnifiedResult::new(Err(errors))
         },
     };
-    let length = message_bytes.len() as u32;
+    let length = match u32::try_from(message_bytes.len()) {
+        Ok(d) => d,
+        Err(_) => {
+            errors.push(ErrorArrayItem::new(
+                Errors::InputOutput,
+                format!("A message of {} bytes can't be framed", message_bytes.len()),
+            ));
+            return UnifiedResult::new(Err(errors))
+        },
+    };
     let length_bytes = length.to_be_bytes(); // Convert length to big-endian bytes
 
     // Send length prefix followed by the message
@@ -36,34 +118,213 @@ pub fn send_message<T: Serialize>(stream: &mut UnixStream, message: &T, mut erro
         return UnifiedResult::new(Err(errors))
     }
 
-    return UnifiedResult::new(Ok(()))
+    UnifiedResult::new(Ok(()))
 }
 
 /// Reads a length-prefixed message from the stream and decodes it.
+///
+/// Messages larger than the configured `max_frame_size` are refused, see [`read_frame`].
 pub fn receive_message(stream: &mut UnixStream, mut errors: ErrorArray) -> UnifiedResult<GeneralMessage> {
-    let mut length_bytes = [0u8; 4];
+    match read_frame(stream, config().max_frame_size) {
+        Ok(d) => UnifiedResult::new(Ok(d)),
+        Err(e) => {
+            errors.push(ErrorArrayItem::from(e));
+            UnifiedResult::new(Err(errors))
+        },
+    }
+}
 
-    if let Err(err) = stream.read_exact(&mut length_bytes) {
-        errors.push(ErrorArrayItem::from(err));
-        return UnifiedResult::new(Err(errors))
-    }; // get the length
+/// Reads a length-prefixed message of at most `limit` bytes.
+///
+/// The length is checked before anything is allocated, so a peer can't make us reserve more
+/// than `limit`. The stream's read timeout is the deadline for the whole message, not for each
+/// read, so a peer can't hold on to us by trickling it in. After an error the stream is no longer
+/// in sync and should be dropped.
+pub fn read_frame(stream: &mut UnixStream, limit: usize) -> Result<GeneralMessage, FrameError> {
+    let timeout: Option<Duration> = stream.read_timeout()?;
+    let deadline: Option<Instant> = timeout.map(|d| Instant::now() + d);
+
+    let read = read_frame_by(stream, limit, deadline);
+    // The next frame gets a deadline of its own
+    stream.set_read_timeout(timeout)?;
+    read
+}
+
+fn read_frame_by(stream: &mut UnixStream, limit: usize, deadline: Option<Instant>) -> Result<GeneralMessage, FrameError> {
+    let mut length_bytes = [0u8; 4];
+    read_exact_by(stream, &mut length_bytes, deadline)?; // get the length
 
     let length = u32::from_be_bytes(length_bytes) as usize;
+    if length > limit {
+        return Err(FrameError::TooLarge { length, limit });
+    }
 
     let mut message_bytes = vec![0u8; length];
+    read_exact_by(stream, &mut message_bytes, deadline)?; // Read the message
 
-    if let Err(err) = stream.read_exact(&mut message_bytes) {
-        errors.push(ErrorArrayItem::from(err));
-        return UnifiedResult::new(Err(errors))
-    } // Read the message
+    serde_json::from_slice(&message_bytes).map_err(FrameError::Malformed)
+}
 
-    let message = match serde_json::from_slice(&message_bytes) {
+/// Fills `buf`, shortening the read timeout as `deadline` gets closer.
+fn read_exact_by(stream: &mut UnixStream, mut buf: &mut [u8], deadline: Option<Instant>) -> Result<(), FrameError> {
+    while !buf.is_empty() {
+        if let Some(deadline) = deadline {
+            let left: Duration = deadline.saturating_duration_since(Instant::now());
+            // A zero timeout would mean none at all
+            if left.is_zero() {
+                return Err(FrameError::TimedOut);
+            }
+            stream.set_read_timeout(Some(left))?;
+        }
+
+        match stream.read(buf) {
+            Ok(0) => return Err(FrameError::Io(io::Error::from(io::ErrorKind::UnexpectedEof))),
+            Ok(n) => buf = &mut buf[n..],
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(FrameError::from(e)),
+        }
+    }
+    Ok(())
+}
+
+/// Passes an open file descriptor to the peer using `SCM_RIGHTS`.
+///
+/// The descriptor travels with a single marker byte so it has to be read with `receive_fd`
+/// before any other data is read from the stream.
+pub fn send_fd(stream: &mut UnixStream, fd: RawFd, mut errors: ErrorArray) -> UnifiedResult<()> {
+    let marker = [0u8; 1];
+    let iov = [IoVec::from_slice(&marker)];
+    let fds = [fd];
+    let cmsg = [ControlMessage::ScmRights(&fds)];
+
+    match sendmsg(stream.as_raw_fd(), &iov, &cmsg, MsgFlags::empty(), None) {
+        Ok(_) => UnifiedResult::new(Ok(())),
+        Err(e) => {
+            errors.push(ErrorArrayItem::from(e));
+            UnifiedResult::new(Err(errors))
+        }
+    }
+}
+
+/// Receives a file descriptor sent with `send_fd`.
+pub fn receive_fd(stream: &mut UnixStream, mut errors: ErrorArray) -> UnifiedResult<File> {
+    let mut marker = [0u8; 1];
+    let iov = [IoVec::from_mut_slice(&mut marker)];
+    let mut cmsg_buffer = cmsg_space!([RawFd; 1]);
+
+    let message = match recvmsg(
+        stream.as_raw_fd(),
+        &iov,
+        Some(&mut cmsg_buffer),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    ) {
         Ok(d) => d,
         Err(e) => {
             errors.push(ErrorArrayItem::from(e));
-            return UnifiedResult::new(Err(errors))
-        },
+            return UnifiedResult::new(Err(errors));
+        }
     };
 
-    UnifiedResult::new(Ok(message))
-}
\ No newline at end of file
+    for cmsg in message.cmsgs() {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+            if let Some(fd) = fds.first() {
+                // The kernel just handed us this descriptor, nothing else owns it
+                return UnifiedResult::new(Ok(unsafe { File::from_raw_fd(*fd) }));
+            }
+        }
+    }
+
+    errors.push(ErrorArrayItem::new(
+        Errors::InvalidFile,
+        String::from("No file descriptor was received"),
+    ));
+    UnifiedResult::new(Err(errors))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut bytes: Vec<u8> = (payload.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    #[test]
+    fn a_frame_within_the_limit_is_read() {
+        let (mut ours, mut theirs) = UnixStream::pair().unwrap();
+        let message = br#"{"version":"2","msg_type":"Status","payload":null,"error":null}"#;
+        theirs.write_all(&frame(message)).unwrap();
+
+        let read: GeneralMessage = read_frame(&mut ours, message.len()).unwrap();
+        assert_eq!(read.msg_type, MessageType::Status);
+        assert_eq!(read.id, None);
+    }
+
+    #[test]
+    fn a_frame_over_the_limit_is_refused_unread() {
+        let (mut ours, mut theirs) = UnixStream::pair().unwrap();
+        // Only the length is sent, a refusal must not wait for the rest
+        theirs.write_all(&(1025u32).to_be_bytes()).unwrap();
+
+        match read_frame(&mut ours, 1024) {
+            Err(FrameError::TooLarge { length, limit }) => {
+                assert_eq!((length, limit), (1025, 1024))
+            }
+            other => panic!("expected TooLarge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn frames_that_arent_messages_are_malformed() {
+        let (mut ours, mut theirs) = UnixStream::pair().unwrap();
+        theirs.write_all(&frame(b"not json")).unwrap();
+        assert!(matches!(
+            read_frame(&mut ours, 1024),
+            Err(FrameError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn a_peer_hanging_up_mid_frame_is_an_io_error() {
+        let (mut ours, mut theirs) = UnixStream::pair().unwrap();
+        theirs.write_all(&frame(b"{}")[..4]).unwrap();
+        drop(theirs);
+        assert!(matches!(
+            read_frame(&mut ours, 1024),
+            Err(FrameError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn a_trickled_frame_runs_into_the_deadline() {
+        let (mut ours, mut theirs) = UnixStream::pair().unwrap();
+        ours.set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+
+        // Every byte well within the timeout, the whole frame not
+        let trickle = thread::spawn(move || {
+            for byte in [0u8, 0, 0, 2, b'{', b'}'] {
+                thread::sleep(Duration::from_millis(100));
+                if theirs.write_all(&[byte]).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let started = Instant::now();
+        assert!(matches!(
+            read_frame(&mut ours, 64),
+            Err(FrameError::TimedOut)
+        ));
+        assert!(started.elapsed() < Duration::from_millis(500));
+        assert_eq!(
+            ours.read_timeout().unwrap(),
+            Some(Duration::from_millis(300))
+        );
+        drop(ours);
+        trickle.join().unwrap();
+    }
+}
@@ -1,7 +1,10 @@
+pub mod client;
+pub mod config;
 pub mod prefix;
 
-use std::{fs, os::unix::fs::PermissionsExt, path::PathBuf, time::Duration};
+use std::{collections::BTreeMap, fs, os::unix::fs::PermissionsExt, path::PathBuf, time::Duration};
 
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use nix::unistd::{chown, Gid, Uid};
 use simple_pretty::halt;
 use serde::{Deserialize, Serialize};
@@ -15,21 +18,34 @@ use dusa_collection_utils::{
 };
 use users::{Groups, Users, UsersCache};
 
-/// Current version of the protocol, derived from the package version.
+/// Version of the dusa package, shown to people. Peers agree on [`PROTOCOL_VERSION`] instead.
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
-/// Time to live in seconds for file that are decrypted.
+/// Newest version of the wire protocol spoken here. It only changes when the messages do.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest version of the wire protocol still spoken here. Version 1 is the protocol from before
+/// the [`Hello`] handshake, its clients send their request right away.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Default time to live in seconds for file that are decrypted, see [`config::Config::ttl`].
 pub const TTL: u64 = 30;
+/// Size in bytes of the pieces files are streamed in.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+/// Default limit in bytes for a single message, see [`config::Config::max_frame_size`].
+pub const MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+/// Default seconds to wait on a silent peer, see [`config::Config::read_timeout`].
+pub const IO_TIMEOUT: u64 = 60;
 
-/// Getting the current uid
+/// Getting the uid and gid of the configured service user
 pub fn get_id() -> (Uid, Gid) {
+    let config = config::config();
     let user_cache: UsersCache = UsersCache::new();
-    let dusa_uid = user_cache.get_user_by_name("dusa").unwrap().uid();
-    let dusa_gid = user_cache.get_group_by_name("dusa").unwrap().gid();
+    let dusa_uid = user_cache.get_user_by_name(&config.user).unwrap().uid();
+    let dusa_gid = user_cache.get_group_by_name(&config.group).unwrap().gid();
 
     (Uid::from_raw(dusa_uid), Gid::from_raw(dusa_gid))
 }
 
-/// Struct representing a write request.
+/// Struct representing a write request. It is refused, the daemon would read the path with its
+/// own rights, the data is sent with [`RequestRecsUpload`] instead.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RequestRecsWrite {
     pub path: PathType,
@@ -38,14 +54,54 @@ pub struct RequestRecsWrite {
     pub uid: u32,
 }
 
+/// Struct representing an upload request. The data follows as [`UploadPayload`] messages once
+/// the daemon has acknowledged the request.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RequestRecsUpload {
+    pub owner: String,
+    pub name: String,
+    /// Where the client says the data came from. Nothing checks it, it is only shown to people
+    /// and never written to.
+    pub orig_p: Option<PathType>,
+    pub uid: u32,
+}
+
 /// Struct representing a plain text request.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RequestRecsPlainText {
     pub command: Commands,
     pub data: String,
+    /// How `data` is encoded when encrypting, and how the plaintext is returned when decrypting.
+    #[serde(default)]
+    pub encoding: Encoding,
+    pub uid: u32,
+}
+
+/// Struct representing many text operations sent as one request, see [`Feature::Batch`].
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RequestRecsBatch {
+    pub items: Vec<BatchItem>,
     pub uid: u32,
 }
 
+/// One operation of a batch, [`Commands::EncryptRawText`] or [`Commands::DecryptRawText`].
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BatchItem {
+    pub command: Commands,
+    pub data: String,
+    /// See [`RequestRecsPlainText::encoding`].
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+
+/// How one item of a batch went, an item failing doesn't fail the others.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum BatchResult {
+    /// The encrypted or decrypted text.
+    Text(String),
+    Error(DusaError),
+}
+
 /// Struct representing a simple request.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RequestRecsSimple {
@@ -55,6 +111,15 @@ pub struct RequestRecsSimple {
     pub uid: u32,
 }
 
+/// Struct representing a request about stored entries in general rather than a single one.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RequestRecsQuery {
+    pub command: Commands,
+    /// Limits the query to a single owner.
+    pub owner: Option<String>,
+    pub uid: u32,
+}
+
 /// Struct representing a response.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ResponseData {
@@ -69,22 +134,386 @@ pub struct DecryptResponseData{
     pub ttl: Duration,
 }
 
+/// Describes a decrypted file handed to the client as a file descriptor. The descriptor itself
+/// follows the response message as `SCM_RIGHTS` ancillary data.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileDescriptorData {
+    /// Where the file was when it was stored, `None` if the data didn't come from a file.
+    pub orig_p: Option<PathType>,
+    pub size: u64,
+}
+
+/// Describes a stored entry. Entries stored before dusad kept a catalog only have an owner and
+/// a name.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EntryInfo {
+    pub owner: String,
+    pub name: String,
+    pub orig_p: Option<PathType>,
+    /// Size of the plaintext in bytes.
+    pub size: Option<u64>,
+    /// When the entry was stored, in seconds since the unix epoch.
+    pub stored: Option<u64>,
+}
+
+/// The answer to a ping, what is known about an entry without decrypting it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileStat {
+    pub exists: bool,
+    /// Size of the plaintext in bytes.
+    pub size: Option<u64>,
+    /// When the entry was stored, in seconds since the unix epoch.
+    pub created: Option<u64>,
+    pub orig_p: Option<PathType>,
+}
+
+/// How the daemon is doing, the answer to a [`MessageType::Status`] message.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DaemonStatus {
+    pub version: String,
+    /// Seconds since the daemon started.
+    pub uptime: u64,
+    pub recs_initialized: bool,
+    /// Connections being handled right now.
+    pub active_connections: usize,
+    /// Decrypted temp files waiting for their ttl to pass.
+    pub pending_temp_files: usize,
+    /// Running totals since the daemon started, by name.
+    pub counters: BTreeMap<String, u64>,
+    /// What's wrong with the daemon, empty when it's healthy.
+    pub problems: Vec<String>,
+}
+
+impl DaemonStatus {
+    pub fn healthy(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// A piece of a streamed file. The bytes are base64 encoded so they survive the json framing.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DataChunk {
+    pub data: String,
+}
+
+impl DataChunk {
+    pub fn new(bytes: &[u8]) -> Self {
+        DataChunk {
+            data: BASE64.encode(bytes),
+        }
+    }
+
+    /// Decodes the bytes carried by this chunk.
+    pub fn bytes(&self) -> Result<Vec<u8>, base64::DecodeError> {
+        BASE64.decode(&self.data)
+    }
+}
+
+/// How plaintext is carried in the `data` of a text request. Anything but utf8 can carry
+/// arbitrary bytes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Base64,
+    Hex,
+}
+
+impl Encoding {
+    /// Encodes `bytes`, failing only for utf8 when they aren't valid utf8.
+    pub fn encode(&self, bytes: Vec<u8>) -> Result<String, String> {
+        match self {
+            Encoding::Utf8 => String::from_utf8(bytes).map_err(|e| {
+                format!(
+                    "The plaintext is not valid utf8 ({}), ask for base64 or hex instead",
+                    e.utf8_error()
+                )
+            }),
+            Encoding::Base64 => Ok(BASE64.encode(bytes)),
+            Encoding::Hex => Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect()),
+        }
+    }
+
+    /// Decodes `data` back into the bytes it carries.
+    pub fn decode(&self, data: &str) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::Utf8 => Ok(data.as_bytes().to_vec()),
+            Encoding::Base64 => BASE64
+                .decode(data.trim())
+                .map_err(|e| format!("The data is not valid base64: {}", e)),
+            Encoding::Hex => {
+                let data: &str = data.trim();
+                if !data.len().is_multiple_of(2) || !data.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return Err(String::from(
+                        "The data is not valid hex, it needs two hex digits per byte",
+                    ));
+                }
+                (0..data.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&data[i..i + 2], 16))
+                    .collect::<Result<Vec<u8>, _>>()
+                    .map_err(|e| format!("The data is not valid hex: {}", e))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Encoding::Utf8 => write!(f, "utf8"),
+            Encoding::Base64 => write!(f, "base64"),
+            Encoding::Hex => write!(f, "hex"),
+        }
+    }
+}
+
+impl std::str::FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utf8" => Ok(Encoding::Utf8),
+            "base64" => Ok(Encoding::Base64),
+            "hex" => Ok(Encoding::Hex),
+            other => Err(format!("Unknown encoding {}", other)),
+        }
+    }
+}
+
 /// Enum representing different request payloads.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum RequestPayload {
     Write(RequestRecsWrite),
     PlainText(RequestRecsPlainText),
     Simple(RequestRecsSimple),
+    Upload(RequestRecsUpload),
+    Query(RequestRecsQuery),
+    Batch(RequestRecsBatch),
+}
+
+impl RequestPayload {
+    /// The features both sides have to support for this request.
+    pub fn needs(&self) -> Vec<Feature> {
+        match self {
+            RequestPayload::Upload(_) => vec![Feature::Streaming],
+            RequestPayload::Simple(req) => match req.command {
+                Commands::StreamFile => vec![Feature::Streaming],
+                Commands::DecryptFileFd => vec![Feature::FdPassing],
+                _ => Vec::new(),
+            },
+            RequestPayload::PlainText(req) if req.encoding != Encoding::Utf8 => {
+                vec![Feature::Encodings]
+            }
+            RequestPayload::Batch(req) => {
+                match req.items.iter().any(|i| i.encoding != Encoding::Utf8) {
+                    true => vec![Feature::Batch, Feature::Encodings],
+                    false => vec![Feature::Batch],
+                }
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// The uid the client claims to be acting as.
+    pub fn uid(&self) -> u32 {
+        match self {
+            RequestPayload::Write(req) => req.uid,
+            RequestPayload::PlainText(req) => req.uid,
+            RequestPayload::Simple(req) => req.uid,
+            RequestPayload::Upload(req) => req.uid,
+            RequestPayload::Query(req) => req.uid,
+            RequestPayload::Batch(req) => req.uid,
+        }
+    }
+
+    /// A name for what is asked for, the same for every request of its kind.
+    pub fn command(&self) -> &'static str {
+        let command: &Commands = match self {
+            RequestPayload::Write(_) => return "Write",
+            RequestPayload::Upload(_) => return "Upload",
+            RequestPayload::Batch(_) => return "Batch",
+            RequestPayload::PlainText(req) => &req.command,
+            RequestPayload::Simple(req) => &req.command,
+            RequestPayload::Query(req) => &req.command,
+        };
+        match command {
+            Commands::EncryptRawText => "EncryptRawText",
+            Commands::DecryptRawText => "DecryptRawText",
+            Commands::DecryptFile => "DecryptFile",
+            Commands::DecryptFileFd => "DecryptFileFd",
+            Commands::StreamFile => "StreamFile",
+            Commands::RemoveFile => "RemoveFile",
+            Commands::PingFile => "PingFile",
+            Commands::List => "List",
+        }
+    }
+}
+
+/// Optional parts of the protocol a peer may or may not support.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Feature {
+    /// Uploads and downloads sent as a series of chunks.
+    Streaming,
+    /// Decrypted files handed over as descriptors with `SCM_RIGHTS`.
+    FdPassing,
+    /// Many requests on one connection, each carrying an id its response is sent back with,
+    /// until the client sends [`MessageType::Close`]. Only used when the client asks for it.
+    Sessions,
+    /// Many text operations answered in one response, see [`RequestRecsBatch`].
+    Batch,
+    /// Text given and returned as base64 or hex, see [`Encoding`].
+    Encodings,
+}
+
+impl std::fmt::Display for Feature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Feature::Streaming => write!(f, "streaming"),
+            Feature::FdPassing => write!(f, "fd passing"),
+            Feature::Sessions => write!(f, "sessions"),
+            Feature::Batch => write!(f, "batches"),
+            Feature::Encodings => write!(f, "encodings"),
+        }
+    }
+}
+
+/// The first message on a connection, each side says which protocol versions and features it
+/// supports. The client sends it with [`MessageType::Hello`], the daemon answers with what
+/// they agreed on.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub min_version: u32,
+    pub max_version: u32,
+    pub features: Vec<Feature>,
+}
+
+/// What both sides of a connection agreed on.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    pub version: u32,
+    pub features: Vec<Feature>,
+}
+
+impl Hello {
+    /// What this build supports.
+    pub fn ours() -> Self {
+        Hello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            features: vec![
+                Feature::Streaming,
+                Feature::FdPassing,
+                Feature::Sessions,
+                Feature::Batch,
+                Feature::Encodings,
+            ],
+        }
+    }
+
+    /// The same hello without offering `feature`.
+    pub fn without(mut self, feature: Feature) -> Self {
+        self.features.retain(|f| *f != feature);
+        self
+    }
+
+    /// Settles on the newest version both sides speak and the features both support.
+    ///
+    /// # Returns
+    /// What was agreed on, `None` if the version ranges don't overlap.
+    pub fn negotiate(&self, other: &Hello) -> Option<Negotiated> {
+        let version: u32 = self.max_version.min(other.max_version);
+        if version < self.min_version.max(other.min_version) {
+            return None;
+        }
+        let mut features: Vec<Feature> = self
+            .features
+            .iter()
+            .filter(|f| other.features.contains(f))
+            .copied()
+            .collect();
+        features.sort();
+        features.dedup();
+        Some(Negotiated { version, features })
+    }
+}
+
+impl Negotiated {
+    /// What a client that sends no [`Hello`] gets, everything protocol version 1 had.
+    pub fn legacy() -> Self {
+        Negotiated {
+            version: 1,
+            features: vec![Feature::Streaming, Feature::FdPassing],
+        }
+    }
+
+    /// The protocol version a client without a [`Hello`] speaks, read from the
+    /// version field of its first message. Those clients sent their own crate
+    /// version there, so "1.2.6" speaks version 1.
+    pub fn legacy_version(version: &str) -> Option<u32> {
+        version.split('.').next()?.trim().parse().ok()
+    }
+
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.features.contains(&feature)
+    }
+}
+
+/// Messages a client sends after an accepted upload request.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum UploadPayload {
+    /// The next piece of the data.
+    Chunk(DataChunk),
+    /// All data was sent, carries the number of bytes sent.
+    EndOfStream(u64),
+}
+
+/// Enum representing different response payloads.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ResponsePayload {
+    /// A file was encrypted and stored, carries a short description.
+    Stored(String),
+    /// The result of encrypting or decrypting text.
+    Text(String),
+    /// A file was decrypted into a temporary path.
+    File(DecryptResponseData),
+    /// A file was decrypted into an anonymous file, its descriptor follows this message.
+    FileDescriptor(FileDescriptorData),
+    /// A file is about to be streamed, carries its size in bytes.
+    StreamStart(u64),
+    /// The next piece of a streamed file.
+    Chunk(DataChunk),
+    /// A stream is complete, carries the number of bytes sent.
+    EndOfStream(u64),
+    /// An entry was removed.
+    Removed,
+    /// What is known about a single entry.
+    Stat(FileStat),
+    /// The entries matching a query.
+    Entries(Vec<EntryInfo>),
+    /// How the daemon is doing.
+    Status(DaemonStatus),
+    /// The results of a batch, one per item in the order they were sent.
+    Batch(Vec<BatchResult>),
+    /// The answer to a [`Hello`], what the connection will use.
+    Negotiated(Negotiated),
+    /// The request failed, the details are in the message's `error`.
+    Error(String),
+    /// Used by acknowledgements and other messages without data.
+    Empty,
 }
 
 /// enums for commands 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Commands {
     EncryptRawText,
     DecryptRawText,
     DecryptFile,
+    DecryptFileFd,
+    StreamFile,
     RemoveFile,
     PingFile,
+    List,
 }
 
 /// Generic message struct used for communication.
@@ -94,6 +523,9 @@ pub struct Message<T> {
     pub msg_type: MessageType,
     pub payload: T,
     pub error: Option<DusaError>,
+    /// Ties a response to its request in a session, see [`Feature::Sessions`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
 }
 
 /// Enum representing different message types.
@@ -105,17 +537,37 @@ pub enum MessageType {
     Simple,
     Acknowledge,
     Test,
+    /// Asks the daemon how it's doing, it needs no payload and no permissions.
+    Status,
+    /// Opens a connection, carries a [`Hello`].
+    Hello,
+    /// Ends a session, it's answered with an ack.
+    Close,
     // Add more custom message types as needed
 }
 
 /// Enum representing different error codes.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ErrorCode {
     UnknownMessageType,
     InvalidPayload,
     InvalidVersion,
     InternalError,
     InvalidPermissions,
+    /// The peer announced a message larger than the daemon accepts.
+    FrameTooLarge,
+    /// The bytes that arrived are not a message.
+    MalformedFrame,
+    /// The peer went silent in the middle of a request.
+    Timeout,
+    /// The daemon has too much to do, try again later.
+    Busy,
+    /// The request needs a feature the two sides didn't agree on.
+    Unsupported,
+    /// Data couldn't be decoded as declared, or the plaintext can't be returned as asked.
+    InvalidEncoding,
+    /// An upload carried more data than the daemon stores at once.
+    UploadTooLarge,
     // Add more standardized error codes as needed
 }
 
@@ -127,6 +579,13 @@ impl std::fmt::Display for ErrorCode {
             ErrorCode::InternalError => write!(f, "Internal error"),
             ErrorCode::InvalidVersion => write!(f, "We aren't speaking the same language"),
             ErrorCode::InvalidPermissions => write!(f, "You have no authority here"),
+            ErrorCode::FrameTooLarge => write!(f, "Message too large"),
+            ErrorCode::MalformedFrame => write!(f, "Malformed message"),
+            ErrorCode::Timeout => write!(f, "Timed out"),
+            ErrorCode::Busy => write!(f, "Server busy"),
+            ErrorCode::Unsupported => write!(f, "Not supported"),
+            ErrorCode::InvalidEncoding => write!(f, "Invalid encoding"),
+            ErrorCode::UploadTooLarge => write!(f, "Upload too large"),
             // Add more standardized error codes as needed
         }
     }
@@ -138,43 +597,29 @@ impl std::fmt::Display for Commands {
             Commands::EncryptRawText => write!(f, "et"),
             Commands::DecryptRawText => write!(f, "dt"),
             Commands::DecryptFile => write!(f, "df"),
+            Commands::DecryptFileFd => write!(f, "dfd"),
+            Commands::StreamFile => write!(f, "sf"),
             Commands::RemoveFile => write!(f, "rf"),
             Commands::PingFile => write!(f, "pf"),
+            Commands::List => write!(f, "ls"),
         }
     }
 }
 
 /// Struct representing an error message.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DusaError {
     pub code: ErrorCode,
     pub message: String,
 }
 
-pub fn check_version(incoming_version: &str) -> bool {
-    // Split the version strings into major, minor, and patch parts
-    let parse_version = |v: &str| -> Option<(u32, u32)> {
-        let parts: Vec<&str> = v.split('.').collect();
-        if parts.len() != 3 {
-            return None;
-        }
-        let major = parts[0].parse::<u32>().ok()?;
-        let minor = parts[1].parse::<u32>().ok()?;
-        let _patch: u32 = parts[2].parse::<u32>().ok()?;
-        Some((major, minor))
-    };
-
-    if let (Some((inc_major, inc_minor)), Some((ver_major, ver_minor))) =
-        (parse_version(incoming_version), parse_version(VERSION))
-    {
-        inc_major == ver_major && inc_minor == ver_minor
-    } else {
-        false
+impl std::fmt::Display for DusaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
     }
 }
 
-
-/// Returns the path to the socket.
+/// Returns the path to the socket, as set in the [`config::Config`].
 ///
 /// # Arguments
 /// * `int` - A boolean indicating if initialization is needed.
@@ -189,7 +634,7 @@ pub fn SOCKET_PATH(
     mut errors: ErrorArray,
     mut warnings: WarningArray,
 ) -> uf<OkWarning<PathType>> {
-    let socket_file = PathType::Content(String::from("/var/run/dusa/dusa.sock"));
+    let socket_file = PathType::PathBuf(config::config().socket_path.clone());
     // let socket_file = PathType::Content(String::from("/home/dwhitfield/Developer/RUST/Dev/server/s.socket"));
     let _socket_dir = match socket_file.ancestors().next() {
         Some(d) => PathType::PathBuf(d.to_path_buf()),
@@ -271,8 +716,104 @@ impl std::fmt::Display for MessageType {
             MessageType::Simple => write!(f, "Simple Message"),
             MessageType::Acknowledge => write!(f, "Understood"),
             MessageType::Test => write!(f, "Test message"),
+            MessageType::Status => write!(f, "Status"),
+            MessageType::Hello => write!(f, "Hello"),
+            MessageType::Close => write!(f, "Close"),
             // Add more custom message types as needed
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(min_version: u32, max_version: u32, features: &[Feature]) -> Hello {
+        Hello {
+            min_version,
+            max_version,
+            features: features.to_vec(),
+        }
+    }
+
+    #[test]
+    fn negotiation_settles_on_the_newest_shared_version_and_features() {
+        let ours = hello(1, 3, &[Feature::Streaming, Feature::Sessions, Feature::Batch]);
+        let theirs = hello(2, 5, &[Feature::Batch, Feature::Encodings, Feature::Streaming]);
+
+        let agreed: Negotiated = ours.negotiate(&theirs).unwrap();
+        assert_eq!(agreed.version, 3);
+        assert_eq!(agreed.features, [Feature::Streaming, Feature::Batch]);
+        assert_eq!(theirs.negotiate(&ours), Some(agreed));
+    }
+
+    #[test]
+    fn versions_that_dont_overlap_are_a_mismatch() {
+        let ours = hello(2, 3, &[Feature::Streaming]);
+        assert_eq!(ours.negotiate(&hello(4, 6, &[Feature::Streaming])), None);
+        assert_eq!(ours.negotiate(&hello(1, 1, &[Feature::Streaming])), None);
+        assert!(ours.negotiate(&hello(1, 2, &[])).is_some());
+    }
+
+    #[test]
+    fn features_left_out_of_a_hello_are_never_agreed_on() {
+        let agreed: Negotiated = Hello::ours()
+            .negotiate(&Hello::ours().without(Feature::Sessions))
+            .unwrap();
+        assert_eq!(agreed.version, PROTOCOL_VERSION);
+        assert!(!agreed.supports(Feature::Sessions));
+        assert!(agreed.supports(Feature::Batch));
+    }
+
+    #[test]
+    fn clients_without_a_hello_get_version_one() {
+        let legacy: Negotiated = Negotiated::legacy();
+        assert_eq!(legacy.version, 1);
+        assert!(legacy.supports(Feature::Streaming));
+        assert!(legacy.supports(Feature::FdPassing));
+        assert!(!legacy.supports(Feature::Sessions));
+        assert!(!legacy.supports(Feature::Batch));
+        assert!(!legacy.supports(Feature::Encodings));
+    }
+
+    #[test]
+    fn the_version_of_a_client_without_a_hello_is_its_major_number() {
+        assert_eq!(Negotiated::legacy_version("1.2.6"), Some(1));
+        assert_eq!(Negotiated::legacy_version("2"), Some(2));
+        assert_eq!(Negotiated::legacy_version("9.0.0"), Some(9));
+        assert_eq!(Negotiated::legacy_version(""), None);
+        assert_eq!(Negotiated::legacy_version("one.two"), None);
+    }
+
+    #[test]
+    fn encodings_round_trip_any_bytes() {
+        let bytes: Vec<u8> = vec![0, 1, 0x7f, 0x80, 0xfe, 0xff];
+        for encoding in [Encoding::Base64, Encoding::Hex] {
+            let encoded: String = encoding.encode(bytes.clone()).unwrap();
+            assert_eq!(encoding.decode(&encoded).unwrap(), bytes);
+        }
+        assert_eq!(Encoding::Hex.encode(bytes.clone()).unwrap(), "00017f80feff");
+        assert_eq!(
+            Encoding::Base64.encode(b"dusa".to_vec()).unwrap(),
+            "ZHVzYQ=="
+        );
+
+        let text: String = Encoding::Utf8.encode("grüße".as_bytes().to_vec()).unwrap();
+        assert_eq!(Encoding::Utf8.decode(&text).unwrap(), "grüße".as_bytes());
+    }
+
+    #[test]
+    fn utf8_refuses_bytes_that_arent_text() {
+        assert!(Encoding::Utf8.encode(vec![0xff, 0xfe]).is_err());
+    }
+
+    #[test]
+    fn invalid_base64_and_hex_are_refused() {
+        assert!(Encoding::Base64.decode("not base64!").is_err());
+        assert!(Encoding::Hex.decode("abc").is_err());
+        assert!(Encoding::Hex.decode("zz").is_err());
+        // from_str_radix would take the sign
+        assert!(Encoding::Hex.decode("+f").is_err());
+        assert_eq!(Encoding::Hex.decode(" 0aFF\n").unwrap(), [0x0a, 0xff]);
+    }
+}